@@ -0,0 +1,141 @@
+use std::io::{ Read, Write };
+use std::io;
+use std::fmt;
+use std::error::Error;
+use crc::crc32;
+use codec::Serialize;
+
+/*
+                            ********************************
+                            *          CHECKSUMS           *
+                            ********************************
+
+    Records on disk are followed by (or, for PHash entries, carry in their header) a CRC32C of
+    their bytes. Anything that is read back is checked against it, and if it doesn't match, or
+    the bytes can't even be parsed, the read fails with a Corruption error (wrapped up in an
+    io::Error of kind InvalidData, so it can go through check! like every other error).
+
+    Pointers that get written over in place (like the next pointer of a PHash entry) aren't
+    covered, since every one of those writes would also have to rewrite the checksum. Instead
+    they're written to PHash's journal first (which is checksummed), so that a torn one can be
+    written again when the table is opened. PTree doesn't write over anything but its root.
+*/
+
+/// A record whose checksum didn't match its contents.
+#[derive(Debug, Clone)]
+pub struct Corruption {
+    /// What kind of record it was, e.g. "node"
+    pub what: String,
+    /// Where in its file it starts
+    pub pos: u64,
+    /// What went wrong
+    pub reason: String
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "corrupt {} at {}: {}", self.what, self.pos, self.reason)
+    }
+}
+
+impl Error for Corruption {
+    fn description(&self) -> &str {
+        "corrupt record"
+    }
+}
+
+pub fn corruption(what: &str, pos: u64, reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, Corruption { what: what.to_string(), pos: pos, reason: reason })
+}
+
+/// Returns the Corruption inside of e, if that's what e is.
+pub fn as_corruption(e: &io::Error) -> Option<&Corruption> {
+    match e.get_ref() {
+        Some(inner) => inner.downcast_ref::<Corruption>(),
+        None => None
+    }
+}
+
+pub fn crc32c(bytes: &[u8]) -> u32 {
+    crc32c_update(0, bytes)
+}
+
+/// Continues a CRC32C: crc32c_update(crc32c(a), b) is the checksum of a followed by b.
+pub fn crc32c_update(crc: u32, bytes: &[u8]) -> u32 {
+    crc32::update(crc, &crc32::CASTAGNOLI_TABLE, bytes)
+}
+
+/// Keeps a running CRC32C of everything that is read through it.
+pub struct CrcReader<R: Read> {
+    inner: R,
+    crc: u32
+}
+
+impl<R: Read> CrcReader<R> {
+    pub fn new(inner: R) -> CrcReader<R> {
+        CrcReader { inner: inner, crc: 0 }
+    }
+
+    /// Adds bytes to the checksum that weren't read through this reader.
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.crc = crc32c_update(self.crc, bytes);
+    }
+
+    pub fn crc(&self) -> u32 {
+        self.crc
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for CrcReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let n;
+        match self.inner.read(buf) {
+            Ok(x) => n = x,
+            Err(e) => return Err(e)
+        }
+        self.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Parses something out of a reader and checks the checksum that follows it. The record is
+/// supposed to be there, so anything that goes wrong (running out of file included) is reported
+/// as corruption.
+pub fn read_checked<T: Serialize>(from: &mut Read, what: &str, pos: u64) -> Result<T::DeserializeOutput, io::Error> {
+    let mut reader = CrcReader::new(from);
+    let x = match T::deserialize(&mut reader) {
+        Ok(x) => x,
+        Err(e) => return Err(corruption(what, pos, format!("{}", e)))
+    };
+    let crc = reader.crc();
+    let stored = match u32::deserialize(&mut reader.into_inner()) {
+        Ok(x) => x,
+        Err(e) => return Err(corruption(what, pos, format!("{}", e)))
+    };
+    if crc != stored {
+        return Err(corruption(what, pos, format!("checksum is {:08x}, expected {:08x}", crc, stored)))
+    }
+    Ok(x)
+}
+
+/// Writes x followed by its checksum, in one write.
+pub fn write_checked<T: Serialize>(x: &T, to: &mut Write) -> Result<(), io::Error> {
+    let mut bytes = Vec::with_capacity(x.serialized_len() as usize + 4);
+    if let Err(e) = x.serialize(&mut bytes) {
+        return Err(e)
+    }
+    let crc = crc32c(&bytes);
+    if let Err(e) = crc.serialize(&mut bytes) {
+        return Err(e)
+    }
+    to.write_all(&bytes)
+}
+
+/// The number of bytes write_checked writes for x.
+pub fn checked_len<T: Serialize>(x: &T) -> u64 {
+    x.serialized_len() + 4
+}
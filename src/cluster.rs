@@ -0,0 +1,733 @@
+use std::cmp::Ordering;
+use std::iter::{ Cloned, Zip };
+use std::slice;
+use std::str::FromStr;
+use rng::Rng;
+
+/*
+                            ********************************
+                            *          CLUSTERING          *
+                            ********************************
+
+    Every user is a point: a sparse vector with a weight for each word they used, indexed by
+    the word's id in the Vocabulary. How close two users are is the cosine distance between
+    their vectors, 1 - cos(angle between them), so it only depends on which words they use
+    and how often compared to each other, not on how much they tweet.
+
+    k-means here is spherical k-means: every point is scaled to length 1, a point belongs to
+    the centroid it has the biggest dot product with (which, for unit vectors, is the one with
+    the smallest cosine distance), and a new centroid is the sum of its members scaled back to
+    length 1. Centroids are sparse too, and only have the words their members used, so they
+    stay small no matter how big the vocabulary is.
+
+    Where k-means ends up depends on where it starts. The starting centroids are picked with
+    k-means++: the first one is a random point, and every one after that is a random point
+    picked with probability proportional to its squared distance from the closest centroid so
+    far, so they start out spread apart. Then it runs a few times from different starts and
+    keeps the run with the smallest error (the sum of every point's distance to its centroid).
+    Everything random comes from an Rng with a seed, so the same seed gives the same groups.
+*/
+
+/// A vector that is mostly zeros. Only the non zero weights are kept, sorted by id.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SparseVector {
+    ids: Vec<u32>,
+    weights: Vec<f64>
+}
+
+impl SparseVector {
+    /// ids has to be sorted, smallest first, with weights[i] being the weight of ids[i].
+    pub fn new(ids: Vec<u32>, weights: Vec<f64>) -> SparseVector {
+        SparseVector { ids: ids, weights: weights }
+    }
+
+    /// The number of non zero weights.
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Every (id, weight) pair, smallest id first.
+    pub fn iter(&self) -> Zip<Cloned<slice::Iter<u32>>, Cloned<slice::Iter<f64>>> {
+        self.ids.iter().cloned().zip(self.weights.iter().cloned())
+    }
+
+    /// One more than the biggest id, i.e. how long a dense copy of this would have to be.
+    pub fn dim(&self) -> usize {
+        match self.ids.last() {
+            Some(&id) => id as usize + 1,
+            None => 0
+        }
+    }
+
+    pub fn norm(&self) -> f64 {
+        self.weights.iter().map(|w| w * w).sum::<f64>().sqrt()
+    }
+
+    /// Scales the vector to length 1. Vectors that are all zeros stay that way.
+    pub fn normalize(&mut self) {
+        let norm = self.norm();
+        if norm != 0.0 {
+            for w in self.weights.iter_mut() {
+                *w /= norm;
+            }
+        }
+    }
+
+    /// Both vectors are sorted by id, so this is a merge.
+    pub fn dot(&self, other: &SparseVector) -> f64 {
+        let (mut i, mut j) = (0, 0);
+        let mut acc = 0.0;
+        while i < self.ids.len() && j < other.ids.len() {
+            match self.ids[i].cmp(&other.ids[j]) {
+                Ordering::Less => i += 1,
+                Ordering::Greater => j += 1,
+                Ordering::Equal => {
+                    acc += self.weights[i] * other.weights[j];
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+        acc
+    }
+
+    /// self + other. Both are sorted by id, so this is a merge too.
+    pub fn add(&self, other: &SparseVector) -> SparseVector {
+        let mut sum = SparseVector {
+            ids: Vec::with_capacity(self.len() + other.len()),
+            weights: Vec::with_capacity(self.len() + other.len())
+        };
+        let (mut i, mut j) = (0, 0);
+        while i < self.ids.len() || j < other.ids.len() {
+            let order = if i == self.ids.len() {
+                Ordering::Greater
+            } else if j == other.ids.len() {
+                Ordering::Less
+            } else {
+                self.ids[i].cmp(&other.ids[j])
+            };
+            match order {
+                Ordering::Less => {
+                    sum.ids.push(self.ids[i]);
+                    sum.weights.push(self.weights[i]);
+                    i += 1;
+                },
+                Ordering::Greater => {
+                    sum.ids.push(other.ids[j]);
+                    sum.weights.push(other.weights[j]);
+                    j += 1;
+                },
+                Ordering::Equal => {
+                    sum.ids.push(self.ids[i]);
+                    sum.weights.push(self.weights[i] + other.weights[j]);
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+        sum
+    }
+
+    /// 1 - the cosine of the angle between the two vectors: 0 if they point the same way, 1 if
+    /// they have no words in common. A vector of all zeros is 1 away from everything.
+    pub fn cosine_distance(&self, other: &SparseVector) -> f64 {
+        let denom = self.norm() * other.norm();
+        if denom == 0.0 {
+            1.0
+        } else {
+            1.0 - self.dot(other) / denom
+        }
+    }
+
+    /// The n biggest weights, biggest first.
+    pub fn top(&self, n: usize) -> Vec<(u32, f64)> {
+        let mut pairs = self.iter().collect::<Vec<_>>();
+        pairs.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        pairs.truncate(n);
+        pairs
+    }
+}
+
+/// What came out of a clustering algorithm.
+pub struct Clustering {
+    /// assignments[i] is the cluster of the i'th point
+    pub assignments: Vec<usize>,
+    /// The center of each cluster, length 1
+    pub centroids: Vec<SparseVector>,
+    /// How many rounds it took to settle down (or the maximum, if it never did)
+    pub iterations: usize,
+    /// The sum of the cosine distances from every point to its centroid
+    pub error: f64
+}
+
+impl Clustering {
+    /// The indices of the points in each cluster.
+    pub fn members(&self) -> Vec<Vec<usize>> {
+        let mut members = vec![vec![]; self.centroids.len()];
+        for (i, &c) in self.assignments.iter().enumerate() {
+            members[c].push(i);
+        }
+        members
+    }
+}
+
+/// Scales copies of the points to length 1.
+pub fn normalized(points: &[SparseVector]) -> Vec<SparseVector> {
+    points.iter().map(|p| {
+        let mut p = p.clone();
+        p.normalize();
+        p
+    }).collect()
+}
+
+/// Spherical k-means, run `restarts` times (at least once) from k-means++ starts, returning
+/// the run with the smallest error. Each run stops when no point changes clusters, or after
+/// max_iters rounds (there is always at least one). If there are fewer than k points, every
+/// point gets its own cluster, and there's always at least one cluster if there are points.
+pub fn k_means(points: &[SparseVector], k: usize, max_iters: usize, restarts: usize, seed: u64) -> Clustering {
+    let k = if k > points.len() { points.len() } else if k == 0 { 1 } else { k };
+    let mut rng = Rng::new(seed);
+    let unit = normalized(points);
+    let mut best: Option<Clustering> = None;
+    for _ in 0..if restarts == 0 { 1 } else { restarts } {
+        let seeds = k_means_plus_plus(&unit, k, &mut rng);
+        let run = k_means_from(&unit, &seeds, max_iters);
+        let better = match best {
+            Some(ref b) => run.error < b.error,
+            None => true
+        };
+        if better {
+            best = Some(run);
+        }
+    }
+    best.unwrap()
+}
+
+/// Picks k different points to start k-means from (see the top of this file). The points
+/// have to be length 1 already.
+pub fn k_means_plus_plus(points: &[SparseVector], k: usize, rng: &mut Rng) -> Vec<usize> {
+    let mut seeds = Vec::with_capacity(k);
+    if k == 0 || points.is_empty() {
+        return seeds
+    }
+    seeds.push(rng.range(0, points.len()));
+    // closest[i] is the squared distance from point i to the closest seed so far. Rounding can
+    // leave a point a hair away from itself, so seeds are set to 0 or they could be picked again
+    let mut closest = points.iter().map(|p| unit_distance(p, &points[seeds[0]]).powi(2)).collect::<Vec<f64>>();
+    closest[seeds[0]] = 0.0;
+    while seeds.len() < k {
+        let total = closest.iter().sum::<f64>();
+        let next = if total > 0.0 {
+            let mut target = rng.next_f64() * total;
+            let mut pick = None;
+            for (i, &d) in closest.iter().enumerate() {
+                if d > 0.0 {
+                    pick = Some(i);
+                    if target < d { break }
+                    target -= d;
+                }
+            }
+            pick.unwrap()
+        } else {
+            // Every point left is on top of a seed, so any one that isn't a seed will do
+            let rest = (0..points.len()).filter(|i| !seeds.contains(i)).collect::<Vec<_>>();
+            rest[rng.range(0, rest.len())]
+        };
+        seeds.push(next);
+        for (i, p) in points.iter().enumerate() {
+            let d = unit_distance(p, &points[next]).powi(2);
+            if d < closest[i] {
+                closest[i] = d;
+            }
+        }
+        closest[next] = 0.0;
+    }
+    seeds
+}
+
+/// The cosine distance between two vectors that are already length 1. Rounding can make it
+/// come out a tiny bit negative, so it's kept at 0 or more.
+fn unit_distance(a: &SparseVector, b: &SparseVector) -> f64 {
+    let d = 1.0 - a.dot(b);
+    if d < 0.0 { 0.0 } else { d }
+}
+
+/// Spherical k-means, starting with the points at `seeds` as the centroids.
+pub fn k_means_from(points: &[SparseVector], seeds: &[usize], max_iters: usize) -> Clustering {
+    let points = normalized(points);
+    let k = seeds.len();
+
+    let mut error;
+    let mut centroids = seeds.iter().map(|&s| points[s].clone()).collect::<Vec<_>>();
+    let mut assignments = vec![k; points.len()];
+    let mut iterations = 0;
+
+    loop {
+        let mut changed = false;
+        error = 0.0;
+        for (i, p) in points.iter().enumerate() {
+            let (closest, dot) = closest_centroid(p, &centroids);
+            if assignments[i] != closest {
+                assignments[i] = closest;
+                changed = true;
+            }
+            error += if dot > 1.0 { 0.0 } else { 1.0 - dot };
+        }
+        iterations += 1;
+        if !changed || iterations >= max_iters {
+            break
+        }
+
+        // A cluster that lost all of its members keeps its old centroid
+        let (sums, sizes) = cluster_sums(&points, &assignments, k);
+        for c in 0..k {
+            if sizes[c] > 0 {
+                centroids[c] = sums[c].clone();
+                centroids[c].normalize();
+            }
+        }
+    }
+
+    Clustering {
+        assignments: assignments,
+        centroids: centroids,
+        iterations: iterations,
+        error: error
+    }
+}
+
+/// The sum of the points in each of the k clusters, and how many points are in each one.
+fn cluster_sums(points: &[SparseVector], assignments: &[usize], k: usize) -> (Vec<SparseVector>, Vec<usize>) {
+    let mut sums = vec![SparseVector::new(vec![], vec![]); k];
+    let mut sizes = vec![0; k];
+    for (i, p) in points.iter().enumerate() {
+        sums[assignments[i]] = sums[assignments[i]].add(p);
+        sizes[assignments[i]] += 1;
+    }
+    (sums, sizes)
+}
+
+/// Turns the groups something else came up with into a Clustering, so they can be described
+/// and scored like k-means groups. assignments[i] is the group of points[i], and the groups
+/// have to be numbered from 0 with none skipped.
+pub fn clustering_of(points: &[SparseVector], assignments: Vec<usize>) -> Clustering {
+    let points = normalized(points);
+    let k = assignments.iter().map(|&c| c + 1).max().unwrap_or(0);
+    let (mut centroids, _) = cluster_sums(&points, &assignments, k);
+    for c in centroids.iter_mut() {
+        c.normalize();
+    }
+    let error = points.iter().enumerate().map(|(i, p)| unit_distance(p, &centroids[assignments[i]])).sum();
+    Clustering {
+        assignments: assignments,
+        centroids: centroids,
+        iterations: 0,
+        error: error
+    }
+}
+
+/// The index of the centroid p has the biggest dot product with (ties go to the first one),
+/// and the dot product.
+fn closest_centroid(p: &SparseVector, centroids: &[SparseVector]) -> (usize, f64) {
+    let mut best = 0;
+    let mut best_dot = ::std::f64::NEG_INFINITY;
+    for (c, centroid) in centroids.iter().enumerate() {
+        let dot = p.dot(centroid);
+        if dot > best_dot {
+            best = c;
+            best_dot = dot;
+        }
+    }
+    (best, best_dot)
+}
+
+/// How good a clustering is. Clusters with no members are left out of all of these.
+#[derive(Clone, Copy, Debug)]
+pub struct Scores {
+    /// From -1 to 1, higher is better: on average, how much closer each point is to the rest
+    /// of its own cluster than to the next closest cluster (by cosine distance).
+    pub silhouette: f64,
+    /// The sum of the squared distances from every (length 1) point to its centroid. It always
+    /// goes down as k goes up, so it only means something compared to other k.
+    pub wcss: f64,
+    /// Davies-Bouldin index, lower is better: on average, how spread out a cluster and its
+    /// most similar neighbour are, compared to how far apart their centroids are. Clusters
+    /// with the same centroid make it huge, but never infinite or NaN.
+    pub davies_bouldin: f64
+}
+
+/// How far apart two centroids are taken to be for Davies-Bouldin if they're closer than this.
+const MIN_CENTROID_DISTANCE: f64 = 1e-9;
+
+/// Scores the clustering of points.
+pub fn scores(points: &[SparseVector], clustering: &Clustering) -> Scores {
+    let points = normalized(points);
+    let members = clustering.members();
+    let clusters = (0..members.len()).filter(|&c| !members[c].is_empty()).collect::<Vec<_>>();
+
+    let mut wcss = 0.0;
+    for (i, p) in points.iter().enumerate() {
+        let c = &clustering.centroids[clustering.assignments[i]];
+        wcss += (p.dot(p) - 2.0 * p.dot(c) + c.dot(c)).max(0.0);
+    }
+
+    // The average distance from each member of a cluster to its centroid
+    let spread = members.iter().enumerate().map(|(c, m)| {
+        if m.is_empty() { 0.0 } else {
+            m.iter().map(|&i| unit_distance(&points[i], &clustering.centroids[c])).sum::<f64>() / m.len() as f64
+        }
+    }).collect::<Vec<f64>>();
+    let mut davies_bouldin = 0.0;
+    if clusters.len() > 1 {
+        for &a in clusters.iter() {
+            let worst = clusters.iter().filter(|&&b| b != a).map(|&b| {
+                // Two clusters with the same centroid are as bad as it gets, so rather than
+                // dividing by 0 (or leaving them out, which would make them look good) the
+                // distance is clamped to a tiny one
+                let apart = clustering.centroids[a].cosine_distance(&clustering.centroids[b]);
+                (spread[a] + spread[b]) / apart.max(MIN_CENTROID_DISTANCE)
+            }).fold(0.0, f64::max);
+            davies_bouldin += worst;
+        }
+        davies_bouldin /= clusters.len() as f64;
+    }
+
+    Scores {
+        silhouette: silhouette(&points, &members, &clustering.assignments),
+        wcss: wcss,
+        davies_bouldin: davies_bouldin
+    }
+}
+
+/// The average silhouette of every point. A point alone in its cluster scores 0, and so does
+/// everything if there's only one cluster.
+fn silhouette(points: &[SparseVector], members: &[Vec<usize>], assignments: &[usize]) -> f64 {
+    if points.is_empty() || members.iter().filter(|m| !m.is_empty()).count() < 2 {
+        return 0.0
+    }
+    let mut total = 0.0;
+    for (i, p) in points.iter().enumerate() {
+        let own = assignments[i];
+        if members[own].len() < 2 {
+            continue
+        }
+        let mean = |m: &Vec<usize>| m.iter().map(|&j| unit_distance(p, &points[j])).sum::<f64>();
+        // p is 0 away from itself, so that just leaves it out of its own cluster's mean
+        let a = mean(&members[own]) / (members[own].len() - 1) as f64;
+        let b = members.iter().enumerate()
+            .filter(|&(c, m)| c != own && !m.is_empty())
+            .map(|(_, m)| mean(m) / m.len() as f64)
+            .fold(::std::f64::INFINITY, f64::min);
+        let max = if a > b { a } else { b };
+        if max > 0.0 {
+            total += (b - a) / max;
+        }
+    }
+    total / points.len() as f64
+}
+
+/// How auto-k picks k.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum KChoice {
+    /// The k with the best silhouette
+    Silhouette,
+    /// The k where the WCSS stops dropping quickly: the point on the WCSS curve that's the
+    /// furthest from the straight line between its two ends
+    Elbow
+}
+
+impl FromStr for KChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<KChoice, String> {
+        match &s.to_lowercase()[..] {
+            "silhouette" => Ok(KChoice::Silhouette),
+            "elbow" => Ok(KChoice::Elbow),
+            _ => Err(format!("{} is not silhouette or elbow", s))
+        }
+    }
+}
+
+/// Picks a k out of the scores of a scan over k, which have to be sorted by k. None if
+/// nothing was scanned.
+pub fn pick_k(scan: &[(usize, Scores)], how: KChoice) -> Option<usize> {
+    if scan.is_empty() {
+        return None
+    }
+    Some(match how {
+        KChoice::Silhouette => {
+            let mut best = scan[0];
+            for &(k, s) in scan.iter() {
+                if s.silhouette > best.1.silhouette {
+                    best = (k, s);
+                }
+            }
+            best.0
+        },
+        KChoice::Elbow => {
+            let (first, last) = (scan[0], scan[scan.len() - 1]);
+            let k_span = (last.0 - first.0) as f64;
+            let wcss_span = first.1.wcss - last.1.wcss;
+            if k_span == 0.0 || wcss_span <= 0.0 {
+                return Some(first.0)
+            }
+            // Both axes go from 0 to 1, and the line from (0, 1) to (1, 0) is x + y = 1
+            let mut best = (first.0, 0.0);
+            for &(k, s) in scan.iter() {
+                let x = (k - first.0) as f64 / k_span;
+                let y = (s.wcss - last.1.wcss) / wcss_span;
+                let dist = 1.0 - x - y;
+                if dist > best.1 {
+                    best = (k, dist);
+                }
+            }
+            best.0
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ SparseVector, k_means, k_means_plus_plus, k_means_from, cluster_sums, normalized, unit_distance };
+    use super::{ Scores, KChoice, Clustering, scores, pick_k, clustering_of };
+    use std::f64::consts::{ FRAC_1_SQRT_2, PI };
+    use rng::Rng;
+
+    fn v(pairs: &[(u32, f64)]) -> SparseVector {
+        SparseVector::new(pairs.iter().map(|p| p.0).collect(), pairs.iter().map(|p| p.1).collect())
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    /// n noisy points around each of the given groups of word ids, one group after another.
+    fn groups(groups: &[&[u32]], n: usize, seed: u64) -> Vec<SparseVector> {
+        let mut rng = Rng::new(seed);
+        let mut points = vec![];
+        for g in groups.iter() {
+            for _ in 0..n {
+                points.push(v(&g.iter().map(|&id| (id, 1.0 + rng.next_f64())).collect::<Vec<_>>()));
+            }
+        }
+        points
+    }
+
+    #[test]
+    fn test_dot_and_add() {
+        let a = v(&[(0, 1.0), (2, 3.0), (5, 1.0)]);
+        let b = v(&[(2, 2.0), (3, 1.0), (5, 4.0)]);
+        assert_eq!(a.dot(&b), 10.0);
+        assert_eq!(b.dot(&a), 10.0);
+        assert_eq!(a.dot(&v(&[])), 0.0);
+        assert_eq!(a.add(&b), v(&[(0, 1.0), (2, 5.0), (3, 1.0), (5, 5.0)]));
+        assert_eq!(b.add(&a), a.add(&b));
+        assert_eq!(a.add(&v(&[])), a);
+        assert_eq!(a.dim(), 6);
+        assert_eq!(a.top(2), vec![(2, 3.0), (0, 1.0)]);
+    }
+
+    #[test]
+    fn test_normalize() {
+        let mut a = v(&[(3, 3.0), (4, 4.0)]);
+        assert_eq!(a.norm(), 5.0);
+        a.normalize();
+        assert_eq!(a, v(&[(3, 0.6), (4, 0.8)]));
+        let mut zero = v(&[]);
+        zero.normalize();
+        assert_eq!(zero, v(&[]));
+    }
+
+    #[test]
+    fn test_cosine_distance() {
+        let a = v(&[(0, 1.0), (1, 2.0)]);
+        assert!(close(a.cosine_distance(&a), 0.0));
+        assert!(close(a.cosine_distance(&v(&[(0, 3.0), (1, 6.0)])), 0.0));
+        assert!(close(a.cosine_distance(&v(&[(2, 1.0), (3, 5.0)])), 1.0));
+        assert!(close(a.cosine_distance(&v(&[(0, 2.0), (1, -1.0)])), 1.0));
+        assert!(close(a.cosine_distance(&v(&[(1, 1.0)])), 1.0 - 2.0 / 5f64.sqrt()));
+        assert_eq!(a.cosine_distance(&v(&[])), 1.0);
+    }
+
+    #[test]
+    fn test_cluster_sums() {
+        let points = vec![v(&[(0, 1.0), (1, 2.0)]), v(&[(1, 1.0), (3, 4.0)]), v(&[(2, 5.0)])];
+        let (sums, sizes) = cluster_sums(&points, &[0, 0, 1], 3);
+        assert_eq!(sums, vec![v(&[(0, 1.0), (1, 3.0), (3, 4.0)]), v(&[(2, 5.0)]), v(&[])]);
+        assert_eq!(sizes, vec![2, 1, 0]);
+    }
+
+    #[test]
+    fn test_k_means_plus_plus() {
+        // Points in a group are all the same, so the second seed can only come from the group
+        // the first one isn't in
+        let mut points = vec![v(&[(0, 1.0), (1, 2.0)]); 5];
+        points.extend(vec![v(&[(5, 1.0), (6, 1.0)]); 5]);
+        let points = normalized(&points);
+        for seed in 0..20 {
+            let seeds = k_means_plus_plus(&points, 2, &mut Rng::new(seed));
+            assert_eq!(seeds.len(), 2);
+            assert!((seeds[0] < 5) != (seeds[1] < 5), "{:?}", seeds);
+
+            // With only two different points, the third seed is a copy of one of them
+            let mut seeds = k_means_plus_plus(&points, 3, &mut Rng::new(seed));
+            seeds.sort();
+            seeds.dedup();
+            assert_eq!(seeds.len(), 3);
+        }
+        assert!(k_means_plus_plus(&points, 0, &mut Rng::new(1)).is_empty());
+        assert!(k_means_plus_plus(&[], 2, &mut Rng::new(1)).is_empty());
+    }
+
+    #[test]
+    fn test_k_means_from() {
+        let points = vec![v(&[(0, 1.0)]), v(&[(0, 1.0), (1, 0.1)]), v(&[(1, 1.0)]), v(&[(0, 0.1), (1, 1.0)])];
+        let c = k_means_from(&points, &[0, 2], 10);
+        assert_eq!(c.assignments, vec![0, 0, 1, 1]);
+        // One round to assign, and one more to see that nothing moves
+        assert_eq!(c.iterations, 2);
+        let unit = normalized(&points);
+        let mut expected = unit[0].add(&unit[1]);
+        expected.normalize();
+        assert!(close(c.centroids[0].dot(&expected), 1.0));
+        assert!(close(c.centroids[1].norm(), 1.0));
+        let error = (0..4).map(|i| unit_distance(&unit[i], &c.centroids[c.assignments[i]])).sum::<f64>();
+        assert!(close(c.error, error));
+
+        assert_eq!(k_means_from(&points, &[0, 2], 1).iterations, 1);
+    }
+
+    #[test]
+    fn test_k_means_separates_groups() {
+        let points = groups(&[&[0, 1, 2], &[10, 11, 12]], 10, 1);
+        let c = k_means(&points, 2, 20, 3, 7);
+        assert_eq!(c.centroids.len(), 2);
+        assert!(c.assignments[..10].iter().all(|&a| a == c.assignments[0]));
+        assert!(c.assignments[10..].iter().all(|&a| a == c.assignments[10]));
+        assert!(c.assignments[0] != c.assignments[10]);
+        for centroid in c.centroids.iter() {
+            assert_eq!(centroid.len(), 3);
+        }
+    }
+
+    #[test]
+    fn test_k_means_same_seed() {
+        // Groups that share words, so where it starts matters
+        let points = groups(&[&[0, 1, 2], &[1, 2, 3], &[2, 3, 4], &[4, 5, 0]], 8, 2);
+        let a = k_means(&points, 3, 20, 2, 11);
+        let b = k_means(&points, 3, 20, 2, 11);
+        assert_eq!(a.assignments, b.assignments);
+        assert_eq!(a.centroids, b.centroids);
+        assert_eq!(a.error, b.error);
+    }
+
+    #[test]
+    fn test_k_means_k_out_of_range() {
+        let points = groups(&[&[0], &[1], &[2]], 1, 3);
+        assert_eq!(k_means(&points, 5, 10, 1, 1).centroids.len(), 3);
+        assert_eq!(k_means(&points, 0, 10, 1, 1).centroids.len(), 1);
+        assert!(close(k_means(&points, 3, 10, 1, 1).error, 0.0));
+    }
+
+    fn scores_of(silhouette: f64, wcss: f64) -> Scores {
+        Scores { silhouette: silhouette, wcss: wcss, davies_bouldin: 0.0 }
+    }
+
+    #[test]
+    fn test_pick_k() {
+        assert_eq!(pick_k(&[], KChoice::Silhouette), None);
+        assert_eq!(pick_k(&[], KChoice::Elbow), None);
+        let scan = vec![(2, scores_of(0.1, 10.0)), (3, scores_of(0.5, 4.0)), (4, scores_of(0.5, 3.5)), (5, scores_of(0.2, 3.0))];
+        // Ties go to the smaller k
+        assert_eq!(pick_k(&scan, KChoice::Silhouette), Some(3));
+        assert_eq!(pick_k(&scan, KChoice::Elbow), Some(3));
+        assert_eq!(pick_k(&scan[..1], KChoice::Silhouette), Some(2));
+        assert_eq!(pick_k(&scan[..1], KChoice::Elbow), Some(2));
+        // WCSS that never drops has no elbow
+        let flat = vec![(1, scores_of(0.0, 2.0)), (2, scores_of(0.0, 2.0))];
+        assert_eq!(pick_k(&flat, KChoice::Elbow), Some(1));
+    }
+
+    #[test]
+    fn test_pick_planted_k() {
+        let points = groups(&[&[0, 1, 2], &[10, 11, 12], &[20, 21, 22], &[30, 31, 32]], 10, 4);
+        let scan = (1..9).map(|k| (k, scores(&points, &k_means(&points, k, 20, 3, 5)))).collect::<Vec<_>>();
+        assert_eq!(pick_k(&scan, KChoice::Silhouette), Some(4));
+        assert_eq!(pick_k(&scan, KChoice::Elbow), Some(4));
+    }
+
+    #[test]
+    fn test_k_choice_from_str() {
+        assert_eq!("Silhouette".parse::<KChoice>(), Ok(KChoice::Silhouette));
+        assert_eq!("elbow".parse::<KChoice>(), Ok(KChoice::Elbow));
+        assert!("knee".parse::<KChoice>().is_err());
+    }
+
+    #[test]
+    fn test_scores() {
+        // Each cluster is a word on its own and a word shared with it, 45 degrees apart, so
+        // both centroids are 22.5 degrees from each of their members
+        let points = vec![v(&[(0, 1.0)]), v(&[(0, 1.0), (1, 1.0)]), v(&[(2, 1.0)]), v(&[(1, 1.0), (2, 1.0)])];
+        let s = scores(&points, &clustering_of(&points, vec![0, 0, 1, 1]));
+        let (half, other) = (FRAC_1_SQRT_2, 0.75);
+        // Points 1 and 3 share word 1, so their other cluster is only 0.75 away on average
+        assert!(close(s.silhouette, (half + (other - (1.0 - half)) / other) / 2.0));
+        assert!(close(s.wcss, 8.0 * (1.0 - (PI / 8.0).cos())));
+        let spread = 1.0 - (PI / 8.0).cos();
+        assert!(close(s.davies_bouldin, 2.0 * spread / (PI / 8.0).cos().powi(2)));
+    }
+
+    #[test]
+    fn test_scores_separate() {
+        let points = vec![v(&[(0, 1.0)]), v(&[(0, 2.0)]), v(&[(1, 1.0)]), v(&[(1, 3.0)])];
+        let s = scores(&points, &clustering_of(&points, vec![0, 0, 1, 1]));
+        assert!(close(s.silhouette, 1.0));
+        assert!(close(s.wcss, 0.0));
+        assert!(close(s.davies_bouldin, 0.0));
+    }
+
+    #[test]
+    fn test_scores_same_centroid() {
+        // Both centroids point halfway between words 0 and 1
+        let points = vec![v(&[(0, 1.0)]), v(&[(1, 1.0)]), v(&[(0, 1.0), (1, 1.0)])];
+        let s = scores(&points, &clustering_of(&points, vec![0, 0, 1]));
+        assert!(s.davies_bouldin.is_finite());
+        assert!(s.davies_bouldin > 1e6);
+        // The first two are closer to the other cluster than to each other
+        assert!(close(s.silhouette, -2.0 * FRAC_1_SQRT_2 / 3.0));
+        assert!(close(s.wcss, 4.0 - 4.0 * FRAC_1_SQRT_2));
+    }
+
+    #[test]
+    fn test_scores_singletons() {
+        let points = vec![v(&[(0, 1.0)]), v(&[(0, 1.0), (1, 1.0)]), v(&[(1, 1.0)])];
+        let s = scores(&points, &clustering_of(&points, vec![0, 1, 2]));
+        assert_eq!(s.silhouette, 0.0);
+        assert!(close(s.wcss, 0.0));
+        assert!(close(s.davies_bouldin, 0.0));
+
+        // One cluster has nothing to be compared to
+        let s = scores(&points, &clustering_of(&points, vec![0, 0, 0]));
+        assert_eq!(s.silhouette, 0.0);
+        assert_eq!(s.davies_bouldin, 0.0);
+    }
+
+    #[test]
+    fn test_scores_skip_empty_clusters() {
+        let points = vec![v(&[(0, 1.0)]), v(&[(0, 1.0), (1, 1.0)]), v(&[(2, 1.0)]), v(&[(1, 1.0), (2, 1.0)])];
+        let c = clustering_of(&points, vec![0, 0, 1, 1]);
+        let expected = scores(&points, &c);
+        // An empty cluster right on top of cluster 0 would make Davies-Bouldin huge
+        let mut centroids = c.centroids.clone();
+        centroids.push(c.centroids[0].clone());
+        let with_empty = Clustering { assignments: c.assignments.clone(), centroids: centroids, iterations: 0, error: c.error };
+        let s = scores(&points, &with_empty);
+        assert!(close(s.silhouette, expected.silhouette));
+        assert!(close(s.wcss, expected.wcss));
+        assert!(close(s.davies_bouldin, expected.davies_bouldin));
+    }
+}
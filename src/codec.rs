@@ -0,0 +1,672 @@
+use std::io::{ Read, Write };
+use std::io;
+use std::fmt;
+use std::error::Error;
+use storage::invalid_data;
+
+/*
+                            ********************************
+                            *        BINARY ENCODING       *
+                            ********************************
+
+    Everything that gets written to disk goes through the Serialize trait in here, so a file
+    written on one machine can be read on any other one.
+
+        u8          -> 1 byte.
+        u32, u64    -> 4 and 8 bytes, little endian. usize is always written as a u64.
+        i64         -> Two's complement, written like a u64.
+        bool        -> 1 byte, 0 or 1.
+        Option<T>   -> A bool saying whether there is one, and then the T if there is.
+        varint      -> An unsigned integer in 7 bit groups, least significant group first. The
+                       high bit of every byte is set if there is another byte after it. Used
+                       for lengths and counts, which are almost always small.
+        String      -> A varint byte length, then that many bytes of UTF-8.
+
+    Pointers into files are always fixed width u64s, since they get written over in place.
+
+    Records (see the record! macro) are for things that are likely to grow new fields, like
+    Tweets. They are laid out as:
+
+        varint      -> The version. This only changes when an old field changes what it means,
+                       and a record with a version other than the one the binary knows about
+                       won't be read at all.
+        varint      -> The number of fields that follow.
+        fields      -> Every field is a varint byte length and then the field itself.
+
+    Adding a field to the end of a record doesn't need a new version. An older binary skips
+    the fields it doesn't know about (the lengths say how far), and a newer binary gives any
+    field that isn't there its default value.
+*/
+
+/// Something that can be written to and read back from a file.
+pub trait Serialize {
+    /// What deserialize gives back. This is almost always Self.
+    type DeserializeOutput;
+
+    fn serialize(&self, to: &mut Write) -> Result<(), io::Error>;
+    fn deserialize(from: &mut Read) -> Result<Self::DeserializeOutput, io::Error>;
+
+    /// The number of bytes serialize will write.
+    fn serialized_len(&self) -> u64;
+}
+
+/// Reads a little endian u64 out of the first 8 bytes of b.
+pub fn u64_from_le(b: &[u8]) -> u64 {
+    let mut x = 0u64;
+    for i in 0..8 {
+        x |= (b[i] as u64) << (8 * i);
+    }
+    x
+}
+
+/// Writes x as a little endian u64 into the first 8 bytes of b.
+pub fn u64_to_le(x: u64, b: &mut [u8]) {
+    for i in 0..8 {
+        b[i] = (x >> (8 * i)) as u8;
+    }
+}
+
+pub fn write_varint(mut x: u64, to: &mut Write) -> Result<(), io::Error> {
+    let mut buf = [0u8; 10];
+    let mut len = 0;
+    loop {
+        let byte = (x & 0x7F) as u8;
+        x >>= 7;
+        if x == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    to.write_all(&buf[..len])
+}
+
+pub fn read_varint(from: &mut Read) -> Result<u64, io::Error> {
+    let mut x = 0u64;
+    let mut byte = [0u8; 1];
+    for i in 0..10 {
+        if let Err(e) = from.read_exact(&mut byte) {
+            return Err(e)
+        }
+        x |= ((byte[0] & 0x7F) as u64) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok(x)
+        }
+    }
+    Err(invalid_data("varint is longer than 10 bytes".to_string()))
+}
+
+/// The number of bytes write_varint will write for x.
+pub fn varint_len(mut x: u64) -> u64 {
+    let mut len = 1;
+    while x >= 0x80 {
+        x >>= 7;
+        len += 1;
+    }
+    len
+}
+
+/// Reads exactly `len` bytes. This doesn't allocate `len` bytes up front, so a garbage length
+/// runs into the end of the file rather than out of memory.
+pub fn read_bytes(from: &mut Read, len: u64) -> Result<Vec<u8>, io::Error> {
+    let mut buf = Vec::new();
+    if let Err(e) = from.take(len).read_to_end(&mut buf) {
+        return Err(e)
+    }
+    if (buf.len() as u64) < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("expected {} bytes, only found {}", len, buf.len())))
+    }
+    Ok(buf)
+}
+
+impl Serialize for u8 {
+    type DeserializeOutput = u8;
+    fn serialize(&self, to: &mut Write) -> Result<(), io::Error> {
+        to.write_all(&[*self])
+    }
+    fn deserialize(from: &mut Read) -> Result<u8, io::Error> {
+        let mut b = [0u8; 1];
+        match from.read_exact(&mut b) {
+            Ok(()) => Ok(b[0]),
+            Err(e) => Err(e)
+        }
+    }
+    fn serialized_len(&self) -> u64 { 1 }
+}
+
+impl Serialize for bool {
+    type DeserializeOutput = bool;
+    fn serialize(&self, to: &mut Write) -> Result<(), io::Error> {
+        (if *self { 1u8 } else { 0u8 }).serialize(to)
+    }
+    fn deserialize(from: &mut Read) -> Result<bool, io::Error> {
+        match u8::deserialize(from) {
+            Ok(0) => Ok(false),
+            Ok(1) => Ok(true),
+            Ok(x) => Err(invalid_data(format!("{} is not a bool", x))),
+            Err(e) => Err(e)
+        }
+    }
+    fn serialized_len(&self) -> u64 { 1 }
+}
+
+impl Serialize for u32 {
+    type DeserializeOutput = u32;
+    fn serialize(&self, to: &mut Write) -> Result<(), io::Error> {
+        let mut b = [0u8; 4];
+        for i in 0..4 {
+            b[i] = (*self >> (8 * i)) as u8;
+        }
+        to.write_all(&b)
+    }
+    fn deserialize(from: &mut Read) -> Result<u32, io::Error> {
+        let mut b = [0u8; 4];
+        if let Err(e) = from.read_exact(&mut b) {
+            return Err(e)
+        }
+        let mut x = 0u32;
+        for i in 0..4 {
+            x |= (b[i] as u32) << (8 * i);
+        }
+        Ok(x)
+    }
+    fn serialized_len(&self) -> u64 { 4 }
+}
+
+impl Serialize for u64 {
+    type DeserializeOutput = u64;
+    fn serialize(&self, to: &mut Write) -> Result<(), io::Error> {
+        let mut b = [0u8; 8];
+        u64_to_le(*self, &mut b);
+        to.write_all(&b)
+    }
+    fn deserialize(from: &mut Read) -> Result<u64, io::Error> {
+        let mut b = [0u8; 8];
+        match from.read_exact(&mut b) {
+            Ok(()) => Ok(u64_from_le(&b)),
+            Err(e) => Err(e)
+        }
+    }
+    fn serialized_len(&self) -> u64 { 8 }
+}
+
+impl Serialize for i64 {
+    type DeserializeOutput = i64;
+    fn serialize(&self, to: &mut Write) -> Result<(), io::Error> {
+        (*self as u64).serialize(to)
+    }
+    fn deserialize(from: &mut Read) -> Result<i64, io::Error> {
+        match u64::deserialize(from) {
+            Ok(x) => Ok(x as i64),
+            Err(e) => Err(e)
+        }
+    }
+    fn serialized_len(&self) -> u64 { 8 }
+}
+
+impl Serialize for usize {
+    type DeserializeOutput = usize;
+    fn serialize(&self, to: &mut Write) -> Result<(), io::Error> {
+        (*self as u64).serialize(to)
+    }
+    fn deserialize(from: &mut Read) -> Result<usize, io::Error> {
+        match u64::deserialize(from) {
+            Ok(x) => Ok(x as usize),
+            Err(e) => Err(e)
+        }
+    }
+    fn serialized_len(&self) -> u64 { 8 }
+}
+
+impl Serialize for String {
+    type DeserializeOutput = String;
+    fn serialize(&self, to: &mut Write) -> Result<(), io::Error> {
+        if let Err(e) = write_varint(self.len() as u64, to) {
+            return Err(e)
+        }
+        to.write_all(self.as_bytes())
+    }
+    fn deserialize(from: &mut Read) -> Result<String, io::Error> {
+        let len;
+        match read_varint(from) {
+            Ok(x) => len = x,
+            Err(e) => return Err(e)
+        }
+        let bytes;
+        match read_bytes(from, len) {
+            Ok(x) => bytes = x,
+            Err(e) => return Err(e)
+        }
+        match String::from_utf8(bytes) {
+            Ok(s) => Ok(s),
+            Err(_) => Err(invalid_data("string is not valid UTF-8".to_string()))
+        }
+    }
+    fn serialized_len(&self) -> u64 {
+        varint_len(self.len() as u64) + self.len() as u64
+    }
+}
+
+impl<T> Serialize for Option<T> where T: Serialize<DeserializeOutput = T> {
+    type DeserializeOutput = Option<T>;
+    fn serialize(&self, to: &mut Write) -> Result<(), io::Error> {
+        if let Err(e) = self.is_some().serialize(to) {
+            return Err(e)
+        }
+        match *self {
+            Some(ref x) => x.serialize(to),
+            None => Ok(())
+        }
+    }
+    fn deserialize(from: &mut Read) -> Result<Option<T>, io::Error> {
+        match bool::deserialize(from) {
+            Ok(true) => T::deserialize(from).map(Some),
+            Ok(false) => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+    fn serialized_len(&self) -> u64 {
+        1 + self.as_ref().map(|x| x.serialized_len()).unwrap_or(0)
+    }
+}
+
+/// Anything with a length prefix: a varint count, then every element.
+impl<T> Serialize for Vec<T> where T: Serialize<DeserializeOutput = T> {
+    type DeserializeOutput = Vec<T>;
+    fn serialize(&self, to: &mut Write) -> Result<(), io::Error> {
+        if let Err(e) = write_varint(self.len() as u64, to) {
+            return Err(e)
+        }
+        for x in self.iter() {
+            if let Err(e) = x.serialize(to) {
+                return Err(e)
+            }
+        }
+        Ok(())
+    }
+    fn deserialize(from: &mut Read) -> Result<Vec<T>, io::Error> {
+        let len;
+        match read_varint(from) {
+            Ok(x) => len = x,
+            Err(e) => return Err(e)
+        }
+        // Don't trust len for the capacity, it could be garbage.
+        let mut v = Vec::new();
+        for _ in 0..len {
+            match T::deserialize(from) {
+                Ok(x) => v.push(x),
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(v)
+    }
+    fn serialized_len(&self) -> u64 {
+        self.iter().fold(varint_len(self.len() as u64), |acc, x| acc + x.serialized_len())
+    }
+}
+
+/// Fixed size arrays are written without a length, since it's part of the type. Those are what
+/// get used for things that are written over in place.
+macro_rules! array_impls {
+    ( $( $n:expr )* ) => ( $(
+        impl<T> Serialize for [T; $n] where T: Serialize<DeserializeOutput = T> + Copy + Default {
+            type DeserializeOutput = [T; $n];
+            fn serialize(&self, to: &mut Write) -> Result<(), io::Error> {
+                for x in self.iter() {
+                    if let Err(e) = x.serialize(to) {
+                        return Err(e)
+                    }
+                }
+                Ok(())
+            }
+            fn deserialize(from: &mut Read) -> Result<[T; $n], io::Error> {
+                let mut a = [T::default(); $n];
+                for x in a.iter_mut() {
+                    match T::deserialize(from) {
+                        Ok(y) => *x = y,
+                        Err(e) => return Err(e)
+                    }
+                }
+                Ok(a)
+            }
+            fn serialized_len(&self) -> u64 {
+                self.iter().fold(0, |acc, x| acc + x.serialized_len())
+            }
+        }
+    )* )
+}
+
+array_impls!(1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31 32
+             33 34 35 36 37 38 39 40 41 42 43 44 45 46 47 48 49 50 51 52 53 54 55 56 57 58 59 60 61 62 63 64);
+
+/// Writes the Serialize impl for a struct, one field after another in the order they're listed.
+/// Every field has to be listed with its type, and every field of the struct has to be there,
+/// otherwise it won't compile (deserialize builds the struct out of them).
+///
+///     serializable!(Tweet { id: u64, text: String });
+///     serializable!(TweetList(0: Vec<Tweet>));
+///
+/// Generic structs list their type parameters, what deserialize gives back, and the bounds:
+///
+///     serializable!(<K, V> Entry => Entry<K::DeserializeOutput, V::DeserializeOutput>
+///                   where [K: Serialize, V: Serialize] { key: K, value: V });
+macro_rules! serializable {
+    ( $name:ident { $( $f:ident : $t:ty ),* $(,)* } ) => (
+        serializable!(<> $name => $name where [] { $( $f : $t ),* });
+    );
+    ( $name:ident ( $( $i:tt : $t:ty ),* $(,)* ) ) => (
+        impl ::codec::Serialize for $name {
+            type DeserializeOutput = $name;
+            fn serialize(&self, to: &mut ::std::io::Write) -> Result<(), ::std::io::Error> {
+                $( if let Err(e) = ::codec::Serialize::serialize(&self.$i, to) { return Err(e) } )*
+                Ok(())
+            }
+            fn deserialize(from: &mut ::std::io::Read) -> Result<$name, ::std::io::Error> {
+                Ok($name( $( match <$t as ::codec::Serialize>::deserialize(from) {
+                    Ok(x) => x,
+                    Err(e) => return Err(e)
+                } ),* ))
+            }
+            fn serialized_len(&self) -> u64 {
+                0 $( + ::codec::Serialize::serialized_len(&self.$i) )*
+            }
+        }
+    );
+    ( < $( $g:ident ),* > $name:ident => $out:ty where [ $( $bound:tt )* ] { $( $f:ident : $t:ty ),* $(,)* } ) => (
+        impl< $( $g ),* > ::codec::Serialize for $name< $( $g ),* > where $( $bound )* {
+            type DeserializeOutput = $out;
+            fn serialize(&self, to: &mut ::std::io::Write) -> Result<(), ::std::io::Error> {
+                $( if let Err(e) = ::codec::Serialize::serialize(&self.$f, to) { return Err(e) } )*
+                Ok(())
+            }
+            fn deserialize(from: &mut ::std::io::Read) -> Result<$out, ::std::io::Error> {
+                $( let $f = match <$t as ::codec::Serialize>::deserialize(from) {
+                    Ok(x) => x,
+                    Err(e) => return Err(e)
+                }; )*
+                Ok($name { $( $f: $f ),* })
+            }
+            fn serialized_len(&self) -> u64 {
+                0 $( + ::codec::Serialize::serialized_len(&self.$f) )*
+            }
+        }
+    );
+}
+
+/// A record that was written with a different version than the one this binary knows about.
+#[derive(Debug, Clone)]
+pub struct VersionMismatch {
+    /// What kind of record it was, e.g. "Tweet"
+    pub what: String,
+    pub found: u64,
+    pub expected: u64
+}
+
+impl fmt::Display for VersionMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} record is version {}, expected version {}", self.what, self.found, self.expected)
+    }
+}
+
+impl Error for VersionMismatch {
+    fn description(&self) -> &str {
+        "record version mismatch"
+    }
+}
+
+pub fn version_mismatch(what: &str, found: u64, expected: u64) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, VersionMismatch { what: what.to_string(), found: found, expected: expected })
+}
+
+/// Returns the VersionMismatch inside of e, if that's what e is.
+pub fn as_version_mismatch(e: &io::Error) -> Option<&VersionMismatch> {
+    match e.get_ref() {
+        Some(inner) => inner.downcast_ref::<VersionMismatch>(),
+        None => None
+    }
+}
+
+/// Reads the bytes of one record field and deserializes them. The field doesn't have to use all
+/// of them, whatever it doesn't use is skipped.
+pub fn read_field<T: Serialize>(from: &mut Read) -> Result<T::DeserializeOutput, io::Error> {
+    let len;
+    match read_varint(from) {
+        Ok(x) => len = x,
+        Err(e) => return Err(e)
+    }
+    let bytes;
+    match read_bytes(from, len) {
+        Ok(x) => bytes = x,
+        Err(e) => return Err(e)
+    }
+    T::deserialize(&mut &bytes[..])
+}
+
+/// Skips over a record field without looking at it.
+pub fn skip_field(from: &mut Read) -> Result<(), io::Error> {
+    let len;
+    match read_varint(from) {
+        Ok(x) => len = x,
+        Err(e) => return Err(e)
+    }
+    match io::copy(&mut from.take(len), &mut io::sink()) {
+        Ok(n) if n == len => Ok(()),
+        Ok(n) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("expected {} bytes, only found {}", len, n))),
+        Err(e) => Err(e)
+    }
+}
+
+/// Like serializable!, but writes a versioned record (see the top of this file) so that fields
+/// can be added to the end later on without breaking anything that is already on disk. Every
+/// field has to implement Default, which is what it gets if an older record doesn't have it.
+///
+///     record!(Tweet, version 1 { id: u64, text: String });
+macro_rules! record {
+    ( $name:ident, version $version:tt { $( $f:ident : $t:ty ),* $(,)* } ) => (
+        impl ::codec::Serialize for $name {
+            type DeserializeOutput = $name;
+            fn serialize(&self, to: &mut ::std::io::Write) -> Result<(), ::std::io::Error> {
+                if let Err(e) = ::codec::write_varint($version, to) { return Err(e) }
+                if let Err(e) = ::codec::write_varint(0 $( + { stringify!($f); 1 } )*, to) { return Err(e) }
+                $(
+                    if let Err(e) = ::codec::write_varint(::codec::Serialize::serialized_len(&self.$f), to) { return Err(e) }
+                    if let Err(e) = ::codec::Serialize::serialize(&self.$f, to) { return Err(e) }
+                )*
+                Ok(())
+            }
+            fn deserialize(from: &mut ::std::io::Read) -> Result<$name, ::std::io::Error> {
+                let version = match ::codec::read_varint(from) {
+                    Ok(x) => x,
+                    Err(e) => return Err(e)
+                };
+                if version != $version {
+                    return Err(::codec::version_mismatch(stringify!($name), version, $version))
+                }
+                let mut fields = match ::codec::read_varint(from) {
+                    Ok(x) => x,
+                    Err(e) => return Err(e)
+                };
+                $( let $f = if fields == 0 {
+                    <$t as Default>::default()
+                } else {
+                    fields -= 1;
+                    match ::codec::read_field::<$t>(from) {
+                        Ok(x) => x,
+                        Err(e) => return Err(e)
+                    }
+                }; )*
+                // Anything left over was written by a newer binary.
+                for _ in 0..fields {
+                    if let Err(e) = ::codec::skip_field(from) { return Err(e) }
+                }
+                Ok($name { $( $f: $f ),* })
+            }
+            fn serialized_len(&self) -> u64 {
+                let fields: u64 = 0 $( + { stringify!($f); 1 } )*;
+                ::codec::varint_len($version) + ::codec::varint_len(fields)
+                    $( + { let len = ::codec::Serialize::serialized_len(&self.$f); ::codec::varint_len(len) + len } )*
+            }
+        }
+    );
+}
+
+/// Serializes x, checks that it wrote exactly serialized_len bytes, and reads it back, checking
+/// that it's equal to x and that none of the bytes were left over.
+pub fn round_trip<T>(x: &T) -> Result<(), String>
+    where T: Serialize + PartialEq<T::DeserializeOutput> {
+    let mut bytes = Vec::new();
+    if let Err(e) = x.serialize(&mut bytes) {
+        return Err(format!("serialize failed: {}", e))
+    }
+    if bytes.len() as u64 != x.serialized_len() {
+        return Err(format!("serialized_len is {} but {} bytes were written", x.serialized_len(), bytes.len()))
+    }
+    let mut from = &bytes[..];
+    let y = match T::deserialize(&mut from) {
+        Ok(y) => y,
+        Err(e) => return Err(format!("deserialize failed: {}", e))
+    };
+    if !from.is_empty() {
+        return Err(format!("deserialize left {} of {} bytes unread", from.len(), bytes.len()))
+    }
+    if *x != y {
+        return Err("it came back different".to_string())
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ Serialize, round_trip, write_varint, read_varint, varint_len, as_version_mismatch };
+
+    #[derive(PartialEq, Debug)]
+    struct Point {
+        x: u64,
+        y: i64,
+        label: String
+    }
+
+    serializable!(Point { x: u64, y: i64, label: String });
+
+    #[derive(PartialEq, Debug)]
+    struct Points(Vec<Point>, Option<u32>);
+
+    serializable!(Points(0: Vec<Point>, 1: Option<u32>));
+
+    #[derive(PartialEq, Debug)]
+    struct Pair<A, B> {
+        first: A,
+        second: B
+    }
+
+    serializable!(<A, B> Pair => Pair<A::DeserializeOutput, B::DeserializeOutput>
+                  where [A: Serialize, B: Serialize] { first: A, second: B });
+
+    /// The same record as it was first written, and then after fields were added to it, and
+    /// then after one of them changed what it meant.
+    #[derive(PartialEq, Debug)]
+    struct Old {
+        id: u64
+    }
+
+    record!(Old, version 1 { id: u64 });
+
+    #[derive(PartialEq, Debug)]
+    struct New {
+        id: u64,
+        text: String,
+        replies: Option<u64>
+    }
+
+    record!(New, version 1 { id: u64, text: String, replies: Option<u64> });
+
+    #[derive(PartialEq, Debug)]
+    struct Newer {
+        id: u64
+    }
+
+    record!(Newer, version 2 { id: u64 });
+
+    fn point(x: u64) -> Point {
+        Point { x: x, y: -(x as i64), label: format!("point {} é 🧀", x) }
+    }
+
+    #[test]
+    fn test_round_trip_struct() {
+        round_trip(&point(0)).unwrap();
+        round_trip(&point(u64::max_value())).unwrap();
+        round_trip(&Point { x: 1, y: i64::min_value(), label: String::new() }).unwrap();
+    }
+
+    #[test]
+    fn test_round_trip_tuple_struct() {
+        round_trip(&Points(vec![], None)).unwrap();
+        round_trip(&Points((0..100).map(point).collect(), Some(7))).unwrap();
+    }
+
+    #[test]
+    fn test_round_trip_generic() {
+        round_trip(&Pair { first: 3u8, second: true }).unwrap();
+        round_trip(&Pair { first: "key".to_string(), second: vec![1u64, 2, 3] }).unwrap();
+        round_trip(&Pair { first: point(5), second: Pair { first: [9u32; 4], second: Some(point(6)) } }).unwrap();
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut bytes = vec![];
+        point(12345).serialize(&mut bytes).unwrap();
+        for len in 0..bytes.len() {
+            assert!(Point::deserialize(&mut &bytes[..len]).is_err(), "read a Point out of {} of {} bytes", len, bytes.len());
+        }
+    }
+
+    #[test]
+    fn test_varint() {
+        for &x in [0u64, 1, 127, 128, 300, 16383, 16384, u32::max_value() as u64, u64::max_value()].iter() {
+            let mut bytes = vec![];
+            write_varint(x, &mut bytes).unwrap();
+            assert_eq!(bytes.len() as u64, varint_len(x));
+            assert_eq!(read_varint(&mut &bytes[..]).unwrap(), x);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_record() {
+        round_trip(&Old { id: 1 }).unwrap();
+        round_trip(&New { id: 2, text: "é🧀".to_string(), replies: Some(3) }).unwrap();
+        round_trip(&New { id: 0, text: String::new(), replies: None }).unwrap();
+    }
+
+    #[test]
+    fn test_record_missing_fields() {
+        let mut bytes = vec![];
+        Old { id: 7 }.serialize(&mut bytes).unwrap();
+        let new = New::deserialize(&mut &bytes[..]).unwrap();
+        assert_eq!(new, New { id: 7, text: String::new(), replies: None });
+    }
+
+    #[test]
+    fn test_record_extra_fields() {
+        // Whatever comes after the record has to still be readable, so the extra fields have
+        // to be skipped rather than left unread
+        let mut bytes = vec![];
+        New { id: 7, text: "new".to_string(), replies: Some(3) }.serialize(&mut bytes).unwrap();
+        "after".to_string().serialize(&mut bytes).unwrap();
+        let mut from = &bytes[..];
+        assert_eq!(Old::deserialize(&mut from).unwrap(), Old { id: 7 });
+        assert_eq!(String::deserialize(&mut from).unwrap(), "after");
+        assert!(from.is_empty());
+    }
+
+    #[test]
+    fn test_record_newer_version() {
+        let mut bytes = vec![];
+        Newer { id: 7 }.serialize(&mut bytes).unwrap();
+        let e = Old::deserialize(&mut &bytes[..]).unwrap_err();
+        let mismatch = as_version_mismatch(&e).expect("expected a VersionMismatch");
+        assert_eq!((mismatch.found, mismatch.expected), (2, 1));
+        assert_eq!(mismatch.what, "Old");
+    }
+}
@@ -0,0 +1,133 @@
+use std::io::{ Read, Write };
+use std::io;
+use std::marker::PhantomData;
+use flate2;
+use flate2::write::DeflateEncoder;
+use flate2::read::DeflateDecoder;
+use codec::{ Serialize, write_varint, read_varint, varint_len, read_bytes };
+use storage::invalid_data;
+
+/*
+                            ********************************
+                            *      VALUE COMPRESSION       *
+                            ********************************
+
+    PHash and PTree store which Compression they were created with in their header, and every
+    value they write is a Packed value:
+
+        8 bits  -> The flag. 0 if the value is stored as is, 1 if it is DEFLATE compressed.
+        varint  -> The number of bytes that follow.
+        n bytes -> The value.
+
+    Every value has a flag, so a store can hold both kinds. Small values, and values that don't
+    get any smaller, are always stored as is, even if the store is compressed.
+*/
+
+/// Values smaller than this aren't worth compressing.
+const MIN_COMPRESS_LEN: usize = 64;
+
+const FLAG_RAW: u8 = 0;
+const FLAG_DEFLATE: u8 = 1;
+
+/// How a store compresses its values.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Compression {
+    None,
+    Deflate
+}
+
+impl Compression {
+    pub fn from_u8(x: u8) -> Result<Compression, io::Error> {
+        match x {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Deflate),
+            _ => Err(invalid_data(format!("{} is not a kind of compression", x)))
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match *self {
+            Compression::None => 0,
+            Compression::Deflate => 1
+        }
+    }
+}
+
+/// A serialized value of type T, which may or may not be compressed.
+pub struct Packed<T> {
+    flag: u8,
+    bytes: Vec<u8>,
+    phantom: PhantomData<T>
+}
+
+impl<T: Serialize> Packed<T> {
+    /// Serializes x, compressing it if `compression` says to and it's worth it.
+    pub fn new(x: &T, compression: Compression) -> Result<Packed<T>, io::Error> {
+        let mut raw = Vec::with_capacity(x.serialized_len() as usize);
+        if let Err(e) = x.serialize(&mut raw) {
+            return Err(e)
+        }
+        if compression == Compression::Deflate && raw.len() >= MIN_COMPRESS_LEN {
+            let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::Default);
+            if let Err(e) = encoder.write_all(&raw) {
+                return Err(e)
+            }
+            match encoder.finish() {
+                Ok(compressed) => if compressed.len() < raw.len() {
+                    return Ok(Packed { flag: FLAG_DEFLATE, bytes: compressed, phantom: PhantomData })
+                },
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(Packed { flag: FLAG_RAW, bytes: raw, phantom: PhantomData })
+    }
+
+    /// Decompresses (if it has to) and deserializes the value.
+    pub fn unpack(&self) -> Result<T::DeserializeOutput, io::Error> {
+        match self.flag {
+            FLAG_RAW => T::deserialize(&mut &self.bytes[..]),
+            FLAG_DEFLATE => T::deserialize(&mut DeflateDecoder::new(&self.bytes[..])),
+            x => Err(invalid_data(format!("{} is not a value flag", x)))
+        }
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.flag == FLAG_DEFLATE
+    }
+}
+
+impl<T> Serialize for Packed<T> {
+    type DeserializeOutput = Packed<T>;
+
+    fn serialize(&self, to: &mut Write) -> Result<(), io::Error> {
+        if let Err(e) = self.flag.serialize(to) {
+            return Err(e)
+        }
+        if let Err(e) = write_varint(self.bytes.len() as u64, to) {
+            return Err(e)
+        }
+        to.write_all(&self.bytes)
+    }
+
+    fn deserialize(from: &mut Read) -> Result<Packed<T>, io::Error> {
+        let flag = match u8::deserialize(from) {
+            Ok(x) => x,
+            Err(e) => return Err(e)
+        };
+        if flag != FLAG_RAW && flag != FLAG_DEFLATE {
+            return Err(invalid_data(format!("{} is not a value flag", flag)))
+        }
+        let len = match read_varint(from) {
+            Ok(x) => x,
+            Err(e) => return Err(e)
+        };
+        match read_bytes(from, len) {
+            Ok(bytes) => Ok(Packed { flag: flag, bytes: bytes, phantom: PhantomData }),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn serialized_len(&self) -> u64 {
+        1 + varint_len(self.bytes.len() as u64) + self.bytes.len() as u64
+    }
+}
@@ -0,0 +1,495 @@
+use std::collections::BTreeMap;
+use phash::PHash;
+use ptree::PTree;
+use storage::{ MemFile, CrashSwitch, CrashFile, Fault };
+use rng::Rng;
+use compress::Compression;
+use codec::{ Serialize, round_trip, read_varint, write_varint };
+use twitter::{ Tweet, TweetList, FrequencyTable, Entities };
+use vocab::Vocabulary;
+use weighting::DocumentFrequencies;
+use tokenize::Pipeline;
+use error::Error;
+
+/*
+                            ********************************
+                            *   CRASH CONSISTENCY TESTING  *
+                            ********************************
+
+    A workload of random inserts and removes is run against a store that lives in memory.
+    It is run once to count how many writes it does, then once for every one of those writes,
+    "crashing" on that write. A crash either drops the write or tears it in half, and every
+    write after it never makes it to the disk. Whatever is left on the "disk" is then opened
+    again and checked:
+
+        - It has to open.
+        - scrub() has to pass, so every list ends, every pointer points somewhere real, and
+          every record that can be reached has the right checksum.
+        - Every operation that finished before the crash has to be there. The operation that
+          was interrupted can either be there or not be there, but nothing else.
+        - It has to take more inserts and removes, and then pass all of the above again.
+*/
+
+#[derive(Clone)]
+enum Op {
+    Insert(String, String),
+    Remove(String)
+}
+
+/// The result of running a crash test.
+pub struct Report {
+    /// The number of different crash points that were tested.
+    pub crash_points: u64,
+    /// A description of everything that went wrong.
+    pub failures: Vec<String>
+}
+
+/// Creates a random workload. Keys are drawn from a small pool so that there are plenty of
+/// overwrites and removes of keys that actually exist.
+fn workload(rng: &mut Rng, num_ops: usize) -> Vec<Op> {
+    let pool = if num_ops / 3 > 1 { num_ops / 3 } else { 1 };
+    let mut ops = Vec::with_capacity(num_ops);
+    for _ in 0..num_ops {
+        // Values are different lengths so that freed space gets reused (or doesn't), and some
+        // are long enough to get compressed.
+        let value = (0..rng.range(1, 120)).map(|_| (b'a' + rng.range(0, 26) as u8) as char).collect::<String>();
+        if rng.range(0, 4) == 0 {
+            ops.push(Op::Remove(format!("key{}", rng.range(0, pool))));
+        } else {
+            ops.push(Op::Insert(format!("key{}", rng.range(0, pool)), value));
+        }
+    }
+    ops
+}
+
+fn key_of(op: &Op) -> &String {
+    match *op {
+        Op::Insert(ref k, _) => k,
+        Op::Remove(ref k) => k
+    }
+}
+
+/// Operations that are run on a store after it's been opened again, to make sure that whatever
+/// a crash left behind can still be written to. Most of them insert keys of their own, and then
+/// one key of the workload is overwritten and another one is removed.
+fn after_ops(ops: &[Op]) -> Vec<Op> {
+    let mut after = (0..6).map(|i| Op::Insert(format!("after{}", i), format!("value{}", i))).collect::<Vec<_>>();
+    let mut keys = ops.iter().map(key_of).collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+    if let Some(k) = keys.get(0) {
+        after.push(Op::Insert((*k).clone(), "written after the crash".to_string()));
+    }
+    if let Some(k) = keys.get(1) {
+        after.push(Op::Remove((*k).clone()));
+    }
+    after
+}
+
+/// Applies the first `n` operations to an empty map.
+fn model(ops: &[Op], n: usize) -> BTreeMap<String, String> {
+    let mut map = BTreeMap::new();
+    for op in ops[..n].iter() {
+        match *op {
+            Op::Insert(ref k, ref v) => { map.insert(k.clone(), v.clone()); },
+            Op::Remove(ref k) => { map.remove(k); }
+        }
+    }
+    map
+}
+
+/// Checks that `actual` is the value for `key` either before or after the interrupted op.
+fn check_value(key: &str, actual: Option<String>, before: &BTreeMap<String, String>, after: &BTreeMap<String, String>) -> Result<(), String> {
+    let (b, a) = (before.get(key), after.get(key));
+    if actual.as_ref() == b || actual.as_ref() == a {
+        Ok(())
+    } else {
+        Err(format!("key '{}' is {:?}, expected {:?} (or {:?})", key, actual, b, a))
+    }
+}
+
+/// Checks the value of every key in `ops` and `after` with get. Keys that were written by `after`
+/// have to be exactly what it left them as, the rest either what they were before the operation
+/// the crash interrupted or after it.
+fn check_keys<F>(ops: &[Op], outcome: &Outcome, after: &[Op], mut get: F) -> Result<(), String>
+    where F: FnMut(&String) -> Result<Option<String>, Error> {
+    let before = model(ops, outcome.completed);
+    let interrupted = model(ops, if outcome.completed < ops.len() { outcome.completed + 1 } else { ops.len() });
+    let mut written = BTreeMap::new();
+    for op in after.iter() {
+        match *op {
+            Op::Insert(ref k, ref v) => { written.insert(k.clone(), Some(v.clone())); },
+            Op::Remove(ref k) => { written.insert(k.clone(), None); }
+        }
+    }
+    for op in ops.iter().chain(after.iter()) {
+        let key = key_of(op);
+        let actual = match get(key) {
+            Ok(x) => x,
+            Err(e) => return Err(format!("reading '{}' failed: {}", key, e))
+        };
+        match written.get(key) {
+            Some(expected) => if actual != *expected {
+                return Err(format!("key '{}' is {:?}, expected {:?}", key, actual, expected))
+            },
+            None => if let Err(e) = check_value(key, actual, &before, &interrupted) {
+                return Err(e)
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The state of the disk after running a workload (and maybe crashing).
+struct Outcome {
+    files: Vec<MemFile>,
+    /// Whether the store was created before the crash
+    created: bool,
+    /// The number of operations that finished before the crash
+    completed: usize,
+    /// An operation failed even though there was no crash
+    error: Option<String>
+}
+
+fn run_phash(ops: &[Op], switch: &CrashSwitch) -> Outcome {
+    let (manifest, dat) = (MemFile::new(), MemFile::new());
+    let mut outcome = Outcome {
+        files: vec![manifest.reopen(), dat.reopen()],
+        created: false,
+        completed: 0,
+        error: None
+    };
+    let res = PHash::<String, String, CrashFile<MemFile>>::create(switch.wrap(manifest), switch.wrap(dat), Compression::Deflate);
+    let mut table = match res {
+        Ok(t) => t,
+        Err(e) => {
+            if !switch.crashed() { outcome.error = Some(format!("create failed: {}", e)); }
+            return outcome
+        }
+    };
+    if switch.crashed() { return outcome }
+    outcome.created = true;
+
+    for (i, op) in ops.iter().enumerate() {
+        let res = match *op {
+            Op::Insert(ref k, ref v) => table.insert(k, v),
+            Op::Remove(ref k) => table.remove(k).map(|_| ())
+        };
+        if switch.crashed() { return outcome }
+        if let Err(e) = res {
+            outcome.error = Some(format!("op {} failed: {}", i, e));
+            return outcome
+        }
+        outcome.completed = i + 1;
+    }
+    outcome
+}
+
+fn check_phash(ops: &[Op], outcome: &Outcome) -> Result<(), String> {
+    if !outcome.created { return Ok(()) }
+    let res = PHash::<String, String, MemFile>::load(outcome.files[0].reopen(), outcome.files[1].reopen());
+    let mut table = match res {
+        Ok(t) => t,
+        Err(e) => return Err(format!("failed to reopen: {}", e))
+    };
+    if let Err(e) = table.scrub() {
+        return Err(format!("scrub failed: {}", e))
+    }
+    if let Err(e) = check_keys(ops, outcome, &[], |k| table.get(k)) {
+        return Err(e)
+    }
+
+    let after = after_ops(ops);
+    for op in after.iter() {
+        let res = match *op {
+            Op::Insert(ref k, ref v) => table.insert(k, v),
+            Op::Remove(ref k) => table.remove(k).map(|_| ())
+        };
+        if let Err(e) = res {
+            return Err(format!("after reopening, writing '{}' failed: {}", key_of(op), e))
+        }
+    }
+    if let Err(e) = table.scrub() {
+        return Err(format!("after reopening, scrub failed: {}", e))
+    }
+    check_keys(ops, outcome, &after, |k| table.get(k)).map_err(|e| format!("after reopening, {}", e))
+}
+
+fn run_ptree(ops: &[Op], switch: &CrashSwitch) -> Outcome {
+    let (tree, keys, vals) = (MemFile::new(), MemFile::new(), MemFile::new());
+    let mut outcome = Outcome {
+        files: vec![tree.reopen(), keys.reopen(), vals.reopen()],
+        created: false,
+        completed: 0,
+        error: None
+    };
+    let res = PTree::<String, String, CrashFile<MemFile>>::create(switch.wrap(tree), switch.wrap(keys), switch.wrap(vals), Compression::Deflate);
+    let mut tree = match res {
+        Ok(t) => t,
+        Err(e) => {
+            if !switch.crashed() { outcome.error = Some(format!("create failed: {}", e)); }
+            return outcome
+        }
+    };
+    if switch.crashed() { return outcome }
+    outcome.created = true;
+
+    for (i, op) in ops.iter().enumerate() {
+        let res = match *op {
+            Op::Insert(ref k, ref v) => tree.insert(k, v),
+            Op::Remove(ref k) => tree.remove(k).map(|_| ())
+        };
+        if switch.crashed() { return outcome }
+        if let Err(e) = res {
+            outcome.error = Some(format!("op {} failed: {}", i, e));
+            return outcome
+        }
+        outcome.completed = i + 1;
+    }
+    outcome
+}
+
+fn check_ptree(ops: &[Op], outcome: &Outcome) -> Result<(), String> {
+    if !outcome.created { return Ok(()) }
+    let res = PTree::<String, String, MemFile>::load(outcome.files[0].reopen(), outcome.files[1].reopen(), outcome.files[2].reopen());
+    let mut tree = match res {
+        Ok(t) => t,
+        Err(e) => return Err(format!("failed to reopen: {}", e))
+    };
+    if let Err(e) = tree.scrub() {
+        return Err(format!("scrub failed: {}", e))
+    }
+    if let Err(e) = check_keys(ops, outcome, &[], |k| tree.search(k)) {
+        return Err(e)
+    }
+
+    let after = after_ops(ops);
+    for op in after.iter() {
+        let res = match *op {
+            Op::Insert(ref k, ref v) => tree.insert(k, v),
+            Op::Remove(ref k) => tree.remove(k).map(|_| ())
+        };
+        if let Err(e) = res {
+            return Err(format!("after reopening, writing '{}' failed: {}", key_of(op), e))
+        }
+    }
+    if let Err(e) = tree.scrub() {
+        return Err(format!("after reopening, scrub failed: {}", e))
+    }
+    check_keys(ops, outcome, &after, |k| tree.search(k)).map_err(|e| format!("after reopening, {}", e))
+}
+
+/// Runs `ops` once without crashing, then once for every write it did with each kind of
+/// fault, checking what is left on the disk every time.
+fn crash_everywhere<R, C>(name: &str, ops: &[Op], run: R, check: C) -> Report
+    where R: Fn(&[Op], &CrashSwitch) -> Outcome,
+          C: Fn(&[Op], &Outcome) -> Result<(), String> {
+    let mut report = Report { crash_points: 0, failures: vec![] };
+
+    let counter = CrashSwitch::never();
+    let clean = run(ops, &counter);
+    if let Some(ref e) = clean.error {
+        report.failures.push(format!("{}: without crashing, {}", name, e));
+        return report
+    }
+    if let Err(e) = check(ops, &clean) {
+        report.failures.push(format!("{}: without crashing, {}", name, e));
+        return report
+    }
+
+    let total_writes = counter.writes();
+    for at in 0..total_writes {
+        for &fault in [Fault::Drop, Fault::Tear].iter() {
+            report.crash_points += 1;
+            let switch = CrashSwitch::new(at, fault);
+            let outcome = run(ops, &switch);
+            let res = match outcome.error {
+                Some(ref e) => Err(e.clone()),
+                None => check(ops, &outcome)
+            };
+            if let Err(e) = res {
+                report.failures.push(format!("{}: crash on write {} of {} ({:?}) after {} ops, {}",
+                                             name, at, total_writes, fault, outcome.completed, e));
+            }
+        }
+    }
+    report
+}
+
+/// Crash tests PHash with random inserts and removes.
+pub fn crash_test_phash(seed: u64, num_ops: usize) -> Report {
+    let mut rng = Rng::new(seed);
+    let ops = workload(&mut rng, num_ops);
+    crash_everywhere("PHash", &ops, run_phash, check_phash)
+}
+
+/// Crash tests PTree with random inserts and removes.
+pub fn crash_test_ptree(seed: u64, num_ops: usize) -> Report {
+    let mut rng = Rng::new(seed);
+    let ops = workload(&mut rng, num_ops);
+    crash_everywhere("PTree", &ops, run_ptree, check_ptree)
+}
+
+/// A random string with some multi byte characters thrown in, so string lengths are never
+/// mixed up with character counts.
+fn random_text(rng: &mut Rng, max_words: usize) -> String {
+    let words = ["the", "rust", "é", "naïve", "🧀", "#hashtag", "@someone", "", "x"];
+    (0..rng.range(0, max_words + 1)).map(|_| words[rng.range(0, words.len())]).collect::<Vec<_>>().join(" ")
+}
+
+fn random_tweet(rng: &mut Rng) -> Tweet {
+    let text = random_text(rng, 20);
+    Tweet {
+        stripped_text: text.to_lowercase(),
+        text: text,
+        id: rng.next_u64(),
+        handle: random_text(rng, 1),
+        created_at: random_option(rng).map(|x| x as i64),
+        retweet_count: rng.next_u64() as u32,
+        favorite_count: rng.range(0, 100) as u32,
+        reply_to: random_option(rng),
+        quoted: random_option(rng),
+        retweet_of: random_option(rng),
+        lang: random_text(rng, 1),
+        entities: Entities {
+            hashtags: (0..rng.range(0, 3)).map(|_| random_text(rng, 1)).collect(),
+            mentions: (0..rng.range(0, 3)).map(|_| random_text(rng, 1)).collect(),
+            urls: (0..rng.range(0, 3)).map(|_| random_text(rng, 2)).collect()
+        }
+    }
+}
+
+fn random_option(rng: &mut Rng) -> Option<u64> {
+    if rng.range(0, 2) == 0 { None } else { Some(rng.next_u64()) }
+}
+
+/// Serializes and deserializes random Tweets, TweetLists, FrequencyTables, the Vocabulary and
+/// DocumentFrequencies they were counted with, and a tokenizer Pipeline, and returns a
+/// description of every one that didn't come back the same. Tweets are also read back with
+/// fields added and taken away, like they were written by a newer or older binary.
+pub fn round_trip_test(seed: u64, count: usize) -> Vec<String> {
+    let mut rng = Rng::new(seed);
+    let mut failures = vec![];
+    let mut vocab = Vocabulary::new();
+    let mut df = DocumentFrequencies::new();
+    let plain = Pipeline::default();
+    // Every other table is stemmed, so some of them have surface words to write
+    let stemming = "entities,extract,drop=url,segment,lowercase,stem".parse::<Pipeline>().unwrap();
+    for i in 0..count {
+        let tokenizer = if i % 2 == 0 { &plain } else { &stemming };
+        let tweets = (0..rng.range(0, 8)).map(|_| random_tweet(&mut rng)).collect::<Vec<_>>();
+        let mut table = FrequencyTable::new();
+        for t in tweets.iter() {
+            table.add_tweet(t, tokenizer, &mut vocab);
+        }
+        if let Some(t) = tweets.first() {
+            if let Err(e) = round_trip(t) {
+                failures.push(format!("Tweet {}: {}", i, e));
+            }
+        }
+        if let Err(e) = round_trip(&table) {
+            failures.push(format!("FrequencyTable {}: {}", i, e));
+        }
+        df.add(&table);
+        if let Some(t) = tweets.first() {
+            if let Err(e) = compatibility(t, &mut rng) {
+                failures.push(format!("Tweet {}: {}", i, e));
+            }
+        }
+        if let Err(e) = round_trip(&TweetList(tweets)) {
+            failures.push(format!("TweetList {}: {}", i, e));
+        }
+    }
+    if let Err(e) = round_trip(&vocab) {
+        failures.push(format!("Vocabulary: {}", e));
+    }
+    if let Err(e) = round_trip(&df) {
+        failures.push(format!("DocumentFrequencies: {}", e));
+    }
+    let pipeline = "entities,extract,drop=emoji,segment,lowercase,stopwords=the+a,auto-stopwords=3,min-length=2,stem,ngrams=3,phrases=2:2.5".parse::<Pipeline>().unwrap();
+    if let Err(e) = round_trip(&pipeline) {
+        failures.push(format!("Pipeline: {}", e));
+    }
+    failures
+}
+
+/// Rewrites a record as if it had `fields` fields, either by dropping fields off the end or
+/// by adding random ones.
+fn change_fields(bytes: &[u8], fields: u64, rng: &mut Rng) -> Vec<u8> {
+    let mut from = bytes;
+    let version = read_varint(&mut from).unwrap();
+    let had = read_varint(&mut from).unwrap();
+    let mut out = vec![];
+    write_varint(version, &mut out).unwrap();
+    write_varint(fields, &mut out).unwrap();
+    for i in 0..fields {
+        if i < had {
+            let len = read_varint(&mut from).unwrap();
+            write_varint(len, &mut out).unwrap();
+            out.extend_from_slice(&from[..len as usize]);
+            from = &from[len as usize..];
+        } else {
+            let junk = (0..rng.range(0, 20)).map(|_| rng.next_u64() as u8).collect::<Vec<u8>>();
+            write_varint(junk.len() as u64, &mut out).unwrap();
+            out.extend_from_slice(&junk);
+        }
+    }
+    out
+}
+
+/// Checks that a Tweet can still be read if it was written by a binary that knew about more
+/// fields, or by one that knew about fewer.
+fn compatibility(t: &Tweet, rng: &mut Rng) -> Result<(), String> {
+    let mut bytes = vec![];
+    if let Err(e) = t.serialize(&mut bytes) {
+        return Err(format!("serialize failed: {}", e))
+    }
+
+    // Past the version, to the number of fields
+    let mut from = &bytes[..];
+    read_varint(&mut from).unwrap();
+    let fields = read_varint(&mut from).unwrap();
+    let newer = change_fields(&bytes, fields + 3, rng);
+    match Tweet::deserialize(&mut &newer[..]) {
+        Ok(ref x) if x == t => {},
+        Ok(_) => return Err("extra fields changed the fields that were known".to_string()),
+        Err(e) => return Err(format!("failed to skip extra fields: {}", e))
+    }
+
+    let older = change_fields(&bytes, 2, rng);
+    let defaults = Tweet { id: t.id, stripped_text: t.stripped_text.clone(), ..Tweet::default() };
+    match Tweet::deserialize(&mut &older[..]) {
+        Ok(ref x) if *x == defaults => Ok(()),
+        Ok(_) => Err("missing fields didn't come back as defaults".to_string()),
+        Err(e) => Err(format!("failed to fill in missing fields: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ Report, crash_test_phash, crash_test_ptree, round_trip_test };
+
+    fn assert_clean(report: Report) {
+        assert!(report.crash_points > 0);
+        assert!(report.failures.is_empty(), "{} of {} crash points failed, the first ones:\n{}",
+                report.failures.len(), report.crash_points, report.failures.iter().take(5).cloned().collect::<Vec<_>>().join("\n"));
+    }
+
+    #[test]
+    fn test_crash_phash() {
+        // Enough ops to fill the table past 3/4, so a crash while growing is covered too
+        assert_clean(crash_test_phash(7, 40));
+    }
+
+    #[test]
+    fn test_crash_ptree() {
+        // Enough keys for the root to split, and then for removes to empty out some leaves
+        assert_clean(crash_test_ptree(7, 150));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let failures = round_trip_test(7, 50);
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+}
@@ -0,0 +1,114 @@
+/*
+                            ********************************
+                            *            DBSCAN            *
+                            ********************************
+
+    Density based clustering. k-means and the dendrogram put everyone in some group, even
+    people who tweet about nothing like anyone else. DBSCAN only groups people that are in a
+    crowd, and says the rest are noise.
+
+    Two users are neighbours if the cosine distance between them (1 - their similarity) is at
+    most eps. A user with at least min_points neighbours (counting themself) is a core user.
+    A group is every core user that can be reached from some core user by going from core
+    user to neighbouring core user, plus every user that neighbours one of them (the border
+    users). Anyone left over is noise.
+
+    A border user that neighbours core users from two groups goes in whichever group found
+    them first. Users are looked at in order, so that's the group with the smallest core
+    user, and the same input always gives the same groups.
+*/
+
+/// Clusters the n things in similarity, an n by n matrix where similarity[i][j] is how
+/// similar i and j are, from 0 (nothing in common) to 1 (the same). Returns the group of
+/// every one of them, numbered from 0 in the order they were found, or None for noise.
+pub fn dbscan(similarity: &[Vec<f64>], eps: f64, min_points: usize) -> Vec<Option<usize>> {
+    let n = similarity.len();
+    let neighbours = (0..n).map(|i| {
+        (0..n).filter(|&j| 1.0 - similarity[i][j] <= eps || i == j).collect::<Vec<usize>>()
+    }).collect::<Vec<_>>();
+    let core = neighbours.iter().map(|ns| ns.len() >= min_points).collect::<Vec<bool>>();
+
+    let mut labels = vec![None; n];
+    let mut groups = 0;
+    for i in 0..n {
+        if labels[i].is_some() || !core[i] {
+            continue
+        }
+        labels[i] = Some(groups);
+        let mut stack = vec![i];
+        while let Some(p) = stack.pop() {
+            for &q in neighbours[p].iter() {
+                if labels[q].is_some() {
+                    continue
+                }
+                labels[q] = Some(groups);
+                // Border users join the group, but it doesn't grow through them
+                if core[q] {
+                    stack.push(q);
+                }
+            }
+        }
+        groups += 1;
+    }
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::dbscan;
+
+    /// A similarity matrix for n things, where the given pairs are the given distance apart
+    /// and everything else has nothing in common.
+    fn similarity(n: usize, close: &[(usize, usize, f64)]) -> Vec<Vec<f64>> {
+        let mut s = vec![vec![0.0; n]; n];
+        for i in 0..n {
+            s[i][i] = 1.0;
+        }
+        for &(i, j, d) in close {
+            s[i][j] = 1.0 - d;
+            s[j][i] = 1.0 - d;
+        }
+        s
+    }
+
+    /// 0, 1 and 2 are close together, 3 is near 2, 4 is on its own, and 5, 6 and 7 are close
+    /// together.
+    fn crowds() -> Vec<Vec<f64>> {
+        similarity(8, &[(0, 1, 0.1), (0, 2, 0.1), (1, 2, 0.1), (2, 3, 0.3), (5, 6, 0.2), (5, 7, 0.2), (6, 7, 0.2), (3, 5, 0.6)])
+    }
+
+    #[test]
+    fn test_core_border_noise() {
+        // 3 only has 2 neighbours (2 and itself), so it's a border user of 2's group
+        assert_eq!(dbscan(&crowds(), 0.35, 3), vec![Some(0), Some(0), Some(0), Some(0), None, Some(1), Some(1), Some(1)]);
+        // 3 isn't close enough to 2 anymore, so it's noise
+        assert_eq!(dbscan(&crowds(), 0.25, 3), vec![Some(0), Some(0), Some(0), None, None, Some(1), Some(1), Some(1)]);
+        // Nothing is close enough to anything
+        assert_eq!(dbscan(&crowds(), 0.05, 2), vec![None; 8]);
+    }
+
+    #[test]
+    fn test_min_points_counts_itself() {
+        // Each of 5, 6 and 7 has 2 neighbours besides itself, which is enough for 3 but not 4
+        assert_eq!(dbscan(&crowds(), 0.35, 3)[5..].to_vec(), vec![Some(1); 3]);
+        // Only 2 has 4 (0, 1, 3 and itself), so it's the only core user, and 0, 1 and 3 are
+        // its border
+        assert_eq!(dbscan(&crowds(), 0.35, 4), vec![Some(0), Some(0), Some(0), Some(0), None, None, None, None]);
+        // Everyone is core with 1, even on their own
+        assert_eq!(dbscan(&crowds(), 0.35, 1), vec![Some(0), Some(0), Some(0), Some(0), Some(1), Some(2), Some(2), Some(2)]);
+        assert_eq!(dbscan(&crowds(), 0.35, 0), dbscan(&crowds(), 0.35, 1));
+    }
+
+    #[test]
+    fn test_border_between_groups() {
+        // 4 is a border user of both crowds, and goes with the one found first
+        let s = similarity(9, &[(0, 1, 0.1), (0, 2, 0.1), (0, 3, 0.1), (1, 2, 0.1), (1, 3, 0.1), (2, 3, 0.1), (3, 4, 0.2),
+                                (4, 5, 0.2), (5, 6, 0.1), (5, 7, 0.1), (5, 8, 0.1), (6, 7, 0.1), (6, 8, 0.1), (7, 8, 0.1)]);
+        assert_eq!(dbscan(&s, 0.25, 4), vec![Some(0), Some(0), Some(0), Some(0), Some(0), Some(1), Some(1), Some(1), Some(1)]);
+    }
+
+    #[test]
+    fn test_empty() {
+        assert!(dbscan(&[], 0.5, 3).is_empty());
+    }
+}
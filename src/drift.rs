@@ -0,0 +1,198 @@
+use std::str::FromStr;
+use twitter::FrequencyTable;
+
+/*
+                            ********************************
+                            *        VOCABULARY DRIFT      *
+                            ********************************
+
+    What someone tweets about changes: a campaign starts, a bill comes up, something happens
+    back home. To see that, every tweet (that has a created_at, see Tweet) is counted into a
+    table for the window of time it was tweeted in, by week or by month, separately for every
+    user. Windows are numbered in order:
+
+        week        -> Weeks since the one 1970-01-01 was in, starting on Mondays (UTC).
+        month       -> year * 12 + month - 1.
+
+    A user's drift from one window they tweeted in to the next one they tweeted in is the
+    cosine distance between the two tables, weighed like users are when they're grouped:
+    0 if they used their words in the same proportions, 1 if they had no words in common.
+    Windows they didn't tweet in are skipped, rather than counting as a jump to nothing and
+    back. Next to each distance are the words that rose and fell most, by how much of what
+    they said was that word.
+*/
+
+/// How long the windows are.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Period {
+    Week,
+    Month
+}
+
+impl FromStr for Period {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Period, String> {
+        match &s.to_lowercase()[..] {
+            "week" => Ok(Period::Week),
+            "month" => Ok(Period::Month),
+            _ => Err(format!("{} is not week or month", s))
+        }
+    }
+}
+
+impl Period {
+    /// The window a time (in seconds since 1970, UTC) is in.
+    pub fn window(&self, time: i64) -> i64 {
+        let days = floor_div(time, 24 * 60 * 60);
+        match *self {
+            // 1970-01-01 was a Thursday, so the week it's in started 3 days earlier
+            Period::Week => floor_div(days + 3, 7),
+            Period::Month => {
+                let (year, month, _) = civil(days);
+                year * 12 + month - 1
+            }
+        }
+    }
+
+    /// When a window starts: the date of its Monday for weeks, like 2017-03-06, and the year
+    /// and month for months, like 2017-03.
+    pub fn label(&self, window: i64) -> String {
+        match *self {
+            Period::Week => {
+                let (year, month, day) = civil(window * 7 - 3);
+                format!("{:04}-{:02}-{:02}", year, month, day)
+            },
+            Period::Month => format!("{:04}-{:02}", floor_div(window, 12), window - floor_div(window, 12) * 12 + 1)
+        }
+    }
+}
+
+/// Division that rounds down, even for negative numbers.
+fn floor_div(a: i64, b: i64) -> i64 {
+    let q = a / b;
+    if a % b != 0 && (a < 0) != (b < 0) { q - 1 } else { q }
+}
+
+/// The year, month and day of the day that's `days` days after 1970-01-01. This is Howard
+/// Hinnant's civil_from_days, which counts in 400 year eras that start on March 1st, so that
+/// leap days are at the end of the year.
+fn civil(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = floor_div(z, 146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Months counted from March
+    let m = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * m + 2) / 5 + 1;
+    let month = if m < 10 { m + 3 } else { m - 9 };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+/// The words a user tweeted in one window.
+pub struct Window {
+    pub window: i64,
+    pub table: FrequencyTable
+}
+
+/// How much a user's words changed from one window to the next one they tweeted in.
+pub struct Step {
+    pub from: i64,
+    pub to: i64,
+    /// The cosine distance between the two windows
+    pub distance: f64,
+    /// The words whose share of the user's words went up the most, and by how much, most
+    /// first
+    pub rose: Vec<(String, f64)>,
+    /// The same for the words that went down, with negative changes
+    pub fell: Vec<(String, f64)>
+}
+
+/// The average distance of the steps, or 0 if there aren't any.
+pub fn average(steps: &[Step]) -> f64 {
+    if steps.is_empty() {
+        0.0
+    } else {
+        steps.iter().map(|s| s.distance).sum::<f64>() / steps.len() as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ Period, Step, floor_div, civil, average };
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    #[test]
+    fn test_floor_div() {
+        assert_eq!(floor_div(7, 2), 3);
+        assert_eq!(floor_div(-7, 2), -4);
+        assert_eq!(floor_div(-6, 2), -3);
+        assert_eq!(floor_div(7, -2), -4);
+        assert_eq!(floor_div(0, 5), 0);
+        assert_eq!(floor_div(-1, DAY), -1);
+    }
+
+    #[test]
+    fn test_civil() {
+        assert_eq!(civil(0), (1970, 1, 1));
+        assert_eq!(civil(-1), (1969, 12, 31));
+        assert_eq!(civil(59), (1970, 3, 1));
+        // 2000 is a leap year, 1900 isn't
+        assert_eq!(civil(11016), (2000, 2, 29));
+        assert_eq!(civil(11017), (2000, 3, 1));
+        assert_eq!(civil(-25508), (1900, 3, 1));
+        assert_eq!(civil(17166), (2016, 12, 31));
+        assert_eq!(civil(17167), (2017, 1, 1));
+    }
+
+    #[test]
+    fn test_weeks() {
+        // 1970-01-01 was a Thursday, so its week started on Monday 1969-12-29
+        assert_eq!(Period::Week.window(0), 0);
+        assert_eq!(Period::Week.label(0), "1969-12-29");
+        assert_eq!(Period::Week.window(-1), 0);
+        assert_eq!(Period::Week.window(4 * DAY - 1), 0);
+        assert_eq!(Period::Week.window(4 * DAY), 1);
+        assert_eq!(Period::Week.label(1), "1970-01-05");
+        assert_eq!(Period::Week.window(-3 * DAY), 0);
+        assert_eq!(Period::Week.window(-3 * DAY - 1), -1);
+        assert_eq!(Period::Week.label(-1), "1969-12-22");
+        // Monday 2017-03-06 to Sunday 2017-03-12
+        assert_eq!(Period::Week.window(17231 * DAY), 2462);
+        assert_eq!(Period::Week.window(17238 * DAY - 1), 2462);
+        assert_eq!(Period::Week.label(2462), "2017-03-06");
+        // A week across the new year starts in the old one
+        assert_eq!(Period::Week.label(Period::Week.window(17167 * DAY)), "2016-12-26");
+    }
+
+    #[test]
+    fn test_months() {
+        assert_eq!(Period::Month.window(0), 1970 * 12);
+        assert_eq!(Period::Month.label(1970 * 12), "1970-01");
+        assert_eq!(Period::Month.window(-1), 1969 * 12 + 11);
+        assert_eq!(Period::Month.label(1969 * 12 + 11), "1969-12");
+        assert_eq!(Period::Month.label(Period::Month.window(12 * 60 * 60 - 25508 * DAY)), "1900-03");
+        // The last second of a leap day, and the first of March
+        assert_eq!(Period::Month.label(Period::Month.window(11017 * DAY - 1)), "2000-02");
+        assert_eq!(Period::Month.label(Period::Month.window(11017 * DAY)), "2000-03");
+        assert_eq!(Period::Month.label(Period::Month.window(17167 * DAY - 1)), "2016-12");
+        assert_eq!(Period::Month.label(Period::Month.window(17167 * DAY)), "2017-01");
+    }
+
+    #[test]
+    fn test_period_from_str() {
+        assert_eq!("Week".parse::<Period>(), Ok(Period::Week));
+        assert_eq!("month".parse::<Period>(), Ok(Period::Month));
+        assert!("year".parse::<Period>().is_err());
+    }
+
+    #[test]
+    fn test_average() {
+        let step = |d| Step { from: 0, to: 1, distance: d, rose: vec![], fell: vec![] };
+        assert_eq!(average(&[]), 0.0);
+        assert_eq!(average(&[step(0.25), step(0.75), step(0.5)]), 0.5);
+    }
+}
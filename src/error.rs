@@ -0,0 +1,116 @@
+use std::io;
+use std::fmt;
+use std::error;
+use checksum::{ Corruption, as_corruption };
+use codec::{ VersionMismatch, as_version_mismatch };
+
+/// Everything that can go wrong in here. Down in the codec everything is an io::Error (that's
+/// what Read and Write give back), but io::Errors that carry a Corruption or a VersionMismatch
+/// turn back into the right variant when they get converted into one of these.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file failed.
+    Io { context: String, cause: io::Error },
+    /// A record on disk doesn't match its checksum, or doesn't make any sense.
+    Corrupt(Corruption),
+    /// A record was written by a binary with a different version of its layout.
+    VersionMismatch(VersionMismatch),
+    /// A file (or something in one) that was supposed to be there isn't.
+    NotFound(String),
+    /// Talking to twitter failed.
+    Twitter(String),
+    /// Another process has the store open.
+    Locked(String),
+    /// The terminal couldn't be set up for the GUI, or read from.
+    Gui(String),
+    /// Bad arguments, or missing keys.
+    Config(String)
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+impl Error {
+    /// Adds a description of what was going on when the error happened, e.g. "opening
+    /// data/tweet_tree". Context added later ends up at the front of the message.
+    pub fn context(self, context: &str) -> Error {
+        match self {
+            Error::Io { context: c, cause } => Error::Io {
+                context: if c.is_empty() { context.to_string() } else { format!("{}: {}", context, c) },
+                cause: cause
+            },
+            Error::Corrupt(mut c) => {
+                c.what = format!("{}: {}", context, c.what);
+                Error::Corrupt(c)
+            },
+            Error::VersionMismatch(mut v) => {
+                v.what = format!("{}: {}", context, v.what);
+                Error::VersionMismatch(v)
+            },
+            Error::NotFound(m) => Error::NotFound(format!("{}: {}", context, m)),
+            Error::Twitter(m) => Error::Twitter(format!("{}: {}", context, m)),
+            Error::Locked(m) => Error::Locked(format!("{}: {}", context, m)),
+            Error::Gui(m) => Error::Gui(format!("{}: {}", context, m)),
+            Error::Config(m) => Error::Config(format!("{}: {}", context, m))
+        }
+    }
+}
+
+/// Lets a context be added straight to a Result, e.g. PTree::open(path).context("opening the tree")
+pub trait Context<T> {
+    fn context(self, context: &str) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> Context<T> for ::std::result::Result<T, E> {
+    fn context(self, context: &str) -> Result<T> {
+        match self {
+            Ok(x) => Ok(x),
+            Err(e) => Err(e.into().context(context))
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        if let Some(c) = as_corruption(&e) {
+            return Error::Corrupt(c.clone())
+        }
+        if let Some(v) = as_version_mismatch(&e) {
+            return Error::VersionMismatch(v.clone())
+        }
+        if e.kind() == io::ErrorKind::NotFound {
+            return Error::NotFound(format!("{}", e))
+        }
+        Error::Io { context: String::new(), cause: e }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io { ref context, ref cause } if context.is_empty() => write!(f, "{}", cause),
+            Error::Io { ref context, ref cause } => write!(f, "{}: {}", context, cause),
+            Error::Corrupt(ref c) => write!(f, "{}", c),
+            Error::VersionMismatch(ref v) => write!(f, "{}", v),
+            Error::NotFound(ref m) => write!(f, "not found: {}", m),
+            Error::Twitter(ref m) => write!(f, "twitter: {}", m),
+            Error::Locked(ref m) => write!(f, "locked: {}", m),
+            Error::Gui(ref m) => write!(f, "gui: {}", m),
+            Error::Config(ref m) => write!(f, "{}", m)
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io { .. } => "I/O error",
+            Error::Corrupt(_) => "corrupt record",
+            Error::VersionMismatch(_) => "record version mismatch",
+            Error::NotFound(_) => "not found",
+            Error::Twitter(_) => "twitter error",
+            Error::Locked(_) => "locked",
+            Error::Gui(_) => "gui error",
+            Error::Config(_) => "configuration error"
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use std::hash::{ Hasher, BuildHasher };
+
+/*
+                            ********************************
+                            *            FXHASH            *
+                            ********************************
+
+    The hash function rustc uses for its own tables (from Firefox, hence the name). Every word
+    of input is mixed in with a rotate, an xor and a multiply, which is a lot less work than
+    SipHash, and it's good enough for keys nobody is picking on purpose.
+
+    It is NOT good enough for keys that someone might pick to make every one of them land in
+    the same bucket (tweets, for example, if anyone cared enough to attack this). For those,
+    use TwitHash::randomized, which hashes with std's RandomState: SipHash seeded with random
+    keys when the table is made.
+*/
+
+const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+#[derive(Default, Clone, Copy)]
+pub struct FxHasher {
+    hash: u64
+}
+
+impl FxHasher {
+    fn add_to_hash(&mut self, word: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(SEED);
+    }
+}
+
+impl Hasher for FxHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks(8);
+        while let Some(chunk) = chunks.next() {
+            let mut word = 0u64;
+            for (i, b) in chunk.iter().enumerate() {
+                word |= (*b as u64) << (i * 8);
+            }
+            self.add_to_hash(word);
+        }
+    }
+
+    fn write_u8(&mut self, x: u8) {
+        self.add_to_hash(x as u64);
+    }
+
+    fn write_u16(&mut self, x: u16) {
+        self.add_to_hash(x as u64);
+    }
+
+    fn write_u32(&mut self, x: u32) {
+        self.add_to_hash(x as u64);
+    }
+
+    fn write_u64(&mut self, x: u64) {
+        self.add_to_hash(x);
+    }
+
+    fn write_usize(&mut self, x: usize) {
+        self.add_to_hash(x as u64);
+    }
+
+    /// The multiply only mixes bits upwards, so the low bits (which pick the bucket) are the
+    /// worst ones. Rotating brings the well mixed high bits down.
+    fn finish(&self) -> u64 {
+        self.hash.rotate_left(26)
+    }
+}
+
+/// Makes FxHashers. This is what a TwitHash uses unless it's told otherwise.
+#[derive(Default, Clone, Copy)]
+pub struct FxBuildHasher;
+
+impl BuildHasher for FxBuildHasher {
+    type Hasher = FxHasher;
+
+    fn build_hasher(&self) -> FxHasher {
+        FxHasher::default()
+    }
+}
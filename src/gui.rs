@@ -701,6 +701,15 @@ impl GuiTextInput {
     }
 }
 
+/// The first width bytes of s, or fewer if that would cut a character in half.
+fn clip(s: &str, width: usize) -> &str {
+    let mut end = min(width, s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
 pub struct GuiSelection2D {
     choices: Vec<Vec<String>>,
     pub cur_choice_x: usize,
@@ -723,14 +732,12 @@ impl Drawable for GuiSelection2D {
 
         if self.choices.len() == 0 { return; }
         let ref s = self.choices[self.cur_choice_x][self.cur_choice_y];
-        let end = min(self.b.width - 2, s.len());
-        rustbox.print(self.b.x + 3, self.b.y + 1, rustbox::RB_BOLD, self.selected_fg, self.selected_bg, &s[..end]);
+        rustbox.print(self.b.x + 3, self.b.y + 1, rustbox::RB_BOLD, self.selected_fg, self.selected_bg, clip(s, self.b.width - 2));
         if self.choices[self.cur_choice_x].len() == 1 { return; }
 
         for i in 1..min(self.b.height - 2, self.choices[self.cur_choice_x].len()) {
             let ref s = self.choices[self.cur_choice_x][(self.cur_choice_y + i) % self.choices[self.cur_choice_x].len()];
-            let end = min(self.b.width - 4, s.len());
-            rustbox.print(self.b.x + 3, self.b.y + i + 1, rustbox::RB_BOLD, self.fg, self.bg, &s[..end]);
+            rustbox.print(self.b.x + 3, self.b.y + i + 1, rustbox::RB_BOLD, self.fg, self.bg, clip(s, self.b.width - 4));
         }
 
     }
@@ -743,14 +750,12 @@ impl Drawable for GuiSelection2D {
         if self.choices.len() == 0 { return; }
 
         let ref s = self.choices[self.cur_choice_x][self.cur_choice_y];
-        let end = min(self.b.width - 2, s.len());
-        rustbox.print(self.b.x + 3, self.b.y + 1, rustbox::RB_BOLD, self.selected_fg, self.selected_bg, &s[..end]);
+        rustbox.print(self.b.x + 3, self.b.y + 1, rustbox::RB_BOLD, self.selected_fg, self.selected_bg, clip(s, self.b.width - 2));
         if self.choices[self.cur_choice_x].len() == 1 { return; }
 
         for i in 1..min(self.b.height - 2, self.choices[self.cur_choice_x].len()) {
             let ref s = self.choices[self.cur_choice_x][(self.cur_choice_y + i) % self.choices[self.cur_choice_x].len()];
-            let end = min(self.b.width - 4, s.len());
-            rustbox.print(self.b.x + 3, self.b.y + i + 1, rustbox::RB_BOLD, self.fg, self.bg, &s[..end]);
+            rustbox.print(self.b.x + 3, self.b.y + i + 1, rustbox::RB_BOLD, self.fg, self.bg, clip(s, self.b.width - 4));
         }
     }
 
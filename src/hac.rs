@@ -0,0 +1,354 @@
+use std::str::FromStr;
+use std::f64;
+
+/*
+                            ********************************
+                            *   HIERARCHICAL  CLUSTERING   *
+                            ********************************
+
+    Agglomerative clustering: every user starts out as their own cluster, and the two closest
+    clusters are merged over and over until there's only one left. The order things were
+    merged in (and how far apart they were) is a tree, the dendrogram, and cutting the tree
+    at some height (or where it has k branches) gives a flat clustering.
+
+    How far apart two users are is 1 - their similarity, the cosine distance. How far apart
+    two clusters are depends on the linkage:
+
+        Single   -> the distance between their closest members
+        Complete -> the distance between their furthest members
+        Average  -> the average distance between every pair of members, one from each
+        Ward     -> how much merging them would grow the spread inside the cluster
+
+    All four are updated with the Lance-Williams formula when two clusters merge, so only the
+    distances between clusters are kept, never the members. Ward's formula is meant for
+    squared euclidean distances, and for vectors of length 1 the squared euclidean distance
+    is exactly twice the cosine distance, so it works here as is. None of these can ever
+    merge at a smaller distance than a merge before it, so the heights only go up.
+
+    Clusters are numbered like scipy numbers them: the n users are 0 to n - 1, and the
+    cluster made by the i'th merge is n + i.
+
+    Finding the closest pair is a scan over every pair that's left, so it is O(n^3). For a
+    few hundred people in congress that's nothing.
+*/
+
+/// How the distance between two clusters is worked out (see the top of this file).
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Linkage {
+    Single,
+    Complete,
+    Average,
+    Ward
+}
+
+impl FromStr for Linkage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Linkage, String> {
+        match &s.to_lowercase()[..] {
+            "single" => Ok(Linkage::Single),
+            "complete" => Ok(Linkage::Complete),
+            "average" => Ok(Linkage::Average),
+            "ward" => Ok(Linkage::Ward),
+            _ => Err(format!("{} is not single, complete, average or ward", s))
+        }
+    }
+}
+
+/// Two clusters that were merged.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Merge {
+    pub a: usize,
+    pub b: usize,
+    /// How far apart a and b were
+    pub distance: f64,
+    /// How many users are in the new cluster
+    pub size: usize
+}
+
+/// Every merge, in order, from n single users down to one cluster.
+pub struct Dendrogram {
+    leaves: usize,
+    merges: Vec<Merge>
+}
+
+/// Where to cut a dendrogram.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Cut {
+    /// Into (at most) this many groups
+    Groups(usize),
+    /// Wherever the merges get further apart than this
+    Distance(f64)
+}
+
+/// Clusters the n things in similarity, an n by n matrix where similarity[i][j] is how
+/// similar i and j are, from 0 (nothing in common) to 1 (the same).
+pub fn agglomerate(similarity: &[Vec<f64>], linkage: Linkage) -> Dendrogram {
+    let n = similarity.len();
+    // dist[i][j] is only kept for i < j
+    let mut dist = similarity.iter().map(|row| row.iter().map(|s| {
+        let d = 1.0 - s;
+        if d < 0.0 { 0.0 } else { d }
+    }).collect::<Vec<f64>>()).collect::<Vec<_>>();
+    // The number each cluster has in the dendrogram, and its size. A cluster keeps the row of
+    // whichever of the two it was merged from came first, and the other row is dropped.
+    let mut id = (0..n).collect::<Vec<usize>>();
+    let mut size = vec![1; n];
+    let mut alive = (0..n).collect::<Vec<usize>>();
+    let mut merges = Vec::with_capacity(if n == 0 { 0 } else { n - 1 });
+
+    while alive.len() > 1 {
+        let (mut x, mut y) = (0, 1);
+        let mut closest = f64::INFINITY;
+        for ai in 0..alive.len() {
+            for bi in ai + 1..alive.len() {
+                let d = dist[alive[ai]][alive[bi]];
+                if d < closest {
+                    closest = d;
+                    x = ai;
+                    y = bi;
+                }
+            }
+        }
+        let (i, j) = (alive[x], alive[y]);
+        let (ni, nj) = (size[i] as f64, size[j] as f64);
+        for &k in alive.iter() {
+            if k == i || k == j {
+                continue
+            }
+            let (dik, djk) = (get(&dist, i, k), get(&dist, j, k));
+            let nk = size[k] as f64;
+            let d = match linkage {
+                Linkage::Single => dik.min(djk),
+                Linkage::Complete => dik.max(djk),
+                Linkage::Average => (ni * dik + nj * djk) / (ni + nj),
+                Linkage::Ward => ((ni + nk) * dik + (nj + nk) * djk - nk * closest) / (ni + nj + nk)
+            };
+            set(&mut dist, i, k, d);
+        }
+        merges.push(Merge {
+            a: id[i],
+            b: id[j],
+            distance: closest,
+            size: size[i] + size[j]
+        });
+        id[i] = n + merges.len() - 1;
+        size[i] += size[j];
+        alive.remove(y);
+    }
+
+    Dendrogram { leaves: n, merges: merges }
+}
+
+fn get(dist: &[Vec<f64>], i: usize, j: usize) -> f64 {
+    if i < j { dist[i][j] } else { dist[j][i] }
+}
+
+fn set(dist: &mut [Vec<f64>], i: usize, j: usize, d: f64) {
+    if i < j { dist[i][j] = d } else { dist[j][i] = d }
+}
+
+impl Dendrogram {
+    /// The number of things that were clustered.
+    pub fn len(&self) -> usize {
+        self.leaves
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves == 0
+    }
+
+    pub fn merges(&self) -> &[Merge] {
+        &self.merges
+    }
+
+    /// Cuts the tree, returning the group of every leaf. Groups are numbered in the order of
+    /// their first leaf, so leaf 0 is always in group 0.
+    pub fn cut(&self, cut: Cut) -> Vec<usize> {
+        let n = self.leaves;
+        if n == 0 {
+            return vec![]
+        }
+        let count = match cut {
+            Cut::Groups(k) => {
+                let k = if k > n { n } else if k == 0 { 1 } else { k };
+                n - k
+            },
+            Cut::Distance(d) => self.merges.iter().take_while(|m| m.distance <= d).count()
+        };
+        // Union-find over the leaves, with every cluster number pointing at one of its leaves
+        let mut parent = (0..n).collect::<Vec<usize>>();
+        let mut leaf = (0..n).collect::<Vec<usize>>();
+        for m in self.merges.iter().take(count) {
+            let (a, b) = (find(&mut parent, leaf[m.a]), find(&mut parent, leaf[m.b]));
+            parent[b] = a;
+            leaf.push(a);
+        }
+        let mut group = vec![n; n];
+        let mut assignments = Vec::with_capacity(n);
+        let mut groups = 0;
+        for i in 0..n {
+            let root = find(&mut parent, i);
+            if group[root] == n {
+                group[root] = groups;
+                groups += 1;
+            }
+            assignments.push(group[root]);
+        }
+        assignments
+    }
+
+    /// The tree in Newick format, e.g. "((a:0.1,b:0.1):0.2,c:0.3);". names[i] is the name of
+    /// leaf i, and the length of a branch is how much higher its parent was merged than it was.
+    pub fn newick(&self, names: &[String]) -> String {
+        let mut s = String::new();
+        if self.leaves > 0 {
+            let root = self.leaves + self.merges.len() - 1;
+            self.write_node(root, self.height(root), names, &mut s);
+        }
+        s.push(';');
+        s
+    }
+
+    /// How high up the tree a cluster was made. Leaves are at 0.
+    fn height(&self, node: usize) -> f64 {
+        if node < self.leaves { 0.0 } else { self.merges[node - self.leaves].distance }
+    }
+
+    fn write_node(&self, node: usize, parent_height: f64, names: &[String], s: &mut String) {
+        if node < self.leaves {
+            s.push_str(&newick_name(&names[node]));
+        } else {
+            let m = self.merges[node - self.leaves];
+            s.push('(');
+            self.write_node(m.a, m.distance, names, s);
+            s.push(',');
+            self.write_node(m.b, m.distance, names, s);
+            s.push(')');
+        }
+        if node != self.leaves + self.merges.len() - 1 {
+            s.push_str(&format!(":{:.6}", parent_height - self.height(node)));
+        }
+    }
+}
+
+fn find(parent: &mut [usize], mut i: usize) -> usize {
+    while parent[i] != i {
+        parent[i] = parent[parent[i]];
+        i = parent[i];
+    }
+    i
+}
+
+/// Quotes a name if it has anything in it that means something in Newick.
+fn newick_name(name: &str) -> String {
+    if name.chars().any(|c| " ()[]':;,".contains(c)) {
+        format!("'{}'", name.replace("'", "''"))
+    } else {
+        name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ Linkage, Cut, agglomerate, newick_name };
+
+    /// A similarity matrix out of the distances between every pair, d[i][j] for i < j.
+    fn similarity(d: &[&[f64]]) -> Vec<Vec<f64>> {
+        let n = d.len() + 1;
+        let mut s = vec![vec![1.0; n]; n];
+        for i in 0..d.len() {
+            for (j, &x) in d[i].iter().enumerate() {
+                s[i][i + 1 + j] = 1.0 - x;
+                s[i + 1 + j][i] = 1.0 - x;
+            }
+        }
+        s
+    }
+
+    /// 0 and 1 are 0.1 apart, 2 and 3 are 0.2 apart, and the two pairs are further apart.
+    fn pairs() -> Vec<Vec<f64>> {
+        similarity(&[&[0.1, 0.5, 0.6], &[0.4, 0.7], &[0.2]])
+    }
+
+    fn merges(similarity: &[Vec<f64>], linkage: Linkage) -> Vec<(usize, usize, usize)> {
+        agglomerate(similarity, linkage).merges().iter().map(|m| (m.a, m.b, m.size)).collect()
+    }
+
+    fn distances(similarity: &[Vec<f64>], linkage: Linkage) -> Vec<f64> {
+        agglomerate(similarity, linkage).merges().iter().map(|m| m.distance).collect()
+    }
+
+    fn close(a: &[f64], b: &[f64]) -> bool {
+        a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| (x - y).abs() < 1e-9)
+    }
+
+    #[test]
+    fn test_linkages() {
+        let s = pairs();
+        for &l in &[Linkage::Single, Linkage::Complete, Linkage::Average, Linkage::Ward] {
+            assert_eq!(merges(&s, l), vec![(0, 1, 2), (2, 3, 2), (4, 5, 4)], "{:?}", l);
+        }
+        // Between the pairs, single takes 1 to 2, complete takes 1 to 3 and average takes all
+        // four. Ward has 2 at 1.7 / 3 and 3 at 2.5 / 3 from {0, 1}, so (1.7 + 2.5 - 0.4) / 4
+        assert!(close(&distances(&s, Linkage::Single), &[0.1, 0.2, 0.4]));
+        assert!(close(&distances(&s, Linkage::Complete), &[0.1, 0.2, 0.7]));
+        assert!(close(&distances(&s, Linkage::Average), &[0.1, 0.2, 0.55]));
+        assert!(close(&distances(&s, Linkage::Ward), &[0.1, 0.2, 0.95]));
+    }
+
+    #[test]
+    fn test_chaining() {
+        // Four in a row: single linkage chains 2 onto 0 and 1, complete pairs it with 3
+        let s = similarity(&[&[0.1, 0.25, 0.55], &[0.15, 0.45], &[0.2]]);
+        assert_eq!(merges(&s, Linkage::Single), vec![(0, 1, 2), (4, 2, 3), (5, 3, 4)]);
+        assert!(close(&distances(&s, Linkage::Single), &[0.1, 0.15, 0.2]));
+        assert_eq!(merges(&s, Linkage::Complete), vec![(0, 1, 2), (2, 3, 2), (4, 5, 4)]);
+        assert!(close(&distances(&s, Linkage::Complete), &[0.1, 0.2, 0.55]));
+    }
+
+    #[test]
+    fn test_cut() {
+        let d = agglomerate(&pairs(), Linkage::Single);
+        assert_eq!(d.cut(Cut::Groups(1)), vec![0, 0, 0, 0]);
+        assert_eq!(d.cut(Cut::Groups(2)), vec![0, 0, 1, 1]);
+        assert_eq!(d.cut(Cut::Groups(3)), vec![0, 0, 1, 2]);
+        assert_eq!(d.cut(Cut::Groups(4)), vec![0, 1, 2, 3]);
+        assert_eq!(d.cut(Cut::Groups(10)), vec![0, 1, 2, 3]);
+        assert_eq!(d.cut(Cut::Groups(0)), vec![0, 0, 0, 0]);
+        assert_eq!(d.cut(Cut::Distance(0.05)), vec![0, 1, 2, 3]);
+        assert_eq!(d.cut(Cut::Distance(0.3)), vec![0, 0, 1, 1]);
+        assert_eq!(d.cut(Cut::Distance(1.0)), vec![0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_empty() {
+        let d = agglomerate(&[], Linkage::Average);
+        assert!(d.is_empty());
+        assert!(d.merges().is_empty());
+        assert_eq!(d.cut(Cut::Groups(0)), vec![]);
+        assert_eq!(d.cut(Cut::Groups(3)), vec![]);
+        assert_eq!(d.cut(Cut::Distance(0.5)), vec![]);
+        assert_eq!(d.newick(&[]), ";");
+
+        let d = agglomerate(&[vec![1.0]], Linkage::Average);
+        assert_eq!(d.cut(Cut::Groups(2)), vec![0]);
+        assert_eq!(d.newick(&["a".to_string()]), "a;");
+    }
+
+    #[test]
+    fn test_newick() {
+        let names = ["a", "b", "c d", "it's"].iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let d = agglomerate(&pairs(), Linkage::Single);
+        assert_eq!(d.newick(&names), "((a:0.100000,b:0.100000):0.300000,('c d':0.200000,'it''s':0.200000):0.200000);");
+        assert_eq!(newick_name("plain"), "plain");
+        assert_eq!(newick_name("a,b"), "'a,b'");
+    }
+
+    #[test]
+    fn test_linkage_from_str() {
+        assert_eq!("Ward".parse::<Linkage>(), Ok(Linkage::Ward));
+        assert_eq!("single".parse::<Linkage>(), Ok(Linkage::Single));
+        assert!("median".parse::<Linkage>().is_err());
+    }
+}
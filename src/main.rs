@@ -1,6 +1,5 @@
 #![allow(dead_code)]
 #![feature(box_syntax)]
-use std::error::Error;
 use std::default::Default;
 use std::fs::File;
 use std::sync::Mutex;
@@ -8,8 +7,6 @@ use std::cell::RefCell;
 use std::borrow::BorrowMut;
 use std::io::Write;
 use std::fs;
-extern crate regex;
-
 extern crate crossbeam;
 
 #[macro_use]
@@ -22,15 +19,45 @@ use egg_mode::*;
 // Date and Time API
 extern crate chrono;
 
-// Random access file
-extern crate random_access_file;
+// C style FILE
 extern crate cfile_rs;
 
+// DEFLATE
+extern crate flate2;
+
+// CRC32C
+extern crate crc;
+
+// Memory mapped files
+extern crate memmap;
+
 // GUI library (termbox)
 extern crate rustbox;
 use rustbox::RustBox;
 use rustbox::Key;
 
+// error.rs
+mod error;
+use error::Context;
+
+// check.rs
+#[macro_use]
+mod check;
+
+// storage.rs
+mod storage;
+
+// compress.rs
+mod compress;
+use compress::Compression;
+
+// checksum.rs
+mod checksum;
+
+// codec.rs
+#[macro_use]
+mod codec;
+
 // phash.rs
 mod phash;
 use phash::*;
@@ -47,11 +74,32 @@ mod log;
 mod twitter;
 use twitter::TweetList;
 mod twithash;
+use twithash::TwitHash;
+mod fxhash;
+mod vocab;
+mod weighting;
+mod tokenize;
+mod stopwords;
+mod stem;
+mod channel;
+mod drift;
+mod cluster;
+mod hac;
+mod dbscan;
+
+// options.rs
+mod options;
 
 // gui.rs
 mod gui;
 use gui::*;
 
+// rng.rs
+mod rng;
+
+// crashtest.rs
+mod crashtest;
+
 use std::sync::Arc;
 use std::thread;
 
@@ -65,7 +113,7 @@ lazy_static! {
 
 static mut CURRENT_GROUP: usize = 0;
 
-fn load() {
+fn load(tokenizer: &tokenize::Pipeline) -> error::Result<()> {
     // The include_str macro reads the file with the given name into a string at
     // compile time
     // These keys are needed to log in to twitter
@@ -73,6 +121,9 @@ fn load() {
     let con_secret = include_str!("consumer_secret").trim();
     let acc_key = include_str!("access_token").trim();
     let acc_secret = include_str!("access_secret").trim();
+    if con_key.is_empty() || con_secret.is_empty() || acc_key.is_empty() || acc_secret.is_empty() {
+        return Err(error::Error::Config("the twitter keys in src/ are empty, fill them in and rebuild".to_string()))
+    }
 
     // Create the access tokens
     let con_token = egg_mode::KeyPair::new(con_key, con_secret);
@@ -93,10 +144,7 @@ fn load() {
             log!("Successfully authenticated with twitter.");
             val
         },
-        Err(e) => {
-            error!("Failed to authenticate with twitter: {}", e);
-            panic!("");
-        }
+        Err(e) => return Err(error::Error::Twitter(format!("failed to authenticate: {}", e)))
     };
 
     let num_tweets = 200;    // Request up to 200 tweets from each user
@@ -111,10 +159,7 @@ fn load() {
     while users.len() < num_users as usize {
         match iter.call() {
             Ok(mut res) => users.append(&mut res.response.users),
-            _ => {
-                error!("Failed to retrieve list of users.");
-                panic!("");
-            }
+            Err(e) => return Err(error::Error::Twitter(format!("failed to retrieve the list of users: {}", e)))
         };
     }
 
@@ -125,25 +170,32 @@ fn load() {
     }
 
     let mut tweets = vec![];
-    let mut cache_res = PHash::<String, twitter::TweetList>::open("data/tweet_cache");
+    // Only start a new cache if there isn't one yet; a damaged or locked cache shouldn't get
+    // written over.
     let mut cache;
-    if let Ok(c) = cache_res {
-        cache = c;
-    } else {
-        cache = PHash::<String, twitter::TweetList>::new("data/tweet_cache").unwrap();
+    match PHash::<String, twitter::TweetList>::open("data/tweet_cache") {
+        Ok(c) => cache = c,
+        Err(error::Error::NotFound(_)) => {
+            match PHash::<String, twitter::TweetList>::new("data/tweet_cache", Compression::Deflate) {
+                Ok(c) => cache = c,
+                Err(e) => return Err(e)
+            }
+        },
+        Err(e) => return Err(e)
     }
 
     while let Some(user) = users.pop() {
         log!("Loading tweets from user '{}'", user.screen_name);
         // There is already data for this user, pull it out and append it to the list of tweets.
         match cache.get(&user.screen_name) {
-            Some(mut t) => {
+            Ok(Some(mut t)) => {
                 log!("Cache already contained tweets for user {}", user.screen_name);
                 let TweetList(mut list) = t;
                 tweets.append(&mut list);
                 continue;
             },
-            _ => {}
+            Ok(None) => {},
+            Err(e) => return Err(e.context(&format!("reading the cached tweets of {}", user.screen_name)))
         }
 
         // There wasnt data for the user, pull some from twitter!
@@ -154,42 +206,265 @@ fn load() {
         let mut converted_tweets = vec![];
         match timeline.start() {
             Ok(resp) => {
-                for tweet in timeline.start().unwrap().response.into_iter() {
+                for tweet in resp.response.into_iter() {
                     converted_tweets.push(twitter::Tweet::new(tweet));
                 }
             },
             Err(e) => {
-                println!("Encountered error: {}", e);
-                break;
+                let e = error::Error::Twitter(format!("failed to retrieve tweets: {}", e));
+                return Err(e.context(&user.screen_name))
             }
         }
         let mut tweet_list = TweetList(converted_tweets);
-        cache.insert(&user.screen_name, &tweet_list);
+        if let Err(e) = cache.insert(&user.screen_name, &tweet_list) {
+            return Err(e.context(&format!("caching the tweets of {}", user.screen_name)))
+        }
         let TweetList(mut tweets_again) = tweet_list;
         tweets.append(&mut tweets_again);
     }
 
     let mut processor = twitter::TweetProcessor::new();
+    processor.tokenizer = tokenizer.clone();
+    log!("Tokenizing with {}", processor.tokenizer);
 
-    for tweet in tweets.iter() {
-        processor.process_tweet(&tweet);
-    }
+    processor.process_tweets(&tweets);
 
-    let mut tree = PTree::<String, twitter::FrequencyTable>::new("data/tweet_tree").unwrap();
+    let mut tree;
+    match PTree::<String, twitter::FrequencyTable>::new("data/tweet_tree", Compression::Deflate) {
+        Ok(t) => tree = t,
+        Err(e) => return Err(e)
+    }
 
-    for key in processor.map.keys() {
-        match processor.map.get(&key) {
-            Some(table) => {
-                tree.insert(&key, &table);
-            },
-            None => {
-                panic!("This is impossible")
+    for (key, table) in processor.map.iter() {
+        if let Err(e) = tree.insert(key, table) {
+            return Err(e.context(&format!("inserting the frequency table of {}", key)))
+        }
+    }
+    for (channel, tables) in channel::OTHERS.iter().zip(processor.channels.iter()) {
+        let mut tree;
+        match PTree::<String, twitter::FrequencyTable>::new(&channel.tree_path(), Compression::Deflate) {
+            Ok(t) => tree = t,
+            Err(e) => return Err(e)
+        }
+        for (key, table) in tables.map.iter() {
+            if let Err(e) = tree.insert(key, table) {
+                return Err(e.context(&format!("inserting the {} table of {}", channel.name(), key)))
             }
         }
     }
+    // The tables in the tree only make sense with the ids they were made with
+    if let Err(e) = processor.vocab.save("data/vocabulary") {
+        return Err(e)
+    }
+    if let Err(e) = processor.df.save("data/document_frequencies") {
+        return Err(e)
+    }
+    // So that it's known what the words in the tables are
+    processor.tokenizer.save("data/tokenizer")
+}
+
+/// The --tokenizer option of the load command, or the default pipeline.
+fn tokenizer_option(args: &[String]) -> error::Result<tokenize::Pipeline> {
+    let options;
+    match options::Options::parse(args, &["tokenizer"]) {
+        Ok(x) => options = x,
+        Err(e) => return Err(e)
+    }
+    options.get("tokenizer", tokenize::Pipeline::default())
+}
+
+/// Which algorithm the display command groups the users with.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Algorithm {
+    KMeans,
+    /// Agglomerative clustering with the given linkage
+    Hierarchical(hac::Linkage),
+    /// DBSCAN, which leaves out users that aren't like anyone else
+    Density { eps: f64, min_points: usize }
+}
+
+/// How the display command groups the users.
+struct DisplayOptions {
+    algorithm: Algorithm,
+    /// None to pick the number of groups automatically
+    groups: Option<usize>,
+    /// Only used by k-means
+    iters: usize,
+    /// Everything random in the clustering comes from this, so the same seed gives the
+    /// same groups
+    seed: u64,
+    /// How many times k-means is run from different starts (the best run is kept)
+    restarts: usize,
+    /// The smallest and biggest number of groups auto-k tries
+    k_min: usize,
+    k_max: usize,
+    /// How auto-k picks the number of groups
+    pick: cluster::KChoice,
+    /// How words are weighed when users are compared
+    weighting: weighting::Weighting,
+    /// How much each channel counts when users are compared
+    channels: channel::Mix,
+    /// Words to take out of the tables before anything else happens
+    stopwords: Vec<String>,
+    /// How many of the words in the most users' tables to take out too
+    auto_stopwords: usize,
+    /// If set, the dendrogram is cut wherever clusters are further apart than this, instead
+    /// of into a number of groups
+    threshold: Option<f64>,
+    /// Where to write the dendrogram, in Newick format
+    newick: Option<String>
+}
+
+fn display_options(args: &[String]) -> error::Result<DisplayOptions> {
+    let options;
+    match options::Options::parse(args, &["seed", "restarts", "k-min", "k-max", "pick", "algorithm",
+                                          "linkage", "threshold", "newick", "eps", "min-points", "weighting",
+                                          "stopwords", "auto-stopwords", "channels"]) {
+        Ok(x) => options = x,
+        Err(e) => return Err(e)
+    }
+    let linkage;
+    match options.get("linkage", hac::Linkage::Average) {
+        Ok(x) => linkage = x,
+        Err(e) => return Err(e)
+    }
+    let (eps, min_points);
+    match (options.get("eps", 0.5), options.get("min-points", 3)) {
+        (Ok(x), Ok(y)) => { eps = x; min_points = y; },
+        (Err(e), _) | (_, Err(e)) => return Err(e)
+    }
+    let algorithm;
+    match options.get("algorithm", "k-means".to_string()) {
+        Ok(ref x) if x == "k-means" => algorithm = Algorithm::KMeans,
+        Ok(ref x) if x == "hac" => algorithm = Algorithm::Hierarchical(linkage),
+        Ok(ref x) if x == "dbscan" => algorithm = Algorithm::Density { eps: eps, min_points: min_points },
+        Ok(x) => return Err(error::Error::Config(format!("{} is not k-means, hac or dbscan", x))),
+        Err(e) => return Err(e)
+    }
+    match algorithm {
+        Algorithm::KMeans if options.len() < 2 =>
+            return Err(error::Error::Config("display command requires two arguments, the number of groups and the number of iters.".to_string())),
+        Algorithm::Hierarchical(_) if options.len() < 1 =>
+            return Err(error::Error::Config("display command requires the number of groups.".to_string())),
+        _ => {}
+    }
+    let groups;
+    match options.positional::<String>(0, "the number of groups") {
+        Ok(Some(ref x)) if x == "auto" => groups = None,
+        _ => match options.positional(0, "the number of groups") {
+            Ok(x) => groups = x,
+            Err(e) => return Err(e)
+        }
+    }
+    let iters;
+    match options.positional(1, "the number of iters") {
+        Ok(x) => iters = x.unwrap_or(0),
+        Err(e) => return Err(e)
+    }
+    let seed;
+    match options.get("seed", 0) {
+        Ok(x) => seed = x,
+        Err(e) => return Err(e)
+    }
+    let restarts;
+    match options.get("restarts", 10) {
+        Ok(x) => restarts = x,
+        Err(e) => return Err(e)
+    }
+    let (k_min, k_max);
+    match (options.get("k-min", 2), options.get("k-max", 12)) {
+        (Ok(x), Ok(y)) => { k_min = x; k_max = y; },
+        (Err(e), _) | (_, Err(e)) => return Err(e)
+    }
+    let pick;
+    match options.get("pick", cluster::KChoice::Silhouette) {
+        Ok(x) => pick = x,
+        Err(e) => return Err(e)
+    }
+    let weighting;
+    match options.get("weighting", weighting::Weighting::TfIdf) {
+        Ok(x) => weighting = x,
+        Err(e) => return Err(e)
+    }
+    let channels;
+    match options.optional::<String>("channels") {
+        Ok(Some(x)) => match x.parse() {
+            Ok(mix) => channels = mix,
+            Err(e) => return Err(error::Error::Config(e))
+        },
+        Ok(None) => channels = channel::Mix::default(),
+        Err(e) => return Err(e)
+    }
+    let stopwords;
+    match options.optional::<String>("stopwords") {
+        Ok(Some(x)) => match stopwords::parse(&x) {
+            Ok(words) => stopwords = words,
+            Err(e) => return Err(error::Error::Config(e))
+        },
+        Ok(None) => stopwords = vec![],
+        Err(e) => return Err(e)
+    }
+    let auto_stopwords;
+    match options.get("auto-stopwords", 0) {
+        Ok(x) => auto_stopwords = x,
+        Err(e) => return Err(e)
+    }
+    let (threshold, newick);
+    match (options.optional("threshold"), options.optional("newick")) {
+        (Ok(x), Ok(y)) => { threshold = x; newick = y; },
+        (Err(e), _) | (_, Err(e)) => return Err(e)
+    }
+    Ok(DisplayOptions {
+        algorithm: algorithm,
+        groups: groups,
+        iters: iters,
+        seed: seed,
+        restarts: restarts,
+        k_min: k_min,
+        k_max: k_max,
+        pick: pick,
+        weighting: weighting,
+        channels: channels,
+        stopwords: stopwords,
+        auto_stopwords: auto_stopwords,
+        threshold: threshold,
+        newick: newick
+    })
+}
+
+/// Reads every table in the tree at path.
+fn read_tables(path: &str) -> error::Result<TwitHash<String, twitter::FrequencyTable>> {
+    let mut tree;
+    match PTree::<String, twitter::FrequencyTable, storage::MmapFile>::open_mapped(path) {
+        Ok(t) => tree = t,
+        Err(e) => return Err(e)
+    }
+    let users;
+    match tree.keys() {
+        Ok(t) => users = t,
+        Err(e) => return Err(e.context(&format!("reading the keys of {}", path)))
+    }
+    let mut tables = TwitHash::new();
+    for user in users.into_iter() {
+        match tree.search(&user) {
+            Ok(Some(r)) => { tables.insert(user, r); },
+            Ok(None) => {},
+            Err(e) => return Err(e.context(&format!("reading the table of {} in {}", user, path)))
+        }
+    }
+    Ok(tables)
 }
 
-fn run(num_groups: usize, num_iters: usize) {
+/// Logs the scores of every k auto-k tried.
+fn log_scan(scan: &[(usize, cluster::Scores)], picked: usize, how: cluster::KChoice) {
+    log!("   k   silhouette         WCSS   Davies-Bouldin");
+    for &(k, s) in scan.iter() {
+        log!("{:>4} {:>12.4} {:>12.4} {:>16.4}", k, s.silhouette, s.wcss, s.davies_bouldin);
+    }
+    log!("Picked k = {} by {:?}", picked, how);
+}
+
+fn run(options: &DisplayOptions) -> error::Result<()> {
     /**
                                           .`*`
                                         .'* *.'
@@ -221,27 +496,31 @@ fn run(num_groups: usize, num_iters: usize) {
 
     let mut tree;
     // If the tree fails to load for some reason, recreate it and try again.
-    match PTree::<String, twitter::FrequencyTable>::open("data/tweet_tree") {
+    // Nothing gets written to the tree here, so it is memory mapped to make reading it cheap.
+    match PTree::<String, twitter::FrequencyTable, storage::MmapFile>::open_mapped("data/tweet_tree") {
         Ok(t) => tree = t,
-        _ => {
+        Err(error::Error::NotFound(_)) => {
             log!("Failed to find any data... Will load some right now :)");
-            load();
-            run(num_groups, num_iters);
-            return;
-        }
+            if let Err(e) = load(&tokenize::Pipeline::default()) {
+                return Err(e)
+            }
+            return run(options)
+        },
+        Err(e) => return Err(e)
     }
 
     let mut processor = twitter::TweetProcessor::new();
+    match vocab::Vocabulary::open("data/vocabulary") {
+        Ok(v) => processor.vocab = v,
+        Err(e) => return Err(e)
+    }
     let users;
     match tree.keys() {
         Ok(t) => {
             log!("Successfully found key list from PTree");
             users = t;
         },
-        _ => {
-            log!("Failed to open data.. Exiting...");
-            return;
-        }
+        Err(e) => return Err(e.context("reading the keys of data/tweet_tree"))
     }
 
     for user in users.into_iter() {
@@ -250,20 +529,113 @@ fn run(num_groups: usize, num_iters: usize) {
                 log!("Found data from tree for user {}", user);
                 processor.map.insert(user, r);
             },
-            _ => log!("Failed to find user {}", user),
+            Ok(None) => log!("Failed to find user {}", user),
+            Err(e) => return Err(e.context(&format!("reading the frequency table of {}", user)))
         }
     }
+    // Data loaded before there were document frequencies doesn't have them, but they can be
+    // counted from the tables
+    match weighting::DocumentFrequencies::open("data/document_frequencies") {
+        Ok(df) => processor.df = df,
+        Err(error::Error::NotFound(_)) => {
+            log!("No document frequencies yet, counting them...");
+            processor.count_documents();
+            if let Err(e) = processor.df.save("data/document_frequencies") {
+                return Err(e)
+            }
+        },
+        Err(e) => return Err(e)
+    }
+    processor.weighting = options.weighting;
+    for (i, channel) in channel::OTHERS.iter().enumerate() {
+        match read_tables(&channel.tree_path()) {
+            Ok(tables) => {
+                log!("Found {} tables of {}", tables.len(), channel.name());
+                processor.channels[i].map = tables;
+                processor.channels[i].count_documents();
+            },
+            Err(error::Error::NotFound(_)) => log!("There are no {} tables, the data was loaded before there were", channel.name()),
+            Err(e) => return Err(e)
+        }
+    }
+    processor.mix = options.channels.clone();
+    log!("Comparing users by {}", processor.mix);
+    match tokenize::Pipeline::open("data/tokenizer") {
+        Ok(t) => processor.tokenizer = t,
+        Err(error::Error::NotFound(_)) => log!("No tokenizer was saved with the data, it was probably the default one"),
+        Err(e) => return Err(e)
+    }
+    log!("The data was tokenized with {}", processor.tokenizer);
+    // Stopwords that weren't taken out when the data was loaded
+    let mut stopwords = options.stopwords.clone();
+    stopwords.append(&mut processor.most_common_words(options.auto_stopwords));
+    if !stopwords.is_empty() {
+        let removed = processor.remove_words(&stopwords);
+        log!("Took out {} occurences of {} stopwords: {}", removed, stopwords.len(), stopwords.join(", "));
+    }
+    log!("Weighing words by {:?} over {} users", options.weighting, processor.df.documents());
 
-    let mut handles = processor.k_means_groups(num_groups, num_iters);
-    handles = handles.into_iter().filter(|ref i| i.len() != 0).collect::<Vec<Vec<String>>>();
-    log!("Finished creating k-means groups");
+    let grouping = match (options.algorithm, options.groups) {
+        (Algorithm::KMeans, Some(k)) => processor.k_means_groups(k, options.iters, options.restarts, options.seed),
+        (Algorithm::KMeans, None) => {
+            let (grouping, scan) = processor.auto_k_groups(options.k_min, options.k_max, options.iters,
+                                                           options.restarts, options.seed, options.pick);
+            log_scan(&scan, grouping.groups.len(), options.pick);
+            grouping
+        },
+        (Algorithm::Hierarchical(linkage), groups) => {
+            let (keys, dendrogram) = processor.dendrogram(linkage);
+            log!("Built the dendrogram with {:?} linkage", linkage);
+            if let Some(ref path) = options.newick {
+                let written = File::create(path).and_then(|mut f| f.write_all(dendrogram.newick(&keys).as_bytes()));
+                if let Err(e) = written.context(&format!("writing the dendrogram to {}", path)) {
+                    return Err(e)
+                }
+                log!("Wrote the dendrogram to {}", path);
+            }
+            match (options.threshold, groups) {
+                (Some(d), _) => processor.cut_groups(keys, &dendrogram, hac::Cut::Distance(d)),
+                (None, Some(k)) => processor.cut_groups(keys, &dendrogram, hac::Cut::Groups(k)),
+                (None, None) => {
+                    let (grouping, scan) = processor.auto_cut_groups(keys, &dendrogram, options.k_min,
+                                                                     options.k_max, options.pick);
+                    log_scan(&scan, grouping.groups.len(), options.pick);
+                    grouping
+                }
+            }
+        },
+        (Algorithm::Density { eps, min_points }, _) => {
+            let grouping = processor.dbscan_groups(eps, min_points);
+            log!("DBSCAN found {} groups and {} outliers", grouping.groups.len(), grouping.outliers.len());
+            grouping
+        }
+    };
+    log!("Finished creating {:?} groups", options.algorithm);
+    let scores = format!("k = {}   silhouette {:.3}   WCSS {:.3}   Davies-Bouldin {:.3}", grouping.groups.len(),
+                         grouping.scores.silhouette, grouping.scores.wcss, grouping.scores.davies_bouldin);
+    let scores = if grouping.outliers.is_empty() { scores } else {
+        format!("{}   outliers {}", scores, grouping.outliers.len())
+    };
+    log!("{}", scores);
+    for (i, group) in grouping.groups.iter().enumerate() {
+        let words = group.top_words.iter().map(|&(ref w, _)| &w[..]).collect::<Vec<_>>();
+        log!("Group {} ({} users): {}", i, group.members.len(), words.join(", "));
+    }
+    let mut handles = grouping.handles();
     log!("Num groups: {}", handles.len());
+    // Outliers go in a column of their own, after every group
+    let outlier_column = if grouping.outliers.is_empty() { None } else { Some(handles.len()) };
+    if !grouping.outliers.is_empty() {
+        log!("Outliers: {}", grouping.outliers.join(", "));
+        handles.push(grouping.outliers.clone());
+    }
 
     log!("Creating GUI...");
     let mut container2 = Container::new(0, 0, 90, 90);
     let t1 = GuiText::new(1, 8, "ᴧ");
     let t2 = GuiText::new(1, 9, "v");
     let mut cur_label = GuiText::new(1, 3, "Currently viewing group #0");
+    let score_label = GuiText::new_string(1, 1, scores);
     let selector = GuiSelection2D::new_default(3, 4, 32, 24, handles).component(Box::new(
         |s, k| {
             match k {
@@ -317,13 +689,14 @@ fn run(num_groups: usize, num_iters: usize) {
 
     let rustbox = match RustBox::init(Default::default()) {
         Result::Ok(v) => v,
-        Result::Err(e) => panic!("{}", e),
+        Result::Err(e) => return Err(error::Error::Gui(format!("failed to start: {}", e)))
     };
 
     container2.next();
 
     container2.draw(&rustbox);
     cur_label.draw(&rustbox);
+    score_label.draw(&rustbox);
     rustbox.present();
     let mut container2 = container2.component(box |s, k| {
         match k {
@@ -351,8 +724,10 @@ fn run(num_groups: usize, num_iters: usize) {
                         let t = mux.components[0].to_string();
                         let key = processor.closest_key(&t);
                         let container_sub = Container::new(0, 0, 100, 100);
-                        let selected_display    = processor.map.get(&&mux.components[1].to_string()).unwrap().display();
-                        let closest_display     = processor.map.get(&&key).unwrap().display();
+                        let (selected_display, closest_display) = match (processor.map.get(&&mux.components[1].to_string()), processor.map.get(&&key)) {
+                            (Some(selected), Some(closest)) => (selected.display(), closest.display()),
+                            _ => continue
+                        };
                         let selected = GuiSelection::new_default(3, 5, 30, 10, selected_display);
                         let selected_name = "@".to_string() + &mux.components[1].to_string();
                         let selected_label = GuiTextBox::new(2, 4, 22, &selected_name);
@@ -387,20 +762,276 @@ fn run(num_groups: usize, num_iters: usize) {
                     }
                 }*/
             },
-            Err(e) => panic!("{}", e.description()),
+            Err(e) => return Err(error::Error::Gui(format!("failed to read a key: {}", e))),
             _ => { }
         }
         unsafe { if KILL { break; } }
         rustbox.clear();
         container2.draw(&rustbox);
-        unsafe { cur_label.text = format!("Currently viewing group #{}", CURRENT_GROUP); }
+        unsafe {
+            cur_label.text = if Some(CURRENT_GROUP) == outlier_column {
+                "Currently viewing the outliers".to_string()
+            } else {
+                format!("Currently viewing group #{}", CURRENT_GROUP)
+            };
+        }
         cur_label.draw(&rustbox);
+        score_label.draw(&rustbox);
         t1.draw(&rustbox);
         t2.draw(&rustbox);
         rustbox.present();
     }
+    Ok(())
 }
 
+/// What the drift command looks at, and how it shows it.
+struct DriftOptions {
+    period: drift::Period,
+    /// Only this user, instead of everyone
+    user: Option<String>,
+    /// How many of the words that rose and fell the most to show at every step
+    top: usize,
+    weighting: weighting::Weighting,
+    /// Whether to show the GUI after printing everything out
+    gui: bool
+}
+
+fn drift_options(args: &[String]) -> error::Result<DriftOptions> {
+    let options;
+    match options::Options::parse(args, &["user", "top", "weighting", "view"]) {
+        Ok(x) => options = x,
+        Err(e) => return Err(e)
+    }
+    let period;
+    match options.positional(0, "the period") {
+        Ok(Some(x)) => period = x,
+        Ok(None) => return Err(error::Error::Config("drift command requires the period, week or month.".to_string())),
+        Err(e) => return Err(e)
+    }
+    let (user, top);
+    match (options.optional("user"), options.get("top", 3)) {
+        (Ok(x), Ok(y)) => { user = x; top = y; },
+        (Err(e), _) | (_, Err(e)) => return Err(e)
+    }
+    let weighting;
+    match options.get("weighting", weighting::Weighting::TfIdf) {
+        Ok(x) => weighting = x,
+        Err(e) => return Err(e)
+    }
+    let gui;
+    match options.get("view", "gui".to_string()) {
+        Ok(ref x) if x == "gui" => gui = true,
+        Ok(ref x) if x == "text" => gui = false,
+        Ok(x) => return Err(error::Error::Config(format!("{} is not gui or text", x))),
+        Err(e) => return Err(e)
+    }
+    Ok(DriftOptions { period: period, user: user, top: top, weighting: weighting, gui: gui })
+}
+
+/// Counts every user's cached tweets by week or month, and prints out (and shows) how much
+/// their words changed from one to the next, the users whose words changed the most first.
+fn drift(options: &DriftOptions) -> error::Result<()> {
+    let mut processor = twitter::TweetProcessor::new();
+    // The words are weighed with what was counted when the data was loaded
+    match vocab::Vocabulary::open("data/vocabulary") {
+        Ok(v) => processor.vocab = v,
+        Err(e) => return Err(e)
+    }
+    processor.weighting = options.weighting;
+    match weighting::DocumentFrequencies::open("data/document_frequencies") {
+        Ok(df) => processor.df = df,
+        Err(error::Error::NotFound(_)) => {
+            log!("No document frequencies were saved with the data, so words are weighed by their raw counts");
+            processor.weighting = weighting::Weighting::Raw;
+        },
+        Err(e) => return Err(e)
+    }
+    match tokenize::Pipeline::open("data/tokenizer") {
+        Ok(t) => processor.tokenizer = t,
+        Err(error::Error::NotFound(_)) => log!("No tokenizer was saved with the data, using the default one"),
+        Err(e) => return Err(e)
+    }
+
+    let users;
+    match options.user {
+        Some(ref user) => users = vec![user.clone()],
+        None => {
+            let mut tree;
+            match PTree::<String, twitter::FrequencyTable, storage::MmapFile>::open_mapped("data/tweet_tree") {
+                Ok(t) => tree = t,
+                Err(e) => return Err(e)
+            }
+            match tree.keys() {
+                Ok(t) => users = t,
+                Err(e) => return Err(e.context("reading the keys of data/tweet_tree"))
+            }
+        }
+    }
+    let mut cache;
+    match PHash::<String, twitter::TweetList, storage::MmapFile>::open_mapped("data/tweet_cache") {
+        Ok(c) => cache = c,
+        Err(e) => return Err(e)
+    }
+    let mut skipped = 0;
+    for user in users.iter() {
+        match cache.get(user) {
+            Ok(Some(TweetList(tweets))) => skipped += processor.process_windows(&tweets, options.period),
+            Ok(None) => log!("There are no cached tweets from {}", user),
+            Err(e) => return Err(e.context(&format!("reading the cached tweets of {}", user)))
+        }
+    }
+    if skipped > 0 {
+        log!("Skipped {} tweets that were cached before their times were kept", skipped);
+    }
+
+    let mut report = users.iter()
+        .map(|user| (user.clone(), processor.drift(user, options.top)))
+        .filter(|&(_, ref steps)| !steps.is_empty())
+        .collect::<Vec<_>>();
+    report.sort_by(|a, b| drift::average(&b.1).partial_cmp(&drift::average(&a.1)).unwrap_or(std::cmp::Ordering::Equal));
+    if report.is_empty() {
+        log!("Nobody tweeted in more than one {:?}", options.period);
+        return Ok(())
+    }
+
+    let (mut labels, mut columns) = (vec![], vec![]);
+    for &(ref user, ref steps) in report.iter() {
+        let label = format!("@{}: average drift {:.3} over {} {:?}s", user, drift::average(steps), steps.len() + 1, options.period);
+        log!("{}", label);
+        let mut lines = vec![];
+        for step in steps.iter() {
+            let rose = step.rose.iter().map(|&(ref w, _)| format!("+{}", w)).collect::<Vec<_>>();
+            let fell = step.fell.iter().map(|&(ref w, _)| format!("-{}", w)).collect::<Vec<_>>();
+            let line = format!("{} -> {}  {:.3}  {} {}", options.period.label(step.from), options.period.label(step.to),
+                               step.distance, rose.join(" "), fell.join(" "));
+            log!("    {}", line);
+            lines.push(line);
+        }
+        labels.push(label);
+        columns.push(lines);
+    }
+    if options.gui {
+        drift_view(columns, labels)
+    } else {
+        Ok(())
+    }
+}
+
+/// Shows the drift of every user in a column of their own, with labels[i] above column i.
+fn drift_view(columns: Vec<Vec<String>>, labels: Vec<String>) -> error::Result<()> {
+    let mut container = Container::new(0, 0, 90, 90);
+    let mut cur_label = GuiText::new_string(1, 1, labels[0].clone());
+    let help = GuiText::new(1, 2, "Left and right go through the users, the ones whose words changed the most first");
+    let selector = GuiSelection2D::new_default(3, 4, 72, 24, columns).component(Box::new(
+        |s, k| {
+            match k {
+                Key::Left => {
+                    s.left();
+                    unsafe { CURRENT_GROUP = s.cur_choice_x; }
+                },
+                Key::Right => {
+                    s.right();
+                    unsafe { CURRENT_GROUP = s.cur_choice_x; }
+                },
+                Key::Up => s.down(),
+                Key::Down => s.up(),
+                _ => {}
+            }
+        }
+    ));
+    container.add(Box::new(selector)).unwrap();
+    let exit_button = GuiSelection::button_default(76, 4, "Exit").component(Box::new(
+        |_, k| {
+            if let Key::Enter = k {
+                unsafe { KILL = true; }
+            }
+        }
+    ));
+    container.add(Box::new(exit_button)).unwrap();
+
+    let rustbox = match RustBox::init(Default::default()) {
+        Result::Ok(v) => v,
+        Result::Err(e) => return Err(error::Error::Gui(format!("failed to start: {}", e)))
+    };
+    container.next();
+    let mut container = container.component(Box::new(|s, k| {
+        if let Key::Tab = k {
+            s.next();
+        } else {
+            s.components[s.selected as usize].handle_input(k);
+        }
+    }));
+    loop {
+        rustbox.clear();
+        container.draw(&rustbox);
+        unsafe { cur_label.text = labels[CURRENT_GROUP].clone(); }
+        cur_label.draw(&rustbox);
+        help.draw(&rustbox);
+        rustbox.present();
+        match rustbox.poll_event(false) {
+            Ok(rustbox::Event::KeyEvent(Key::Esc)) => break,
+            Ok(rustbox::Event::KeyEvent(k)) => container.handle_input(k),
+            Err(e) => return Err(error::Error::Gui(format!("failed to read a key: {}", e))),
+            _ => {}
+        }
+        unsafe { if KILL { break; } }
+    }
+    Ok(())
+}
+
+/// Runs the crash consistency tests on PHash and PTree and prints out what went wrong.
+fn crash_test(seed: u64, num_ops: usize) {
+    log!("Crash testing with seed {} and {} operations...", seed, num_ops);
+    let reports = vec![crashtest::crash_test_phash(seed, num_ops), crashtest::crash_test_ptree(seed, num_ops)];
+    let mut failed = false;
+    for report in reports.iter() {
+        for failure in report.failures.iter() {
+            error!("{}", failure);
+            failed = true;
+        }
+    }
+    let points = reports.iter().fold(0, |acc, r| acc + r.crash_points);
+    if failed {
+        error!("Crash testing found problems ({} crash points tested)", points);
+    } else {
+        log!("Everything survived all {} crash points", points);
+    }
+}
+
+/// Checks the checksum of every record in data/tweet_cache and data/tweet_tree.
+fn scrub() {
+    match PHash::<String, twitter::TweetList, storage::MmapFile>::open_mapped("data/tweet_cache") {
+        Ok(mut cache) => match cache.scrub() {
+            Ok(n) => log!("data/tweet_cache is fine ({} entries)", n),
+            Err(e) => error!("data/tweet_cache is damaged: {}", e)
+        },
+        Err(e) => error!("Failed to open data/tweet_cache: {}", e)
+    }
+    match PTree::<String, twitter::FrequencyTable, storage::MmapFile>::open_mapped("data/tweet_tree") {
+        Ok(mut tree) => match tree.scrub() {
+            Ok(n) => log!("data/tweet_tree is fine ({} keys)", n),
+            Err(e) => error!("data/tweet_tree is damaged: {}", e)
+        },
+        Err(e) => error!("Failed to open data/tweet_tree: {}", e)
+    }
+}
+
+/// Round trips random records through the codec and prints out the ones that came back wrong.
+fn round_trip_test(seed: u64, count: usize) {
+    log!("Round tripping {} random records with seed {}...", count, seed);
+    let failures = crashtest::round_trip_test(seed, count);
+    for failure in failures.iter() {
+        error!("{}", failure);
+    }
+    if failures.is_empty() {
+        log!("Every record came back the same");
+    } else {
+        error!("{} records did not come back the same", failures.len());
+    }
+}
+
+const USAGE: &'static str = "Usage:\n\tload [--tokenizer stages] - Loads data into the persistant data structures. Tweets are split into words by the comma separated --tokenizer stages (entities, extract, drop=kind, segment, lowercase, stopwords=list, auto-stopwords=n, min-length=n, stem, ngrams=n, phrases=n[:pmi]), which are saved with the data. The default is entities,extract,segment,lowercase. stem cuts words down to their Porter stems (\"voting\" -> \"vote\"), and the shortest word each stem came from is shown in its place. ngrams=3 also counts every run of two and three words as a phrase, and phrases=3 only counts the ones whose PMI over all the tweets is at least pmi (3 by default). Words and phrases, hashtags, mentions, the domains of URLs and emoji are each counted in tables of their own\n\n\tdisplay <groups|auto> <iters> [--algorithm k-means|hac|dbscan] [--linkage l] [--threshold d] [--newick path] [--eps d] [--min-points n] [--weighting raw|tf-idf|bm25] [--channels mix] [--stopwords list] [--auto-stopwords n] [--seed n] [--restarts n] [--k-min n] [--k-max n] [--pick silhouette|elbow] - Loads the data from the persistant data structures into a gui. If there is no data in the persistant data structures the program will say so and exit. Users are compared by the cosine similarity of their word counts weighed by --weighting (tf-idf by default), so words everyone uses don't make everyone look alike. --channels picks which of the tables are compared, and how much each one counts, like words=1,domains=2 (words, hashtags, mentions, domains or emoji, each =1 if no weight is given; just words by default), so users can be grouped by the outlets they link to with --channels domains. --stopwords (a + separated list of words, english, twitter or file:path) and --auto-stopwords n (the n words the most users use) take words out of the tables before anything else. The same seed always gives the same groups. k-means is run --restarts times (10 by default) and the best run is kept. With auto, every number of groups from --k-min to --k-max (2 to 12 by default) is tried, and the one with the best silhouette (or the elbow of the WCSS) is picked. With --algorithm hac the users are clustered hierarchically instead (--linkage single|complete|average|ward, average by default), iters isn't needed, and the tree is cut into the number of groups, or wherever clusters are further apart than --threshold. --newick path writes the whole tree to path in Newick format. With --algorithm dbscan neither argument is needed: users with at least --min-points users (3 by default, counting themselves) within a cosine distance of --eps (0.5 by default) make up groups, and users that aren't near any of them are shown as outliers in a column of their own.\n\n\tdrift <week|month> [--user handle] [--top n] [--weighting raw|tf-idf|bm25] [--view gui|text] - Counts every user's cached tweets by the week or month they were tweeted in, and shows how much their words changed from each one they tweeted in to the next, as the cosine distance between them (0 is no change, 1 is nothing in common), along with the --top words (3 by default) that rose and fell the most. Users are shown the ones whose words changed the most first, in a gui unless --view is text. Tweets that were cached before their times were kept are skipped.\n\n\tcrashtest [seed] [ops] - Simulates crashes at every write of a random workload and checks that the persistant data structures survive them.\n\n\tscrub - Reads every record in the persistant data structures and checks its checksum.\n\n\troundtrip [seed] [count] - Writes random records to bytes and reads them back, checking that nothing changed.";
+
 static mut KILL: bool = false;
 static mut DISPLAY_TWEETS: bool = false;
 
@@ -409,36 +1040,91 @@ fn main() {
     let args: Vec<String> = env::args().collect();
     if args.len() == 1 {
         println!("No arguments provided...");
-        println!("Usage:\n\tload - Loads data into the persistant data structures\n\n\tdisplay - Loads the data from the persistant data structures into a gui. If there is no data in the persistant data structures the program will say so and exit.");
+        println!("{}", USAGE);
     } else if args[1].to_uppercase() == "LOAD".to_string() {
-        load();
+        let tokenizer = match tokenizer_option(&args[2..]) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("{}", e);
+                return
+            }
+        };
+        if let Err(e) = load(&tokenizer) {
+            error!("Failed to load: {}", e);
+        }
     } else if args[1].to_uppercase() == "CLEAR".to_string() {
         fs::remove_dir_all("data").unwrap();
         fs::create_dir("data").unwrap();
-    } else if args[1].to_uppercase() == "DISPLAY".to_string() {
-        if args.len() < 4 {
-            println!("display command requires two arguments, the number of groups and the number of iters.");
-            return;
-        }
-        let k;
-        match args[2].parse::<usize>() {
-            Ok(x) => k = x,
+    } else if args[1].to_uppercase() == "CRASHTEST".to_string() {
+        let seed = match args.get(2) {
+            Some(x) => match x.parse::<u64>() {
+                Ok(x) => x,
+                Err(_) => {
+                    println!("Failed to parse second argument '{}'", x);
+                    return
+                }
+            },
+            None => 0
+        };
+        let num_ops = match args.get(3) {
+            Some(x) => match x.parse::<usize>() {
+                Ok(x) => x,
+                Err(_) => {
+                    println!("Failed to parse third argument '{}'", x);
+                    return
+                }
+            },
+            None => 24
+        };
+        crash_test(seed, num_ops);
+    } else if args[1].to_uppercase() == "SCRUB".to_string() {
+        scrub();
+    } else if args[1].to_uppercase() == "ROUNDTRIP".to_string() {
+        let seed = match args.get(2) {
+            Some(x) => match x.parse::<u64>() {
+                Ok(x) => x,
+                Err(_) => {
+                    println!("Failed to parse second argument '{}'", x);
+                    return
+                }
+            },
+            None => 0
+        };
+        let count = match args.get(3) {
+            Some(x) => match x.parse::<usize>() {
+                Ok(x) => x,
+                Err(_) => {
+                    println!("Failed to parse third argument '{}'", x);
+                    return
+                }
+            },
+            None => 100
+        };
+        round_trip_test(seed, count);
+    } else if args[1].to_uppercase() == "DRIFT".to_string() {
+        let options = match drift_options(&args[2..]) {
+            Ok(x) => x,
             Err(e) => {
-                println!("Failed to parse second argument '{}'", args[2]);
+                println!("{}", e);
                 return
             }
         };
-        let g;
-        match args[3].parse::<usize>() {
-            Ok(x) => g = x,
+        if let Err(e) = drift(&options) {
+            error!("{}", e);
+        }
+    } else if args[1].to_uppercase() == "DISPLAY".to_string() {
+        let options = match display_options(&args[2..]) {
+            Ok(x) => x,
             Err(e) => {
-                println!("Failed to parse third argument '{}'", args[3]);
+                println!("{}", e);
                 return
             }
+        };
+        if let Err(e) = run(&options) {
+            error!("{}", e);
         }
-        run(k, g);
     } else {
         println!("No valid arguments provided...");
-        println!("Usage:\n\tload - Loads data into the persistant data structures\n\n\tdisplay - Loads the data from the persistant data structures into a gui. If there is no data in the persistant data structures the program will say so and exit.");
+        println!("{}", USAGE);
     }
 }
@@ -0,0 +1,77 @@
+use std::str::FromStr;
+use error::Error;
+
+/// The arguments of a command, split into positional arguments and `--name value` (or
+/// `--name=value`) options. Options can go anywhere after the command.
+pub struct Options {
+    positional: Vec<String>,
+    named: Vec<(String, String)>
+}
+
+impl Options {
+    /// Parses args, which shouldn't include the program or the command. `known` is every
+    /// option name (without the --) that the command takes; anything else is an error.
+    pub fn parse(args: &[String], known: &[&str]) -> Result<Options, Error> {
+        let mut options = Options { positional: vec![], named: vec![] };
+        let mut i = 0;
+        while i < args.len() {
+            let arg = &args[i];
+            i += 1;
+            if !arg.starts_with("--") {
+                options.positional.push(arg.clone());
+                continue
+            }
+            let (name, value) = match arg.find('=') {
+                Some(eq) => (arg[2..eq].to_string(), arg[eq + 1..].to_string()),
+                None => {
+                    if i >= args.len() {
+                        return Err(Error::Config(format!("{} needs a value", arg)))
+                    }
+                    i += 1;
+                    (arg[2..].to_string(), args[i - 1].clone())
+                }
+            };
+            if !known.contains(&&name[..]) {
+                return Err(Error::Config(format!("unknown option --{}", name)))
+            }
+            options.named.push((name, value));
+        }
+        Ok(options)
+    }
+
+    /// The i'th positional argument, or None if there aren't that many.
+    pub fn positional<T: FromStr>(&self, i: usize, what: &str) -> Result<Option<T>, Error> {
+        match self.positional.get(i) {
+            Some(x) => match x.parse::<T>() {
+                Ok(v) => Ok(Some(v)),
+                Err(_) => Err(Error::Config(format!("failed to parse {} '{}'", what, x)))
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// The number of positional arguments.
+    pub fn len(&self) -> usize {
+        self.positional.len()
+    }
+
+    /// The value of --name, or default if it wasn't given. If it was given more than once,
+    /// the last one wins.
+    pub fn get<T: FromStr>(&self, name: &str, default: T) -> Result<T, Error> {
+        match self.optional(name) {
+            Ok(x) => Ok(x.unwrap_or(default)),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// The value of --name, or None if it wasn't given.
+    pub fn optional<T: FromStr>(&self, name: &str) -> Result<Option<T>, Error> {
+        match self.named.iter().rev().find(|&&(ref n, _)| n == name) {
+            Some(&(_, ref x)) => match x.parse::<T>() {
+                Ok(v) => Ok(Some(v)),
+                Err(_) => Err(Error::Config(format!("failed to parse --{} '{}'", name, x)))
+            },
+            None => Ok(None)
+        }
+    }
+}
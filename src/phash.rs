@@ -1,124 +1,149 @@
-use std::hash::{ Hash, Hasher };
-use std::collections::hash_map::DefaultHasher;
 use std::io::{ Seek, SeekFrom, Read, Write };
 use std::io;
-use random_access_file::Serialize;
+use codec::{ Serialize, u64_from_le };
 use cfile_rs::CFile;
 use std::marker::PhantomData;
+use storage::{ Storage, MmapFile, LockFile, invalid_data, read_at, bytes_at };
+use compress::{ Compression, Packed };
+use checksum::{ CrcReader, crc32c, crc32c_update, corruption, read_checked, write_checked, checked_len };
+use codec::u64_to_le;
+use error::{ Error, Context };
+
+/// The fixed size part at the front of every entry in the dat file.
+struct EntryHeader {
+    pub next: u64,
+    /// The number of bytes after the header, which can be more than the key and value need
+    /// if the entry went into a bigger hole from the free list.
+    pub size: u64,
+    pub hash: u64,
+    /// CRC32C of the size, the hash, and the key and value (see entry_crc).
+    pub crc: u32
+}
 
-/// An incredibly useful macro. It will check an expression of type Error<T, Z>,
-/// if it is Err(err) it will RETURN in whatever function it is placed in with
-/// error, otherwise it will continue in the function. This cuts down on the amount
-/// of error checking code that will clog things up.
-/// Optionally, it will also store the value x in $v (e.g. if it is Ok(x), $v = x).
-macro_rules! check {
-    ( $e:expr ) => (
-    match $e {
-        Ok(_) => {},
-        Err(e) => return Err(e)
-        }
-    );
-    ( $e:expr, $v:ident) => (
-        match $e {
-            Ok(r) => $v = r,
-            Err(e) => return Err(e)
-        }
-    )
+serializable!(EntryHeader { next: u64, size: u64, hash: u64, crc: u32 });
+
+/// The checksum of an entry. `body` is the serialized key followed by the serialized value.
+fn entry_crc(size: u64, hash: u64, body: &[u8]) -> u32 {
+    let mut buf = [0u8; 16];
+    u64_to_le(size, &mut buf[0..8]);
+    u64_to_le(hash, &mut buf[8..16]);
+    crc32c_update(crc32c(&buf), body)
 }
 
 struct Entry<K, V>
-    where   K: Hash + Eq {
+    where   K: Eq {
+    pub header: EntryHeader,
     pub key: K,
-    pub value: V,
-    pub hash: u64,
-    pub next: u64
+    pub value: Packed<V>
 }
 
+serializable!(<K, V> Entry => Entry<K::DeserializeOutput, V>
+              where [K: Eq + Serialize, K::DeserializeOutput: Eq, V: Serialize]
+              { header: EntryHeader, key: K, value: Packed<V> });
+
 impl<K, V> Entry<K, V>
-    where   K: Hash + Eq + Serialize,
+    where   K: Eq + Serialize,
             V: Serialize {
-    fn new(key: K, value: V, hash: u64, next: u64) -> Entry<K, V> {
-        Entry {
-            key: key,
-            value: value,
-            hash: hash,
-            next: next
+    /// Reads a whole entry, checking its checksum. Nothing past the size in its header is read,
+    /// so a bad key or value length can't send it off into the rest of the file.
+    fn read_checked<R: Read>(from: &mut R, pos: u64) -> Result<Entry<K::DeserializeOutput, V>, io::Error>
+        where K::DeserializeOutput: Eq {
+        let header;
+        check!(EntryHeader::deserialize(from), header);
+        let mut buf = [0u8; 16];
+        u64_to_le(header.size, &mut buf[0..8]);
+        u64_to_le(header.hash, &mut buf[8..16]);
+        let mut reader = CrcReader::new(from.take(header.size));
+        reader.update(&buf);
+        let key = match K::deserialize(&mut reader) {
+            Ok(x) => x,
+            Err(e) => return Err(corruption("entry", pos, format!("{}", e)))
+        };
+        let value = match Packed::<V>::deserialize(&mut reader) {
+            Ok(x) => x,
+            Err(e) => return Err(corruption("entry", pos, format!("{}", e)))
+        };
+        if reader.crc() != header.crc {
+            return Err(corruption("entry", pos, format!("checksum is {:08x}, expected {:08x}", reader.crc(), header.crc)))
         }
+        Ok(Entry { header: header, key: key, value: value })
     }
 
     /// Returns meta data about an entry. To be specific, the pointer to the next entry, it's hash,
     /// and the key. They key isn't exactly meta data but you're going to have to deal with it.
-    fn meta_data(from: &mut CFile) -> Result<(u64, u64, K::DeserializeOutput), io::Error> {
-        let next;
-        check!(u64::deserialize(from), next);
-        check!(from.seek(SeekFrom::Current(8 * 1)));
-        let hash;
-        check!(u64::deserialize(from), hash);
+    fn meta_data<R: Read>(from: &mut R) -> Result<(u64, u64, K::DeserializeOutput), io::Error> {
+        let header;
+        check!(EntryHeader::deserialize(from), header);
         let key;
         check!(K::deserialize(from), key);
-        Ok((next, hash, key))
+        Ok((header.next, header.hash, key))
     }
+}
 
-    fn next_and_hash(from: &mut CFile) -> Result<(u64, u64), io::Error> {
-        let next;
-        check!(u64::deserialize(from), next);
-        check!(from.seek(SeekFrom::Current(8 * 1)));
-        let hash;
-        check!(u64::deserialize(from), hash);
-        Ok((next, hash))
+/// FNV-1a of a key's bytes. Hashes are kept in every entry and pick which bucket it's in, so
+/// they have to come out the same in every build of this program. std's DefaultHasher doesn't
+/// promise that (it can change with any Rust release).
+fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for &b in bytes {
+        hash = (hash ^ b as u64).wrapping_mul(0x100000001b3);
     }
+    hash
+}
 
-    fn next(from: &mut CFile) -> Result<u64, io::Error> {
-        let next;
-        check!(u64::deserialize(from), next);
-        Ok(next)
-    }
+/// One u64 that an operation writes over in place: a pointer, or a number in the manifest
+/// header. See the journal at the top of this file.
+struct Update {
+    /// MANIFEST or DAT
+    pub file: u8,
+    pub pos: u64,
+    pub value: u64
+}
+
+serializable!(Update { file: u8, pos: u64, value: u64 });
 
-    fn size(from: &mut CFile) -> Result<u64, io::Error> {
-        check!(u64::deserialize(from));
-        let size;
-        check!(u64::deserialize(from), size);
-        Ok(size)
+const MANIFEST: u8 = 0;
+const DAT: u8 = 1;
+
+impl Update {
+    /// Points the entry (or free block) at `entry` to `next`.
+    fn next(entry: u64, next: u64) -> Update {
+        Update { file: DAT, pos: entry, value: next }
     }
-}
 
-impl<K, V> Serialize for Entry<K, V>
-    where   K: Hash + Eq + Serialize,
-            K::DeserializeOutput: Hash + Eq,
-            V: Serialize {
-    type DeserializeOutput = Entry<K::DeserializeOutput, V::DeserializeOutput>;
+    /// Makes `entry` the first entry in a bucket.
+    fn bucket(bucket: u64, entry: u64) -> Update {
+        Update { file: MANIFEST, pos: MANIFEST_OFFSET + 8 * bucket, value: entry }
+    }
 
-    fn serialize(&self, to: &mut Write) -> Result<(), io::Error> {
-        check!(self.next.serialize(to));
+    /// Makes `block` the first block on the free list.
+    fn free_list(block: u64) -> Update {
+        Update { file: DAT, pos: 0, value: block }
+    }
 
-        check!((self.value.serialized_len() + self.key.serialized_len()).serialize(to));
-        check!(self.hash.serialize(to));
-        check!(self.key.serialize(to));
-        check!(self.value.serialize(to));
-        Ok(())
+    /// Sets one of the numbers in the manifest header.
+    fn header(pos: u64, value: u64) -> Update {
+        Update { file: MANIFEST, pos: pos, value: value }
     }
-    fn deserialize(from: &mut Read) -> Result<Entry<K::DeserializeOutput, V::DeserializeOutput>, io::Error> {
-        let next_res = u64::deserialize(from);
-
-        // size_of res isnt in entry
-        let _ = u64::deserialize(from);
-
-        let hash_res = u64::deserialize(from);
-        let key_res = K::deserialize(from);
-        let val_res = V::deserialize(from);
-        if let Err(e)       = next_res  { Err(e) }
-        else if let Err(e)  = hash_res  { Err(e) }
-        else if let Err(e)  = key_res   { Err(e) }
-        else if let Err(e)  = val_res   { Err(e) }
-        else                            { Ok(Entry {
-            next: next_res.unwrap(),
-            key: key_res.unwrap(),
-            value: val_res.unwrap(),
-            hash: hash_res.unwrap()
-        }) }
-    }
-    fn serialized_len(&self) -> u64 {
-        8 + 8 + 8 + self.key.serialized_len() + self.value.serialized_len()
+}
+
+/// Where an entry is in its bucket's list.
+struct Found {
+    bucket: u64,
+    /// The entry before it, or NO_ELEMENT if it's the first one.
+    prev: u64,
+    pos: u64,
+    next: u64
+}
+
+impl Found {
+    /// The update that takes the entry out of its list.
+    fn unlink(&self) -> Update {
+        if self.prev == NO_ELEMENT {
+            Update::bucket(self.bucket, self.next)
+        } else {
+            Update::next(self.prev, self.next)
+        }
     }
 }
 
@@ -136,14 +161,19 @@ manifest file:
                    there is no next element.
         64 bits -> An unsigned integer that represents (n + m) / 8, or in other words, the number
                    of bytes taken up by the key and value
-        64 bits -> The hash of the key.
+        64 bits -> The hash of the key's bytes (see hash_bytes).
+        32 bits -> CRC32C of the size, the hash, the key and the value.
         n bits  -> The key.
-        m bits  -> The value.
+        m bits  -> The value, packed (see compress.rs).
 
     The layout of the manifest file itself:
 
-        64 bits -> An unsigned integer that represents the length of the hashtable.
-        64 bits -> An unsigned integer that represents the number of elements in the hashtable.
+        64 bits -> The number of buckets keys are hashed into.
+        64 bits -> The number of keys in the hashtable.
+        64 bits -> How values are compressed (Compression::to_u8).
+        64 bits -> The number of buckets once the table is done growing. This is the same as
+                   the number of buckets, unless it's in the middle of growing (see below).
+        256 bytes -> The journal (see below).
         length * 64 bits -> An array of indices to the dat file.
 
 dat file:
@@ -156,15 +186,54 @@ dat file:
         64 bits -> Pointer to the linked list of free memory. This value will be equal to NO_ELEMENT
                     if there is no free memory, meaning you have to write to the end of the file.
         n bits  -> All the binary data.
+
+the journal:
+
+    An entry is always written somewhere nothing points to yet, either onto the end of the dat
+    file or into a free block (past the pointer to the next free block, which is left alone).
+    What puts it in the table are the u64s that get written over in place: bucket indices, next
+    pointers, the free list and the numbers in the manifest header. If the program died halfway
+    through writing those, lists would end up pointing into each other, and if it died halfway
+    through writing one of them, it would point at nothing at all.
+
+    So every operation works out every u64 it's going to write over before it writes any of
+    them, and writes all of them to the journal first, in one go:
+
+        varint  -> The number of updates.
+        updates -> 8 bits saying which file (0 for the manifest, 1 for the dat file), then the
+                   64 bit position and the 64 bit value that goes there, for every update.
+        32 bits -> CRC32C of the above.
+
+    Then it writes them where they go. When a table is opened and its journal passes its
+    checksum, every update in it that didn't make it to the disk is written again. If it
+    doesn't pass, the program died while writing it, and none of that operation happened
+    (except for the entry it wrote, which nothing points to and just takes up space). Since
+    entries are only ever written where nothing points to, doing the updates of the last
+    operation again never undoes anything.
+
+growing:
+
+    Once there are 3 keys for every 4 buckets, the table doubles its number of buckets. The
+    new number of buckets goes in the header first, and then every entry that belongs in one
+    of the new buckets is moved there, one at a time, each move going through the journal like
+    any other operation. Until every entry has been moved, a key could be in the bucket it hashed
+    to before or the one it hashes to after, so lookups check both. If a table is opened in the
+    middle of growing, the next insert or remove finishes it.
 */
 
-pub struct PHash<K, V> where
-    K: Hash + Serialize + Eq, V: Serialize,
-    K::DeserializeOutput: Hash + Eq {
-    manifest: CFile,
-    dat: CFile,
+pub struct PHash<K, V, S = CFile> where
+    K: Serialize + Eq, V: Serialize,
+    K::DeserializeOutput: Serialize + Eq,
+    S: Storage {
+    manifest: S,
+    dat: S,
     len: usize,
+    /// The number of buckets once the table is done growing (see the top of this file).
+    new_len: usize,
     pub count: usize,
+    compression: Compression,
+    /// Held for as long as the files are open for writing.
+    lock: Option<LockFile>,
     phantom_k: PhantomData<K>,
     phantom_v: PhantomData<V>
 }
@@ -173,27 +242,89 @@ use std::u64;
 
 static NO_ELEMENT: u64 = u64::MAX;
 static DEFAULT_TABLE_SIZE: u64 = 16u64;
-static MANIFEST_OFFSET: u64 = 16u64;
-
-impl<K, V> PHash<K, V> where
-    K: Hash + Serialize + Eq, V: Serialize,
-    K::DeserializeOutput: Hash + Eq {
-
-    /// Creates a new PHash.
+/// Where the numbers in the manifest header are.
+const LEN_POS: u64 = 0;
+const COUNT_POS: u64 = 8;
+const NEW_LEN_POS: u64 = 24;
+const JOURNAL_POS: u64 = 32;
+/// The room there is for the journal, which is plenty for the most updates an operation makes (6).
+const JOURNAL_LEN: u64 = 256;
+const MANIFEST_OFFSET: u64 = JOURNAL_POS + JOURNAL_LEN;
+/// The size of the next, size, hash and crc fields at the start of every entry.
+static ENTRY_HEADER_LEN: u64 = 28u64;
+
+impl<K, V> PHash<K, V, CFile> where
+    K: Serialize + Eq, V: Serialize,
+    K::DeserializeOutput: Serialize + Eq {
+
+    /// Creates a new PHash, which compresses its values with `compression`.
     /// If a PHash with the same name has already been created, it will be overwritten, or this
     /// function will return Err
-    pub fn new(path: &str) -> Result<PHash<K, V>, io::Error> {
-        let mut manifest;
-        check!(CFile::open(&(path.to_string() + ".manifest"), "w+"), manifest);
-        let mut dat;
-        check!(CFile::open(&(path.to_string() + ".dat"), "w+"), dat);
+    pub fn new(path: &str, compression: Compression) -> Result<PHash<K, V, CFile>, Error> {
+        let lock;
+        check!(LockFile::acquire(path), lock);
+        let manifest;
+        check!(CFile::open(&(path.to_string() + ".manifest"), "w+").context(&format!("creating {}.manifest", path)), manifest);
+        let dat;
+        check!(CFile::open(&(path.to_string() + ".dat"), "w+").context(&format!("creating {}.dat", path)), dat);
+        let mut table;
+        check!(PHash::create(manifest, dat, compression).context(&format!("creating {}", path)), table);
+        table.lock = Some(lock);
+        Ok(table)
+    }
+
+    pub fn open(path: &str) -> Result<PHash<K, V, CFile>, Error> {
+        let lock;
+        check!(LockFile::acquire(path), lock);
+        let manifest;
+        check!(CFile::open(&(path.to_string() + ".manifest"), "r+").context(&format!("opening {}.manifest", path)), manifest);
+        let dat;
+        check!(CFile::open(&(path.to_string() + ".dat"), "r+").context(&format!("opening {}.dat", path)), dat);
+        let mut table;
+        check!(PHash::load(manifest, dat).context(&format!("opening {}", path)), table);
+        table.lock = Some(lock);
+        Ok(table)
+    }
+}
+
+impl<K, V> PHash<K, V, MmapFile> where
+    K: Serialize + Eq, V: Serialize,
+    K::DeserializeOutput: Serialize + Eq {
+
+    /// Opens a PHash with its manifest and dat files memory mapped, so lookups are parsed
+    /// right out of memory. The PHash is read only: inserting or removing will return Err.
+    pub fn open_mapped(path: &str) -> Result<PHash<K, V, MmapFile>, Error> {
+        let manifest;
+        check!(MmapFile::open(&(path.to_string() + ".manifest")).context(&format!("mapping {}.manifest", path)), manifest);
+        let dat;
+        check!(MmapFile::open(&(path.to_string() + ".dat")).context(&format!("mapping {}.dat", path)), dat);
+        PHash::load(manifest, dat).context(&format!("opening {}", path))
+    }
+}
 
+impl<K, V, S> PHash<K, V, S> where
+    K: Serialize + Eq, V: Serialize,
+    K::DeserializeOutput: Serialize + Eq,
+    S: Storage {
+
+    /// Creates a new, empty PHash in the given manifest and dat backends. Anything that was
+    /// already in them gets written over.
+    pub fn create(mut manifest: S, mut dat: S, compression: Compression) -> Result<PHash<K, V, S>, Error> {
+        check!(manifest.seek(SeekFrom::Start(0)));
+        check!(dat.seek(SeekFrom::Start(0)));
         check!(NO_ELEMENT.serialize(&mut dat));
 
         check!(DEFAULT_TABLE_SIZE.serialize(&mut manifest));
 
         check!(0u64.serialize(&mut manifest));
 
+        check!((compression.to_u8() as u64).serialize(&mut manifest));
+
+        check!(DEFAULT_TABLE_SIZE.serialize(&mut manifest));
+
+        // Nothing in the journal yet. All zeros doesn't pass the checksum.
+        check!(manifest.write_all(&vec![0u8; JOURNAL_LEN as usize]));
+
         for i in 0..DEFAULT_TABLE_SIZE {
             check!(NO_ELEMENT.serialize(&mut manifest))
         }
@@ -203,399 +334,495 @@ impl<K, V> PHash<K, V> where
             dat: dat,
             count: 0,
             len: 16,
+            new_len: 16,
+            compression: compression,
+            lock: None,
             phantom_k: PhantomData {},
             phantom_v: PhantomData {}
         })
     }
 
-    pub fn open(path: &str) -> Result<PHash<K, V>, io::Error> {
-        let mut manifest;
-        check!(CFile::open(&(path.to_string() + ".manifest"), "r+"), manifest);
-        let mut dat;
-        check!(CFile::open(&(path.to_string() + ".dat"), "r+"), dat);
-
-        check!(manifest.seek(SeekFrom::Start(0)));
-        let len;
-        check!(u64::deserialize(&mut manifest), len);
-        let count;
-        check!(u64::deserialize(&mut manifest), count);
-
-        Ok(PHash {
+    /// Opens a PHash that was previously created in the given manifest and dat backends. If the
+    /// last operation on it never finished, it gets finished now, which fails if the backends
+    /// are read only.
+    pub fn load(manifest: S, dat: S) -> Result<PHash<K, V, S>, Error> {
+        let mut table = PHash {
             manifest: manifest,
             dat: dat,
-            count: count as usize,
-            len: len as usize,
+            count: 0,
+            len: 0,
+            new_len: 0,
+            compression: Compression::None,
+            lock: None,
             phantom_k: PhantomData {},
             phantom_v: PhantomData {}
-        })
+        };
+        check!(table.recover());
+        check!(table.read_header());
+        Ok(table)
+    }
 
+    fn read_header(&mut self) -> Result<(), Error> {
+        check!(self.manifest.seek(SeekFrom::Start(0)));
+        let len;
+        check!(u64::deserialize(&mut self.manifest), len);
+        let count;
+        check!(u64::deserialize(&mut self.manifest), count);
+        let flag;
+        check!(u64::deserialize(&mut self.manifest), flag);
+        let new_len;
+        check!(u64::deserialize(&mut self.manifest), new_len);
+        if flag > 0xFF {
+            return Err(From::from(corruption("manifest", 16, format!("{} is not a kind of compression", flag))))
+        }
+        let compression;
+        check!(Compression::from_u8(flag as u8), compression);
+        // Buckets are picked with hash & (len - 1), so there has to be a power of two of them
+        if len == 0 || len & (len - 1) != 0 || (new_len != len && new_len != 2 * len) {
+            return Err(From::from(corruption("manifest", 0, format!("{} buckets (growing to {}) is not a power of two", len, new_len))))
+        }
+        self.compression = compression;
+        self.len = len as usize;
+        self.count = count as usize;
+        self.new_len = new_len as usize;
+        Ok(())
     }
 
-    fn set_next(&mut self, set_next: u64, to_this: u64) -> Result<(), io::Error> {
-        let _ = self.dat.seek(SeekFrom::Start(set_next));
-        match to_this.serialize(&mut self.dat) {
-            Ok(()) => Ok(()),
-            Err(e) => Err(e)
+    /// Writes every update in the journal that didn't make it to the disk (see the top of this
+    /// file).
+    fn recover(&mut self) -> Result<(), Error> {
+        let updates = {
+            let bytes;
+            check!(bytes_at(&mut self.manifest, JOURNAL_POS, JOURNAL_LEN as usize), bytes);
+            read_checked::<Vec<Update>>(&mut &bytes[..], "journal", JOURNAL_POS)
+        };
+        // If the journal doesn't pass its checksum, the program died while writing it, before
+        // any of the updates in it were written
+        if let Ok(updates) = updates {
+            for u in updates.iter() {
+                let current;
+                check!(self.read_pointer(u.file, u.pos), current);
+                if current != u.value {
+                    check!(self.write_pointer(u.file, u.pos, u.value).context("finishing the last operation"));
+                }
+            }
         }
+        Ok(())
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.count == 0
+    fn file(&mut self, file: u8) -> &mut S {
+        if file == MANIFEST { &mut self.manifest } else { &mut self.dat }
     }
 
-    fn increment_count(&mut self) -> Result<(), io::Error> {
-        let _ = self.manifest.seek(SeekFrom::Start(8));
-        self.count += 1;
-        match (self.count as u64).serialize(&mut self.manifest) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e)
-        }
+    fn read_pointer(&mut self, file: u8, pos: u64) -> Result<u64, io::Error> {
+        let bytes;
+        check!(bytes_at(self.file(file), pos, 8), bytes);
+        Ok(u64_from_le(&bytes))
     }
 
-    fn double_len(&mut self) -> Result<(), io::Error> {
-        let _ = self.manifest.seek(SeekFrom::Start(0));
-        self.len *= 2;
-        check!(self.len.serialize(&mut self.manifest));
-        Ok(())
+    fn write_pointer(&mut self, file: u8, pos: u64, value: u64) -> Result<(), io::Error> {
+        let to = self.file(file);
+        check!(to.seek(SeekFrom::Start(pos)));
+        value.serialize(to)
     }
 
-    fn clear_buckets(&mut self) -> Result<(), io::Error> {
-        check!(self.manifest.seek(SeekFrom::Start(MANIFEST_OFFSET)));
-        for i in 0..self.len {
-            check!(NO_ELEMENT.serialize(&mut self.manifest));
+    /// Writes the updates to the journal, and then to where they go.
+    fn commit(&mut self, updates: &Vec<Update>) -> Result<(), io::Error> {
+        if checked_len(updates) > JOURNAL_LEN {
+            return Err(invalid_data(format!("{} updates don't fit in the journal", updates.len())))
+        }
+        check!(self.manifest.seek(SeekFrom::Start(JOURNAL_POS)));
+        check!(write_checked(updates, &mut self.manifest));
+        for u in updates.iter() {
+            check!(self.write_pointer(u.file, u.pos, u.value));
+            if u.file == MANIFEST {
+                match u.pos {
+                    LEN_POS => self.len = u.value as usize,
+                    COUNT_POS => self.count = u.value as usize,
+                    NEW_LEN_POS => self.new_len = u.value as usize,
+                    _ => {}
+                }
+            }
         }
         Ok(())
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
     fn free_space(&mut self) -> Result<u64, io::Error> {
-        check!(self.dat.seek(SeekFrom::Start(0)));
-        let x;
-        check!(u64::deserialize(&mut self.dat), x);
-        Ok(x)
-    }
-
-    fn add_free_space(&mut self, ptr: u64) -> Result<(), io::Error> {
-        match self.free_space() {
-            Ok(head_ptr) => {
-                if head_ptr == NO_ELEMENT {
-                    let _ = self.dat.seek(SeekFrom::Start(0));
-                    let res = ptr.serialize(&mut self.dat);
-                    if let Err(e) = self.set_next(ptr, NO_ELEMENT) {
-                        return Err(e)
-                    }
-                    Ok(())
-                } else {
-                    if let Err(e) = self.set_next(ptr, head_ptr) {
-                        return Err(e);
-                    }
-                    let _ = self.dat.seek(SeekFrom::Start(0));
-                    match ptr.serialize(&mut self.dat) {
-                        Ok(()) => Ok(()),
-                        Err(e) => Err(e)
-                    }
-                }
-            },
-            Err(e) => Err(e)
-        }
+        self.read_pointer(DAT, 0)
     }
 
-    fn bucket_location(&mut self, hash: u64) -> Result<u64, io::Error> {
-        let _ = self.manifest.seek(SeekFrom::Start(MANIFEST_OFFSET + 8*(hash & (self.len as u64 - 1))));
-        match u64::deserialize(&mut self.manifest) {
-            Ok(e) => Ok(e),
-            Err(e) => {
-                Err(e)
-            }
-        }
+    /// The first entry in a bucket.
+    fn bucket(&mut self, bucket: u64) -> Result<u64, io::Error> {
+        self.read_pointer(MANIFEST, MANIFEST_OFFSET + 8 * bucket)
     }
 
-    fn set_bucket_location(&mut self, hash: u64, location: u64) -> Result<(), io::Error> {
-        let _ = self.manifest.seek(SeekFrom::Start(MANIFEST_OFFSET + 8*(hash & (self.len as u64 - 1))));
-        match location.serialize(&mut self.manifest) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e)
-        }
+    /// Reads the header of the entry (or free block) at `pos`, straight out of memory if the
+    /// dat file is mapped.
+    fn header_at(&mut self, pos: u64) -> Result<EntryHeader, io::Error> {
+        let mut from;
+        check!(read_at(&mut self.dat, pos), from);
+        EntryHeader::deserialize(&mut from)
     }
 
-    fn last_element(&mut self, mut list: u64) -> Result<u64, io::Error> {
-        let mut last = list;
-        while list != NO_ELEMENT {
-            last = list;
-            check!(self.dat.seek(SeekFrom::Start(last)));
-            check!(Entry::<i8, i8>::next(&mut self.dat), list);
+    /// Reads the meta data of the entry at `pos`, straight out of memory if the dat file is
+    /// mapped.
+    fn meta_data_at(&mut self, pos: u64) -> Result<(u64, u64, K::DeserializeOutput), io::Error> {
+        let mut from;
+        check!(read_at(&mut self.dat, pos), from);
+        Entry::<K, V>::meta_data(&mut from)
+    }
+
+    /// Whether the entry at `pos` has `key`.
+    fn key_equals(&mut self, pos: u64, key: &K::DeserializeOutput) -> Result<bool, io::Error> {
+        let meta;
+        check!(self.meta_data_at(pos), meta);
+        Ok(meta.2 == *key)
+    }
+
+    /// Whether the entry at `pos` has the key that serializes to `key`. A K can't be compared
+    /// to the K::DeserializeOutput that gets read back, but the bytes of the keys can be.
+    fn key_is(&mut self, pos: u64, key: &[u8]) -> Result<bool, io::Error> {
+        let header;
+        check!(self.header_at(pos), header);
+        if header.size < key.len() as u64 {
+            return Ok(false)
         }
-        Ok(last)
+        let bytes;
+        check!(bytes_at(&mut self.dat, pos + ENTRY_HEADER_LEN, key.len()), bytes);
+        Ok(&bytes[..] == key)
     }
 
-    fn write_entry(&mut self, k: &K, v: &V, hash: u64) -> Result<u64, io::Error> {
-        let mut size = v.serialized_len() + k.serialized_len();
-        let mut freespace;
-        check!(self.free_space(), freespace);
-        if freespace == NO_ELEMENT {
-            check!(self.dat.seek(SeekFrom::End(0)));
-        } else {
-            let mut last = NO_ELEMENT;
-            while freespace != NO_ELEMENT {
-                check!(self.dat.seek(SeekFrom::Start(freespace)));
-                let next;
-                check!(u64::deserialize(&mut self.dat), next);
-                let entry_size;
-                check!(u64::deserialize(&mut self.dat), entry_size);
-                if entry_size >= size {
-                    size = entry_size;
-                    if last == NO_ELEMENT {
-                        check!(self.dat.seek(SeekFrom::Start(0)));
-                        check!(next.serialize(&mut self.dat));
-                    } else {
-                        check!(self.dat.seek(SeekFrom::Start(last)));
-                        check!(next.serialize(&mut self.dat));
+    /// Finds the entry with `hash` that is_key says is the one. While the table is growing it
+    /// could be in either of two buckets.
+    fn find<F>(&mut self, hash: u64, mut is_key: F) -> Result<Option<Found>, io::Error>
+        where F: FnMut(&mut Self, u64) -> Result<bool, io::Error> {
+        let before = hash & (self.len as u64 - 1);
+        let after = hash & (self.new_len as u64 - 1);
+        let buckets = if before == after { vec![before] } else { vec![before, after] };
+        for &bucket in buckets.iter() {
+            let mut prev = NO_ELEMENT;
+            let mut cur;
+            check!(self.bucket(bucket), cur);
+            while cur != NO_ELEMENT {
+                let header;
+                check!(self.header_at(cur), header);
+                if header.hash == hash {
+                    let same;
+                    check!(is_key(self, cur), same);
+                    if same {
+                        return Ok(Some(Found { bucket: bucket, prev: prev, pos: cur, next: header.next }))
                     }
-                    check!(self.dat.seek(SeekFrom::Start(freespace)));
-                    break;
-                } else {
-                    freespace = next;
                 }
-                last = next;
+                prev = cur;
+                cur = header.next;
             }
-            if freespace == NO_ELEMENT {
-                check!(self.dat.seek(SeekFrom::End(0)));
+        }
+        Ok(None)
+    }
+
+    /// Writes an entry where nothing points to it: into the first block on the free list that's
+    /// big enough, past its pointer to the next free block, or onto the end of the dat file.
+    /// Returns where it went, and if it went into a free block, the update that takes that
+    /// block off of the free list.
+    fn write_entry(&mut self, k: &K, v: &V, hash: u64) -> Result<(u64, Option<Update>), io::Error> {
+        let value;
+        check!(Packed::new(v, self.compression), value);
+        let mut body = Vec::with_capacity((k.serialized_len() + value.serialized_len()) as usize);
+        check!(k.serialize(&mut body));
+        check!(value.serialize(&mut body));
+        let size = body.len() as u64;
+
+        let mut prev = NO_ELEMENT;
+        let mut freespace;
+        check!(self.free_space(), freespace);
+        while freespace != NO_ELEMENT {
+            let block;
+            check!(self.header_at(freespace), block);
+            if block.size >= size {
+                let mut bytes = vec![];
+                check!(block.size.serialize(&mut bytes));
+                check!(hash.serialize(&mut bytes));
+                check!(entry_crc(block.size, hash, &body).serialize(&mut bytes));
+                bytes.extend_from_slice(&body);
+                check!(self.dat.seek(SeekFrom::Start(freespace + 8)));
+                check!(self.dat.write_all(&bytes));
+                let unlink = if prev == NO_ELEMENT { Update::free_list(block.next) } else { Update::next(prev, block.next) };
+                return Ok((freespace, Some(unlink)))
             }
+            prev = freespace;
+            freespace = block.next;
         }
+
         let loc;
-        check!(self.dat.current_pos(), loc);
-        check!(NO_ELEMENT.serialize(&mut self.dat));
-        check!(size.serialize(&mut self.dat));
-        check!(hash.serialize(&mut self.dat));
-        check!(k.serialize(&mut self.dat));
-        check!(v.serialize(&mut self.dat));
-        Ok(loc)
-    }
-
-    fn link_all(&mut self) -> Result<u64, io::Error> {
-        let mut start = NO_ELEMENT;
-        let mut last = NO_ELEMENT;
-        for i in 0..self.len as u64 {
-            if last == NO_ELEMENT {
-                check!(self.bucket_location(i), start);
-                if start == NO_ELEMENT { continue }
-                check!(self.last_element(start), last);
-            } else {
-                let cur_bucket;
-                check!(self.bucket_location(i), cur_bucket);
-                if cur_bucket == NO_ELEMENT { continue }
-                check!(self.set_next(last, cur_bucket));
-                check!(self.last_element(cur_bucket), last);
+        check!(self.dat.seek(SeekFrom::End(0)), loc);
+        let header = EntryHeader { next: NO_ELEMENT, size: size, hash: hash, crc: entry_crc(size, hash, &body) };
+        let mut bytes = Vec::with_capacity((ENTRY_HEADER_LEN + size) as usize);
+        check!(header.serialize(&mut bytes));
+        bytes.extend_from_slice(&body);
+        check!(self.dat.write_all(&bytes));
+        Ok((loc, None))
+    }
+
+    /// Inserts key, replacing the value that was there if there was one. Will dynamically resize
+    /// the size of the hash once alpha is > .75 (alpha being the total number of elements divided
+    /// by the number of slots).
+    pub fn insert(&mut self, key: &K, value: &V) -> Result<(), Error> {
+        check!(self.finish_growing());
+        let mut key_bytes = vec![];
+        check!(key.serialize(&mut key_bytes));
+        let hash = hash_bytes(&key_bytes);
+        let old;
+        check!(self.find(hash, |t, pos| t.key_is(pos, &key_bytes)), old);
+        let bucket = hash & (self.len as u64 - 1);
+        let head;
+        check!(self.bucket(bucket), head);
+        let written;
+        check!(self.write_entry(key, value, hash), written);
+        let (location, unlink_block) = written;
+
+        let mut free;
+        check!(self.free_space(), free);
+        let mut updates = vec![];
+        if let Some(u) = unlink_block {
+            if u.file == DAT && u.pos == 0 {
+                free = u.value;
             }
+            updates.push(u);
         }
-        Ok(start)
-    }
-
-    /// Will dynamically resize the size of the hash once alpha is > .75 (alpha being
-    /// the total number of elements divided by the number of slots).
-    pub fn insert(&mut self, key: &K, value: &V) -> Result<(), io::Error> {
-        let hash = self.hash(&key);
-        match self.bucket_location(hash) {
-            Ok(x) => {
-                if x == NO_ELEMENT {
-                    let result = self.write_entry(key, value, hash);
-                    match result {
-                        Ok(loc) => {
-                            let _ = self.set_bucket_location(hash, loc);
-                        },
-                        Err(e) => return Err(e)
-                    }
+        match old {
+            // The old entry is taken out of the list, and its block goes on the free list
+            Some(ref f) => {
+                if f.pos == head {
+                    updates.push(Update::next(location, f.next));
                 } else {
-                    let result = self.write_entry(key, value, hash);
-                    let current_head_loc = self.bucket_location(hash);
-                    if result.is_err() {
-                        return Err(result.err().unwrap())
-                    } else if current_head_loc.is_err() {
-                        return Err(current_head_loc.err().unwrap())
-                    } else {
-                        let location = result.unwrap();
-                        let r1 = self.set_next(location, current_head_loc.unwrap());
-                        let r2 = self.set_bucket_location(hash, location);
-                        if r1.is_err() {
-                            return Err(r1.err().unwrap())
-                        } else if r2.is_err() {
-                            return Err(r2.err().unwrap())
-                        } else {
-                            let _ = self.increment_count();
-                        }
-                    }
+                    updates.push(Update::next(location, head));
+                    updates.push(f.unlink());
                 }
+                updates.push(Update::bucket(bucket, location));
+                updates.push(Update::next(f.pos, free));
+                updates.push(Update::free_list(f.pos));
             },
-            Err(e) => {
-                return Err(e)
+            None => {
+                updates.push(Update::next(location, head));
+                updates.push(Update::bucket(bucket, location));
+                updates.push(Update::header(COUNT_POS, self.count as u64 + 1));
             }
         }
-        // TODO: Fix this
-        if self.count as f32 / self.len as f32 >= 0.75 {
-            match self.link_all() {
-                Ok(head) => {
-                    check!(self.double_len());
-                    check!(self.clear_buckets());
-                    let mut cur = head;
-                    while cur != NO_ELEMENT {
-                        let _ = self.dat.seek(SeekFrom::Start(cur));
-                        match Entry::<K, V>::next_and_hash(&mut self.dat) {
-                            Ok((next, hash)) => {
-                                match self.bucket_location(hash) {
-                                    Ok(location) => {
-                                        let _ = self.dat.seek(SeekFrom::Start(cur));
-                                        let _ = location.serialize(&mut self.dat);
-                                        let _ = self.set_bucket_location(hash, cur);
-                                        cur = next;
-                                    },
-                                    Err(e) => return Err(e)
-                                }
-                            },
-                            Err(e) => return Err(e)
-                        }
+        check!(self.commit(&updates));
 
-                    }
-                },
-                Err(e) => return Err(e)
-            }
+        if self.count as f32 / self.len as f32 >= 0.75 {
+            check!(self.grow());
         }
         Ok(())
     }
 
-    pub fn remove(&mut self, key: &K::DeserializeOutput) -> Result<bool, io::Error> {
-        let hash = self.hash(&key);
-        let loc_res = self.bucket_location(hash);
-        if let Ok(loc) = loc_res {
-            if loc == NO_ELEMENT {
-                Ok(false)
-            } else {
-                // Save the pointer to the current entrie
-                let mut cur_pos = loc;
-                let mut prev = loc;
-
-                // Move to the current entry
-                check!(self.dat.seek(SeekFrom::Start(loc)));
-
-                // loop until we:
-                //  1 - Reach the end with no match, return None
-                //  2 - Find a match, return Some
-                //
-                // if neither of those things happen we seek to the next entry and repeat
-                loop {
-                    let meta = Entry::<K, V>::meta_data(&mut self.dat);
-                    match meta {
-                        Err(e) => return Err(e),
-                        Ok((next, entry_hash, entry_key)) => {
-                            if hash == entry_hash && *key == entry_key {
-                                if loc == cur_pos {
-                                    check!(self.set_bucket_location(hash, NO_ELEMENT));
-                                } else {
-                                    check!(self.dat.seek(SeekFrom::Start(cur_pos)));
-                                    check!(self.set_next(prev, next));
-                                }
-                                check!(self.add_free_space(cur_pos));
-                                return Ok(true)
-                            } else if next == NO_ELEMENT {
-                                return Ok(false)
-                            } else {
-                                check!(self.dat.seek(SeekFrom::Start(next)));
-                                prev = cur_pos;
-                                cur_pos = next;
-                            }
-                        }
-                    };
+    /// Doubles the number of buckets (see growing at the top of this file).
+    fn grow(&mut self) -> Result<(), io::Error> {
+        // The new buckets come after the ones in use, so nothing reads them yet
+        check!(self.manifest.seek(SeekFrom::Start(MANIFEST_OFFSET + 8 * self.len as u64)));
+        let mut empty = Vec::with_capacity(8 * self.len);
+        for _ in 0..self.len {
+            check!(NO_ELEMENT.serialize(&mut empty));
+        }
+        check!(self.manifest.write_all(&empty));
+        let new_len = 2 * self.len as u64;
+        check!(self.commit(&vec![Update::header(NEW_LEN_POS, new_len)]));
+        self.finish_growing()
+    }
+
+    /// Moves every entry that belongs in one of the new buckets there, one at a time, and then
+    /// starts hashing into all of the buckets. Does nothing if the table isn't growing.
+    fn finish_growing(&mut self) -> Result<(), io::Error> {
+        if self.new_len == self.len {
+            return Ok(())
+        }
+        let mask = self.new_len as u64 - 1;
+        for bucket in 0..self.len as u64 {
+            let mut prev = NO_ELEMENT;
+            let mut cur;
+            check!(self.bucket(bucket), cur);
+            while cur != NO_ELEMENT {
+                let header;
+                check!(self.header_at(cur), header);
+                let to = header.hash & mask;
+                if to != bucket {
+                    let head;
+                    check!(self.bucket(to), head);
+                    let found = Found { bucket: bucket, prev: prev, pos: cur, next: header.next };
+                    check!(self.commit(&vec![found.unlink(), Update::next(cur, head), Update::bucket(to, cur)]));
+                } else {
+                    prev = cur;
                 }
+                cur = header.next;
             }
-        } else {
-            Ok(false)
         }
+        let new_len = self.new_len as u64;
+        self.commit(&vec![Update::header(LEN_POS, new_len)])
     }
 
-    pub fn get(&mut self, key: &K::DeserializeOutput) -> Option<V::DeserializeOutput> {
-        let hash = self.hash(&key);
-        let loc_res = self.bucket_location(hash);
-        if let Ok(loc) = loc_res {
-            if loc == NO_ELEMENT {
-                None
-            } else {
-                // Save the pointer to the current entry
-                let mut cur_pos = loc;
-
-                // Move to the current entry
-                let _ = self.dat.seek(SeekFrom::Start(loc));
-
-                // loop until we:
-                //  1 - Reach the end with no match, return None
-                //  2 - Find a match, return Some
-                //
-                // if neither of those things happen we seek to the next entry and repeat
-                loop {
-                    let meta = Entry::<K, V>::meta_data(&mut self.dat);
-                    match meta {
-                        Err(_) => return None,
-                        Ok((next, entry_hash, entry_key)) => {
-
-                            if hash == entry_hash && *key == entry_key {
-                                let _ = self.dat.seek(SeekFrom::Start(cur_pos));
-                                match Entry::<K, V>::deserialize(&mut self.dat) {
-                                    Ok(entry) => return Some(entry.value),
-                                    Err(_)    => return None
-                                };
-                            } else if next == NO_ELEMENT {
-                                return None
-                            } else {
-                                let _ = self.dat.seek(SeekFrom::Start(next));
-                                cur_pos = next;
-                            }
-                        }
-                    };
-                }
+    /// Removes key, returning whether it was there.
+    pub fn remove(&mut self, key: &K::DeserializeOutput) -> Result<bool, Error> {
+        check!(self.finish_growing());
+        let hash;
+        check!(self.hash(key), hash);
+        let found;
+        check!(self.find(hash, |t, pos| t.key_equals(pos, key)), found);
+        let found = match found {
+            Some(f) => f,
+            None => return Ok(false)
+        };
+        let free;
+        check!(self.free_space(), free);
+        let count = if self.count > 0 { self.count as u64 - 1 } else { 0 };
+        check!(self.commit(&vec![found.unlink(), Update::next(found.pos, free), Update::free_list(found.pos),
+                                 Update::header(COUNT_POS, count)]));
+        Ok(true)
+    }
 
-            }
-        } else {
-            None
+    /// Returns the value for key, or None if there isn't one.
+    pub fn get(&mut self, key: &K::DeserializeOutput) -> Result<Option<V::DeserializeOutput>, Error> {
+        let hash;
+        check!(self.hash(key), hash);
+        let found;
+        check!(self.find(hash, |t, pos| t.key_equals(pos, key)), found);
+        let pos = match found {
+            Some(f) => f.pos,
+            None => return Ok(None)
+        };
+        let entry;
+        {
+            let mut from;
+            check!(read_at(&mut self.dat, pos), from);
+            check!(Entry::<K, V>::read_checked(&mut from, pos), entry);
         }
+        let value;
+        check!(entry.value.unpack(), value);
+        Ok(Some(value))
+    }
+
+    /// Walks every bucket and the free list, making sure that every pointer lands inside of
+    /// the dat file, every list actually ends, and every entry is in the bucket its hash says
+    /// it belongs in. Returns the number of entries that were found.
+    pub fn verify(&mut self) -> Result<usize, Error> {
+        self.check_lists(false)
+    }
+
+    /// Does everything verify does, and also reads every entry, checking its checksum. The
+    /// first corrupt entry is returned as Error::Corrupt.
+    pub fn scrub(&mut self) -> Result<usize, Error> {
+        self.check_lists(true)
     }
 
-    fn hash<T: Hash>(&self, k: &T) -> u64 {
-        let mut hasher = DefaultHasher::new();
-        k.hash(&mut hasher);
-        hasher.finish()
-    }
-
-    pub fn contains_key(&mut self, key: &K::DeserializeOutput) -> Result<bool, Option<io::Error>> {
-        let hash = self.hash(&key);
-        let loc_res = self.bucket_location(hash);
-        if let Ok(loc) = loc_res {
-            if loc == NO_ELEMENT {
-                Err(None)
-            } else {
-                // Move to the current entry
-                let _ = self.dat.seek(SeekFrom::Start(loc));
-
-                // loop until we:
-                //  1 - Reach the end with no match, return false
-                //  2 - Find a match, return true
-                //
-                // if neither of those things happen we seek to the next entry and repeat
-                loop {
-                    let meta = Entry::<K, V>::meta_data(&mut self.dat);
-                    match meta {
-                        Err(_) => return Err(None),
-                        Ok((next, entry_hash, entry_key)) => {
-                            if hash == entry_hash && *key == entry_key {
-                                return Ok(true)
-                            } else if next == NO_ELEMENT {
-                                return Err(None)
-                            } else {
-                                let _ = self.dat.seek(SeekFrom::Start(next));
-                            }
-                        }
-                    };
+    fn check_lists(&mut self, checksums: bool) -> Result<usize, Error> {
+        let dat_len;
+        check!(self.dat.seek(SeekFrom::End(0)), dat_len);
+        // No list can have more entries than could possibly fit in the file
+        let max_entries = dat_len / ENTRY_HEADER_LEN;
+        let mut found = 0;
+        for i in 0..self.new_len as u64 {
+            let mut cur;
+            check!(self.bucket(i), cur);
+            let mut steps = 0;
+            while cur != NO_ELEMENT {
+                if cur < 8 || cur.checked_add(ENTRY_HEADER_LEN).map_or(true, |end| end > dat_len) {
+                    return Err(From::from(corruption("bucket", i, format!("entry outside of the dat file ({})", cur))))
+                }
+                steps += 1;
+                if steps > max_entries {
+                    return Err(From::from(corruption("bucket", i, "the list never ends".to_string())))
+                }
+                let header;
+                check!(self.header_at(cur), header);
+                // While growing, an entry can still be in the bucket it hashed to before
+                let (before, after) = (header.hash & (self.len as u64 - 1), header.hash & (self.new_len as u64 - 1));
+                if before != i && after != i {
+                    return Err(From::from(corruption("entry", cur, format!("it is in bucket {} but hashes to bucket {}", i, after))))
                 }
+                if checksums {
+                    let mut from;
+                    check!(read_at(&mut self.dat, cur), from);
+                    check!(Entry::<K, V>::read_checked(&mut from, cur));
+                }
+                found += 1;
+                cur = header.next;
+            }
+        }
 
+        let mut cur;
+        check!(self.free_space(), cur);
+        let mut steps = 0;
+        while cur != NO_ELEMENT {
+            if cur < 8 || cur.checked_add(ENTRY_HEADER_LEN).map_or(true, |end| end > dat_len) {
+                return Err(From::from(corruption("free list", 0, format!("block outside of the dat file ({})", cur))))
             }
-        } else {
-            Err(None)
+            steps += 1;
+            if steps > max_entries {
+                return Err(From::from(corruption("free list", 0, "the list never ends".to_string())))
+            }
+            let block;
+            check!(self.header_at(cur), block);
+            cur = block.next;
+        }
+        Ok(found)
+    }
+
+    /// Hashes the bytes key serializes to, which are the same for a K and the
+    /// K::DeserializeOutput it reads back as.
+    fn hash<T: Serialize>(&self, key: &T) -> Result<u64, io::Error> {
+        let mut key_bytes = vec![];
+        check!(key.serialize(&mut key_bytes));
+        Ok(hash_bytes(&key_bytes))
+    }
+
+    pub fn contains_key(&mut self, key: &K::DeserializeOutput) -> Result<bool, Error> {
+        let hash;
+        check!(self.hash(key), hash);
+        let found;
+        check!(self.find(hash, |t, pos| t.key_equals(pos, key)), found);
+        Ok(found.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ PHash, MANIFEST, DAT, MANIFEST_OFFSET };
+    use storage::MemFile;
+    use compress::Compression;
+    use error::Error;
+
+    fn table() -> PHash<String, String, MemFile> {
+        let mut table = PHash::<String, String, MemFile>::create(MemFile::new(), MemFile::new(), Compression::Deflate).unwrap();
+        for i in 0..4 {
+            table.insert(&format!("key {}", i), &format!("value {}", i)).unwrap();
+        }
+        assert_eq!(table.scrub().unwrap(), 4);
+        table
+    }
+
+    #[test]
+    fn test_bucket_pointer_near_end_of_address_space() {
+        let mut table = table();
+        // A pointer this close to u64::MAX overflows when the header length is added to it
+        for i in 0..table.new_len as u64 {
+            table.write_pointer(MANIFEST, MANIFEST_OFFSET + 8 * i, u64::max_value() - 2).unwrap();
+        }
+        match table.scrub() {
+            Err(Error::Corrupt(_)) => {},
+            other => panic!("expected a corrupt error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_free_pointer_near_end_of_address_space() {
+        let mut table = table();
+        table.write_pointer(DAT, 0, u64::max_value() - 2).unwrap();
+        match table.verify() {
+            Err(Error::Corrupt(_)) => {},
+            other => panic!("expected a corrupt error, got {:?}", other)
         }
     }
 }
@@ -1,30 +1,12 @@
-use std::hash::{ Hash, Hasher };
-use std::collections::hash_map::DefaultHasher;
-use std::io::{ Seek, SeekFrom, Read, Write };
+use std::io::{ Seek, SeekFrom };
 use std::io;
-use random_access_file::Serialize;
+use codec::Serialize;
 use cfile_rs::CFile;
 use std::marker::PhantomData;
-
-/// An incredibly useful macro. It will check an expression of type Error<T, Z>,
-/// if it is Err(err) it will RETURN in whatever function it is placed in with
-/// error, otherwise it will continue in the function. This cuts down on the amount
-/// of error checking code that will clog things up.
-/// Optionally, it will also store the value x in $v (e.g. if it is Ok(x), $v = x).
-macro_rules! check {
-    ( $e:expr ) => (
-    match $e {
-        Ok(_) => {},
-    Err(e) => return Err(e)
-        }
-    );
-    ( $e:expr, $v:ident) => (
-        match $e {
-            Ok(r) => $v = r,
-            Err(e) => return Err(e)
-        }
-    )
-}
+use storage::{ Storage, MmapFile, LockFile, invalid_data, read_at, bytes_at };
+use compress::{ Compression, Packed };
+use checksum::{ read_checked, write_checked, corruption };
+use error::{ Error, Context };
 
 #[derive(PartialEq, Eq)]
 struct Entry {
@@ -37,13 +19,13 @@ impl Entry {
         Entry { key: key, value: value }
     }
 
-    fn len(from: &mut CFile) -> Result<u64, io::Error> {
+    fn len<S: Storage>(from: &mut S) -> Result<u64, io::Error> {
         let len;
         check!(u64::deserialize(from), len);
         Ok(len)
     }
 
-    fn pair(from: &mut CFile) -> Result<(u64, u64), io::Error> {
+    fn pair<S: Storage>(from: &mut S) -> Result<(u64, u64), io::Error> {
         let key;
         let value;
         check!(u64::deserialize(from), key);
@@ -51,41 +33,79 @@ impl Entry {
         Ok((key, value))
     }
 
-    fn read_entry(pos: u64, from: &mut CFile) -> Result<Entry, io::Error> {
+    fn read_entry<S: Storage>(pos: u64, from: &mut S) -> Result<Entry, io::Error> {
         check!(from.seek(SeekFrom::Start(pos)));
         Self::deserialize(from)
     }
+
+    /// Parses an Entry (and checks its checksum) straight out of a slice of ENTRY_LEN bytes.
+    fn from_bytes(b: &[u8], pos: u64) -> Result<Entry, io::Error> {
+        if (b.len() as u64) < ENTRY_LEN {
+            return Err(invalid_data(format!("an entry needs {} bytes, only got {}", ENTRY_LEN, b.len())))
+        }
+        read_checked::<Entry>(&mut &b[..ENTRY_LEN as usize], "entry", pos)
+    }
 }
 
-impl Serialize for Entry
-    where {
-    type DeserializeOutput = Entry;
+serializable!(Entry { key: u64, value: u64 });
 
-    fn serialize(&self, to: &mut Write) -> Result<(), io::Error> {
-        check!(self.key.serialize(to));
-        check!(self.value.serialize(to));
-        Ok(())
-    }
-    fn deserialize(from: &mut Read) -> Result<Entry, io::Error> {
-        let key;
-        let val;
-        check!(u64::deserialize(from), key);
-        check!(u64::deserialize(from), val);
-        Ok(
-            Entry {
-                key: key,
-                value: val
-            }
-        )
-    }
-    fn serialized_len(&self) -> u64 {
-        8 + 8 + self.key.serialized_len()
-    }
+/// Which node is the root, written into one of the two root slots in the tree file header.
+struct Root {
+    pub location: u64,
+    /// How many times a root has been written. The slot with the bigger one is the root.
+    pub generation: u64
 }
+
+serializable!(Root { location: u64, generation: u64 });
+
+/*
+                            ********************************
+                            *     PERSISTANT TREE LAYOUT   *
+                            ********************************
+
+tree file:
+
+        64 bits -> How values are compressed (Compression::to_u8).
+        160 bits -> Root slot 0.
+        160 bits -> Root slot 1.
+        n bits  -> Nodes and entries.
+
+    A root slot is the position of the root node and its generation (64 bits each), and a
+    CRC32C of those.
+
+key and val files:
+
+    Every key and (packed, see compress.rs) value that was ever inserted, one after another,
+    each followed by its CRC32C. Entries in the tree file point into these.
+
+Nodes are never written over. Inserting or removing writes a copy of every node it changes,
+all the way up to a new root, onto the end of the tree file, and then writes the new root into
+the slot that wasn't written last, with a generation one bigger than the last one. Opening the
+tree reads both slots, and the one with the bigger generation that passes its checksum is the
+root. So if the program dies before a root slot is written, the tree is the one it was before
+the insert or remove, and the nodes that were already written are just never used. If it dies
+while a root slot is being written, that slot doesn't pass its checksum, and the other one still
+has the tree from before.
+
+Removing doesn't merge nodes that end up with fewer than T - 1 entries, like a textbook B-tree
+would, so the tree stays the same depth everywhere but nodes can end up empty.
+*/
+
 const T: usize = 24;
 const NUM_CHILDREN: usize = 2 * T;
 const NUM_ENTRIES: usize = NUM_CHILDREN - 1;
 const IS_NONE: u64 = 0xFFFFFFFFFFFFFFFFu64;
+/// The number of bytes a serialized Node takes up, checksum included.
+const NODE_LEN: u64 = (NUM_ENTRIES * 8 + NUM_CHILDREN * 8 + 8 + 1 + 4) as u64;
+/// The number of bytes a serialized Entry takes up, checksum included.
+const ENTRY_LEN: u64 = 16 + 4;
+/// Where the two root slots are, and how long each one is, checksum included.
+const ROOTS_POS: u64 = 8;
+const ROOT_LEN: u64 = 16 + 4;
+/// The compression and the root slots come before the first node in the tree file.
+const TREE_HEADER_LEN: u64 = ROOTS_POS + 2 * ROOT_LEN;
+/// No real tree will ever get this deep.
+const MAX_DEPTH: usize = 64;
 struct Node {
     /// Disk positions of Entries. If the value is IS_NONE, there is no key.
     pub entries: [u64; NUM_ENTRIES],
@@ -106,141 +126,237 @@ impl Node {
             leaf: true,
         }
     }
-}
 
-impl Serialize for Node {
-    type DeserializeOutput = Self;
-    fn serialize(&self, to: &mut Write) -> Result<(), io::Error> {
-         for i in 0..NUM_ENTRIES {
-             check!(self.entries[i].serialize(to));
-         }
-         for i in 0..NUM_CHILDREN {
-             check!(self.children[i].serialize(to));
-         }
-         check!(self.len.serialize(to));
-         if self.leaf {
-             check!(1u8.serialize(to));
-         } else {
-             check!(0u8.serialize(to));
-         }
-         Ok(())
-    }
-    fn deserialize(from: &mut Read) -> Result<Node, io::Error> {
-        let mut keys = [0u64; NUM_ENTRIES];
-        let mut children = [0u64; NUM_CHILDREN];
-
-        for i in 0..NUM_ENTRIES {
-            let res;
-            check!(u64::deserialize(from), res);
-            keys[i] = res;
-        }
-        for i in 0..NUM_CHILDREN {
-            let res;
-            check!(u64::deserialize(from), res);
-            children[i] = res;
+    /// Parses a Node (and checks its checksum) straight out of a slice of NODE_LEN bytes,
+    /// rather than asking a file for one u64 at a time.
+    fn from_bytes(b: &[u8], pos: u64) -> Result<Node, io::Error> {
+        if (b.len() as u64) < NODE_LEN {
+            return Err(invalid_data(format!("a node needs {} bytes, only got {}", NODE_LEN, b.len())))
         }
-        let len;
-        check!(u64::deserialize(from), len);
+        read_checked::<Node>(&mut &b[..NODE_LEN as usize], "node", pos)
+    }
+}
 
-        let is_leaf;
-        check!(u8::deserialize(from), is_leaf);
-        Ok (
-            Node {
-                entries: keys,
-                children: children,
-                len: len,
-                leaf: is_leaf != 0u8
-            }
-        )
+/// Takes the entry at `entry` and the child at `child` out of x, moving everything after them
+/// down.
+fn remove_at(x: &mut Node, entry: usize, child: usize) {
+    let n = x.len as usize;
+    for j in entry .. n - 1 {
+        x.entries[j] = x.entries[j + 1];
     }
-    fn serialized_len(&self) -> u64 {
-        (NUM_ENTRIES * 8 + NUM_CHILDREN * 8 + 8) as u64
+    if !x.leaf {
+        for j in child .. n {
+            x.children[j] = x.children[j + 1];
+        }
     }
+    x.len -= 1;
 }
 
+serializable!(Node { entries: [u64; NUM_ENTRIES], children: [u64; NUM_CHILDREN], len: u64, leaf: bool });
 
-
-pub struct PTree<K, V> where K: Serialize + Eq + PartialOrd + PartialEq,
+pub struct PTree<K, V, S = CFile> where K: Serialize + Eq + PartialOrd + PartialEq,
                              V: Serialize,
                              K::DeserializeOutput: Serialize + Eq + PartialOrd + PartialEq,
-                             V::DeserializeOutput: Serialize {
-    treefile: CFile,
-    keyfile: CFile,
-    valfile: CFile,
+                             V::DeserializeOutput: Serialize,
+                             S: Storage {
+    treefile: S,
+    keyfile: S,
+    valfile: S,
     root_location: u64,
+    /// The generation of the root that was written last.
+    generation: u64,
+    compression: Compression,
+    /// Held for as long as the files are open for writing.
+    lock: Option<LockFile>,
     phantom_k: PhantomData<K>,
     phantom_v: PhantomData<V>
 }
 
 use std::fmt::Display;
 
-impl<K, V> PTree<K, V> where K: Serialize + Eq + PartialOrd + PartialEq,
+impl<K, V> PTree<K, V, CFile> where K: Serialize + Eq + PartialOrd + PartialEq,
                        V: Serialize,
                        V::DeserializeOutput: Serialize,
                        K::DeserializeOutput: Serialize + Eq + PartialOrd + PartialEq {
 
-    pub fn new(path: &str) -> Result<PTree<K, V>, io::Error> {
-        let mut treefile;
-        check!(CFile::open(&(path.to_string() + ".tree"), "w+"), treefile);
-        let mut keyfile;
-        check!(CFile::open(&(path.to_string() + ".key"), "w+"), keyfile);
-        let mut valfile;
-        check!(CFile::open(&(path.to_string() + ".val"), "w+"), valfile);
-
-        let mut node = Node::new();
-        let _ = treefile.seek(SeekFrom::Start(8));
-        let _ = node.serialize(&mut treefile);
-        Ok(
-            PTree {
-                keyfile: keyfile,
-                valfile: valfile,
-                treefile: treefile,
-                root_location: 8,
-                phantom_k: PhantomData {},
-                phantom_v: PhantomData {}
-            }
-        )
+    /// Creates a new PTree, which compresses its values with `compression`. If there is already
+    /// a PTree at path, it gets written over.
+    pub fn new(path: &str, compression: Compression) -> Result<PTree<K, V, CFile>, Error> {
+        let lock;
+        check!(LockFile::acquire(path), lock);
+        let treefile;
+        check!(CFile::open(&(path.to_string() + ".tree"), "w+").context(&format!("creating {}.tree", path)), treefile);
+        let keyfile;
+        check!(CFile::open(&(path.to_string() + ".key"), "w+").context(&format!("creating {}.key", path)), keyfile);
+        let valfile;
+        check!(CFile::open(&(path.to_string() + ".val"), "w+").context(&format!("creating {}.val", path)), valfile);
+        let mut tree;
+        check!(PTree::create(treefile, keyfile, valfile, compression).context(&format!("creating {}", path)), tree);
+        tree.lock = Some(lock);
+        Ok(tree)
+    }
+
+    pub fn open(path: &str) -> Result<PTree<K, V, CFile>, Error> {
+        let lock;
+        check!(LockFile::acquire(path), lock);
+        let treefile;
+        check!(CFile::open(&(path.to_string() + ".tree"), "r+").context(&format!("opening {}.tree", path)), treefile);
+        let keyfile;
+        check!(CFile::open(&(path.to_string() + ".key"), "r+").context(&format!("opening {}.key", path)), keyfile);
+        let valfile;
+        check!(CFile::open(&(path.to_string() + ".val"), "r+").context(&format!("opening {}.val", path)), valfile);
+        let mut tree;
+        check!(PTree::load(treefile, keyfile, valfile).context(&format!("opening {}", path)), tree);
+        tree.lock = Some(lock);
+        Ok(tree)
+    }
+}
+
+impl<K, V> PTree<K, V, MmapFile> where K: Serialize + Eq + PartialOrd + PartialEq,
+                       V: Serialize,
+                       V::DeserializeOutput: Serialize,
+                       K::DeserializeOutput: Serialize + Eq + PartialOrd + PartialEq {
+
+    /// Opens a PTree with all of its files memory mapped. Nodes, keys and values are parsed
+    /// right out of memory, which makes lookups a lot cheaper, but the tree is read only:
+    /// inserting will return Err.
+    pub fn open_mapped(path: &str) -> Result<PTree<K, V, MmapFile>, Error> {
+        let treefile;
+        check!(MmapFile::open(&(path.to_string() + ".tree")).context(&format!("mapping {}.tree", path)), treefile);
+        let keyfile;
+        check!(MmapFile::open(&(path.to_string() + ".key")).context(&format!("mapping {}.key", path)), keyfile);
+        let valfile;
+        check!(MmapFile::open(&(path.to_string() + ".val")).context(&format!("mapping {}.val", path)), valfile);
+        PTree::load(treefile, keyfile, valfile).context(&format!("opening {}", path))
+    }
+}
+
+impl<K, V, S> PTree<K, V, S> where K: Serialize + Eq + PartialOrd + PartialEq,
+                       V: Serialize,
+                       V::DeserializeOutput: Serialize,
+                       K::DeserializeOutput: Serialize + Eq + PartialOrd + PartialEq,
+                       S: Storage {
+
+    /// Creates a new, empty PTree in the given backends. Anything that was already in them
+    /// gets written over.
+    pub fn create(mut treefile: S, keyfile: S, valfile: S, compression: Compression) -> Result<PTree<K, V, S>, Error> {
+        let node = Node::new();
+        check!(treefile.seek(SeekFrom::Start(0)));
+        check!((compression.to_u8() as u64).serialize(&mut treefile));
+        // Neither slot passes its checksum until the empty root is written
+        check!(treefile.write_all(&[0u8; 2 * ROOT_LEN as usize]));
+        check!(write_checked(&node, &mut treefile));
+        let mut tree = PTree {
+            keyfile: keyfile,
+            valfile: valfile,
+            treefile: treefile,
+            root_location: TREE_HEADER_LEN,
+            generation: 0,
+            compression: compression,
+            lock: None,
+            phantom_k: PhantomData {},
+            phantom_v: PhantomData {}
+        };
+        check!(tree.set_root(TREE_HEADER_LEN));
+        Ok(tree)
     }
 
-    pub fn open(path: &str) -> Result<PTree<K, V>, io::Error> {
-        let mut treefile;
-        check!(CFile::open(&(path.to_string() + ".tree"), "r+"), treefile);
-        let mut keyfile;
-        check!(CFile::open(&(path.to_string() + ".key"), "r+"), keyfile);
-        let mut valfile;
-        check!(CFile::open(&(path.to_string() + ".val"), "r+"), valfile);
+    /// Opens a PTree that was previously created in the given backends.
+    pub fn load(mut treefile: S, keyfile: S, valfile: S) -> Result<PTree<K, V, S>, Error> {
         check!(treefile.seek(SeekFrom::Start(0)));
-        let root;
-        check!(u64::deserialize(&mut treefile), root);
+        let flag;
+        check!(u64::deserialize(&mut treefile), flag);
+        if flag > 0xFF {
+            return Err(From::from(corruption("tree header", 0, format!("{} is not a kind of compression", flag))))
+        }
+        let compression;
+        check!(Compression::from_u8(flag as u8).map_err(|e| corruption("tree header", 0, format!("{}", e))), compression);
+
+        // A slot that doesn't pass its checksum was being written when the program died
+        let mut root: Option<Root> = None;
+        for slot in 0..2 {
+            let pos = ROOTS_POS + slot * ROOT_LEN;
+            let bytes;
+            check!(bytes_at(&mut treefile, pos, ROOT_LEN as usize), bytes);
+            if let Ok(r) = read_checked::<Root>(&mut &bytes[..], "root", pos) {
+                if root.as_ref().map(|x| r.generation > x.generation).unwrap_or(true) {
+                    root = Some(r);
+                }
+            }
+        }
+        let root = match root {
+            Some(r) => r,
+            None => return Err(From::from(corruption("tree header", ROOTS_POS, "neither root slot passes its checksum".to_string())))
+        };
 
         Ok(
             PTree {
                 keyfile: keyfile,
                 treefile: treefile,
                 valfile: valfile,
-                root_location: root,
+                root_location: root.location,
+                generation: root.generation,
+                compression: compression,
+                lock: None,
                 phantom_k: PhantomData {},
                 phantom_v: PhantomData {}
             }
         )
     }
 
-    pub fn keys(&mut self) -> Result<Vec<K::DeserializeOutput>, io::Error> {
+    /// Makes the node at `loc` the root, by writing it into the slot that wasn't written last.
+    fn set_root(&mut self, loc: u64) -> Result<(), io::Error> {
+        let root = Root { location: loc, generation: self.generation + 1 };
+        check!(self.treefile.seek(SeekFrom::Start(ROOTS_POS + (root.generation % 2) * ROOT_LEN)));
+        check!(write_checked(&root, &mut self.treefile));
+        self.root_location = loc;
+        self.generation = root.generation;
+        Ok(())
+    }
+
+    /// Returns every key in the tree, in order.
+    pub fn keys(&mut self) -> Result<Vec<K::DeserializeOutput>, Error> {
         let mut keys = vec![];
-        check!(self.keyfile.seek(SeekFrom::Start(0)));
-        loop {
-            match K::deserialize(&mut self.keyfile) {
-                Ok(r) => keys.push(r),
-                Err(_) => break
+        let root = self.root_location;
+        check!(self.keys_rec(root, 0, &mut keys));
+        Ok(keys)
+    }
+
+    fn keys_rec(&mut self, pos: u64, depth: usize, keys: &mut Vec<K::DeserializeOutput>) -> Result<(), io::Error> {
+        if depth > MAX_DEPTH {
+            return Err(corruption("node", pos, "the tree never ends".to_string()))
+        }
+        let x;
+        check!(self.read_node(pos), x);
+        for i in 0..x.len as usize + 1 {
+            if !x.leaf {
+                check!(self.keys_rec(x.children[i], depth + 1, keys));
+            }
+            if i < x.len as usize {
+                let k_i;
+                check!(self.read_key(x.entries[i]), k_i);
+                keys.push(k_i);
             }
         }
-        Ok(keys)
+        Ok(())
+    }
+
+    /// Finds where k goes in x: the index of the first entry whose key isn't less than k, and
+    /// whether that key is k.
+    fn find(&mut self, x: &Node, k: &K::DeserializeOutput) -> Result<(usize, bool), io::Error> {
+        for i in 0..x.len as usize {
+            let k_i;
+            check!(self.read_key(x.entries[i]), k_i);
+            if !(k > &k_i) {
+                return Ok((i, k == &k_i))
+            }
+        }
+        Ok((x.len as usize, false))
     }
 
-    fn split_child(&mut self, x: &mut Node, x_loc: u64, child: usize) -> Result<(), io::Error> {
-        let mut y;
-        check!(self.read_node(x.children[child]), y);
-        let y_loc = x.children[child];
+    /// Splits y, the full child of x at `child`, into two nodes, with the entry in the middle of
+    /// y moving up into x. The two halves are written, but x isn't. Returns the two halves.
+    fn split_child(&mut self, x: &mut Node, child: usize, mut y: Node) -> Result<(Node, Node), io::Error> {
         let mut z = Node::new();
         z.leaf = y.leaf;
         z.len = T as u64 - 1;
@@ -253,129 +369,266 @@ impl<K, V> PTree<K, V> where K: Serialize + Eq + PartialOrd + PartialEq,
             }
         }
         y.len = T as u64 - 1;
+        let y_loc;
+        check!(self.write_node(&y), y_loc);
+        let z_loc;
+        check!(self.write_node(&z), z_loc);
         for j in (child + 1 .. (x.len + 1) as usize).rev() {
             x.children[j + 1] = x.children[j];
         }
-        let z_loc;
-        check!(self.write_node(&z), z_loc);
-        x.children[child as usize + 1] = z_loc;
+        x.children[child] = y_loc;
+        x.children[child + 1] = z_loc;
         for j in (child as i64 .. x.len as i64).rev() {
             x.entries[j as usize + 1] = x.entries[j as usize];
         }
         x.len += 1;
         x.entries[child] = y.entries[T - 1]; // Minus one because arrays are base zero etc.
-        check!(self.update_node(&x, x_loc));
-        check!(self.update_node(&y, y_loc));
-        Ok(())
+        Ok((y, z))
     }
 
-    pub fn insert(&mut self, k: &K::DeserializeOutput, v: &V::DeserializeOutput) -> Result<(), io::Error> {
+    /// Inserts k, replacing its value if it's already there.
+    pub fn insert(&mut self, k: &K::DeserializeOutput, v: &V::DeserializeOutput) -> Result<(), Error> {
         let mut r;
         let r_loc = self.root_location;
         check!(self.root(), r);
         if r.len == NUM_ENTRIES as u64 {
             let mut s = Node::new();
-            let s_loc;
             s.leaf = false;
             s.len = 0;
             s.children[0] = r_loc;
-            check!(self.write_node(&s), s_loc);
-            self.root_location = s_loc;
-            check!(self.treefile.seek(SeekFrom::Start(0)));
-            check!(self.root_location.serialize(&mut self.treefile));
-            check!(self.split_child(&mut s, s_loc, 0));
-            check!(self.insert_nonfull(&mut s, s_loc, k, v));
-        } else {
-            check!(self.insert_nonfull(&mut r, r_loc, k, v));
+            check!(self.split_child(&mut s, 0, r));
+            r = s;
         }
+        let new_root;
+        check!(self.insert_nonfull(r, k, v), new_root);
+        check!(self.set_root(new_root));
         Ok(())
     }
 
-    fn insert_nonfull(&mut self, x: &mut Node, x_loc: u64, k: &K::DeserializeOutput, v: &V::DeserializeOutput) -> Result<(), io::Error> {
-        let mut i = x.len as i64;
-        let mut r;
-        check!(self.root(), r);
+    /// Inserts into the subtree x, which isn't full, writing a copy of every node that changes.
+    /// Returns where the copy of x went.
+    fn insert_nonfull(&mut self, mut x: Node, k: &K::DeserializeOutput, v: &V::DeserializeOutput) -> Result<u64, io::Error> {
+        let found;
+        check!(self.find(&x, k), found);
+        let (mut i, exists) = found;
+        if exists {
+            let entry_loc;
+            check!(self.write_entry(k, v), entry_loc);
+            x.entries[i] = entry_loc;
+            return self.write_node(&x)
+        }
         if x.leaf {
-            if i > 0 {
-                i -= 1;
-                let mut k_i;
-                check!(self.read_key(x.entries[i as usize]), k_i);
-                while i >= 0 && k < &k_i {
-                    x.entries[i as usize + 1] = x.entries[i as usize];
-                    i -= 1;
-                    if i >= 0 {
-                        check!(self.read_key(x.entries[i as usize]), k_i);
-                    }
+            for j in (i .. x.len as usize).rev() {
+                x.entries[j + 1] = x.entries[j];
+            }
+            let entry_loc;
+            check!(self.write_entry(k, v), entry_loc);
+            x.entries[i] = entry_loc;
+            x.len += 1;
+            self.write_node(&x)
+        } else {
+            let mut c_i;
+            check!(self.read_node(x.children[i]), c_i);
+            if c_i.len == NUM_ENTRIES as u64 {
+                let halves;
+                check!(self.split_child(&mut x, i, c_i), halves);
+                let k_i;
+                check!(self.read_key(x.entries[i]), k_i);
+                if k == &k_i {
+                    let entry_loc;
+                    check!(self.write_entry(k, v), entry_loc);
+                    x.entries[i] = entry_loc;
+                    return self.write_node(&x)
                 }
-                let entry_loc;
-                check!(self.write_entry(k, v), entry_loc);
-                x.len += 1;
-                x.entries[(i + 1) as usize] = entry_loc;
-                check!(self.update_node(x, x_loc));
-                Ok(())
+                c_i = if k > &k_i {
+                    i += 1;
+                    halves.1
+                } else {
+                    halves.0
+                };
+            }
+            let c_loc;
+            check!(self.insert_nonfull(c_i, k, v), c_loc);
+            x.children[i] = c_loc;
+            self.write_node(&x)
+        }
+    }
+
+    /// Removes k, returning whether it was there.
+    pub fn remove(&mut self, k: &K::DeserializeOutput) -> Result<bool, Error> {
+        let r;
+        check!(self.root(), r);
+        let new_root;
+        check!(self.remove_rec(r, k), new_root);
+        match new_root {
+            Some(loc) => {
+                check!(self.set_root(loc));
+                Ok(true)
+            },
+            None => Ok(false)
+        }
+    }
+
+    /// Removes k from the subtree x, writing a copy of every node that changes. Returns where
+    /// the copy of x went, or None if k isn't in it.
+    fn remove_rec(&mut self, mut x: Node, k: &K::DeserializeOutput) -> Result<Option<u64>, io::Error> {
+        let found;
+        check!(self.find(&x, k), found);
+        let (i, exists) = found;
+        if exists {
+            if x.leaf {
+                remove_at(&mut x, i, i);
             } else {
-                let entry_loc;
-                check!(self.write_entry(k, v), entry_loc);
-                x.entries[0] = entry_loc;
-                x.len += 1;
-                check!(self.update_node(x, x_loc));
-                Ok(())
+                // The biggest entry to the left of k takes its place
+                let c_i;
+                check!(self.read_node(x.children[i]), c_i);
+                let popped;
+                check!(self.pop_max(c_i), popped);
+                match popped {
+                    Some((c_loc, entry)) => {
+                        x.children[i] = c_loc;
+                        x.entries[i] = entry;
+                    },
+                    // There's nothing to the left of k, so the empty subtree can go with it
+                    None => remove_at(&mut x, i, i)
+                }
             }
+            self.write_node(&x).map(Some)
+        } else if x.leaf {
+            Ok(None)
         } else {
-            let mut k_i;
-            i -= 1;
-            check!(self.read_key(x.entries[i as usize]), k_i);
-            while i >= 0 && k < &k_i {
-                i -= 1;
-                if i >= 0 { check!(self.read_key(x.entries[i as usize]), k_i); }
+            let c_i;
+            check!(self.read_node(x.children[i]), c_i);
+            let c_loc;
+            check!(self.remove_rec(c_i, k), c_loc);
+            match c_loc {
+                Some(loc) => {
+                    x.children[i] = loc;
+                    self.write_node(&x).map(Some)
+                },
+                None => Ok(None)
             }
-            i = i + 1;
-            let x_child_i;
-            check!(self.read_node(x.children[i as usize]), x_child_i);
-            if x_child_i.len == NUM_ENTRIES as u64 {
-                self.split_child(x, x_loc, i as usize);
-                let k_i;
-                check!(self.read_key(x.entries[i as usize]), k_i);
-                if k > &k_i {
-                    i += 1
-                }
+        }
+    }
+
+    /// Takes the biggest entry out of the subtree x, writing a copy of every node that changes.
+    /// Returns where the copy of x went and the entry, or None if there are no entries in it.
+    fn pop_max(&mut self, mut x: Node) -> Result<Option<(u64, u64)>, io::Error> {
+        let n = x.len as usize;
+        if !x.leaf {
+            let last;
+            check!(self.read_node(x.children[n]), last);
+            let popped;
+            check!(self.pop_max(last), popped);
+            if let Some((c_loc, entry)) = popped {
+                x.children[n] = c_loc;
+                return self.write_node(&x).map(|loc| Some((loc, entry)))
             }
-            let mut c_i;
-            check!(self.read_node(x.children[i as usize]), c_i);
-            self.insert_nonfull(&mut c_i, x.children[i as usize], k, v)
         }
+        if n == 0 {
+            return Ok(None)
+        }
+        // Everything to the right of the last entry is empty, so it goes along with the entry
+        let entry = x.entries[n - 1];
+        x.len -= 1;
+        self.write_node(&x).map(|loc| Some((loc, entry)))
     }
 
-    pub fn search(&mut self, k: &K::DeserializeOutput) -> Result<Option<V::DeserializeOutput>, io::Error> {
+    pub fn search(&mut self, k: &K::DeserializeOutput) -> Result<Option<V::DeserializeOutput>, Error> {
         let root = self.root_location;
-        self.search_rec(k, root)
+        self.search_rec(k, root).map_err(From::from)
     }
 
     fn search_rec(&mut self, k: &K::DeserializeOutput, pos: u64) -> Result<Option<V::DeserializeOutput>, io::Error> {
         let x;
         check!(self.read_node(pos), x);
-        if x.len == 0 { return Ok(None); }
-        let mut k_i: K::DeserializeOutput;
-        check!(self.read_key(x.entries[0]), k_i);
-        let mut i = 0;
-
-        while i < x.len && k > &k_i {
-            i += 1;
-            if i < x.len {
-                check!(self.read_key(x.entries[i as usize]), k_i);
-            }
-        }
-        if i < x.len && k == &k_i {
+        let found;
+        check!(self.find(&x, k), found);
+        let (i, exists) = found;
+        if exists {
             let ret;
-            check!(self.read_value(x.entries[i as usize]), ret);
+            check!(self.read_value(x.entries[i]), ret);
             Ok(Some(ret))
         } else if x.leaf {
             Ok(None)
         } else {
-            self.search_rec(k, x.children[i as usize])
+            self.search_rec(k, x.children[i])
         }
     }
 
+    /// Walks the whole tree, making sure that every node and entry is inside of the tree
+    /// file, that the keys in every node are in order, and that all of the leaves are at the
+    /// same depth. Returns the number of keys in the tree.
+    pub fn verify(&mut self) -> Result<usize, Error> {
+        let tree_len;
+        check!(self.treefile.seek(SeekFrom::End(0)), tree_len);
+        let root = self.root_location;
+        let mut leaf_depth = None;
+        self.verify_rec(root, 0, tree_len, &mut leaf_depth, false).map_err(From::from)
+    }
+
+    /// Does everything verify does, and also reads every value, so that every node, entry, key
+    /// and value in the tree has its checksum checked. The first corrupt record is returned as
+    /// Error::Corrupt.
+    pub fn scrub(&mut self) -> Result<usize, Error> {
+        let tree_len;
+        check!(self.treefile.seek(SeekFrom::End(0)), tree_len);
+        let root = self.root_location;
+        let mut leaf_depth = None;
+        self.verify_rec(root, 0, tree_len, &mut leaf_depth, true).map_err(From::from)
+    }
+
+    fn verify_rec(&mut self, pos: u64, depth: usize, tree_len: u64, leaf_depth: &mut Option<usize>, values: bool) -> Result<usize, io::Error> {
+        if pos < TREE_HEADER_LEN || pos.checked_add(NODE_LEN).map_or(true, |end| end > tree_len) {
+            return Err(corruption("node", pos, "outside of the tree file".to_string()))
+        }
+        // A tree this deep would need more nodes than there are atoms, so there's a loop
+        if depth > MAX_DEPTH {
+            return Err(corruption("node", pos, "the tree never ends".to_string()))
+        }
+        let x;
+        check!(self.read_node(pos), x);
+        if x.len > NUM_ENTRIES as u64 {
+            return Err(corruption("node", pos, format!("claims to have {} entries", x.len)))
+        }
+
+        let mut count = x.len as usize;
+        let mut last: Option<K::DeserializeOutput> = None;
+        for i in 0..x.len as usize {
+            if x.entries[i] < TREE_HEADER_LEN || x.entries[i].checked_add(ENTRY_LEN).map_or(true, |end| end > tree_len) {
+                return Err(corruption("node", pos, format!("has an entry outside of the tree file ({})", x.entries[i])))
+            }
+            let k_i;
+            check!(self.read_key(x.entries[i]), k_i);
+            if values {
+                check!(self.read_value(x.entries[i]));
+            }
+            if let Some(ref k_prev) = last {
+                if !(k_prev < &k_i) {
+                    return Err(corruption("node", pos, "keys are out of order".to_string()))
+                }
+            }
+            last = Some(k_i);
+        }
+
+        if x.leaf {
+            match *leaf_depth {
+                None => *leaf_depth = Some(depth),
+                Some(d) if d != depth => {
+                    return Err(corruption("leaf", pos, format!("is at depth {}, but others are at depth {}", depth, d)))
+                },
+                _ => {}
+            }
+        } else {
+            for i in 0..x.len as usize + 1 {
+                let sub;
+                check!(self.verify_rec(x.children[i], depth + 1, tree_len, leaf_depth, values), sub);
+                count += sub;
+            }
+        }
+        Ok(count)
+    }
+
     fn write_entry(&mut self, k: &K::DeserializeOutput, v: &V::DeserializeOutput) -> Result<u64, io::Error> {
         let key_pos;
         let val_pos;
@@ -387,7 +640,7 @@ impl<K, V> PTree<K, V> where K: Serialize + Eq + PartialOrd + PartialEq,
         check!(self.treefile.seek(SeekFrom::End(0)));
         let pos;
         check!(self.treefile.current_pos(), pos);
-        check!(entry.serialize(&mut self.treefile));
+        check!(write_checked(&entry, &mut self.treefile));
         Ok(pos)
     }
 
@@ -395,15 +648,17 @@ impl<K, V> PTree<K, V> where K: Serialize + Eq + PartialOrd + PartialEq,
         check!(self.keyfile.seek(SeekFrom::End(0)));
         let pos;
         check!(self.keyfile.current_pos(), pos);
-        check!(k.serialize(&mut self.keyfile));
+        check!(write_checked(k, &mut self.keyfile));
         Ok(pos)
     }
 
     fn write_val(&mut self, v: &V::DeserializeOutput) -> Result<u64, io::Error> {
+        let packed;
+        check!(Packed::new(v, self.compression), packed);
         check!(self.valfile.seek(SeekFrom::End(0)));
         let pos;
         check!(self.valfile.current_pos(), pos);
-        check!(v.serialize(&mut self.valfile));
+        check!(write_checked(&packed, &mut self.valfile));
         Ok(pos)
     }
 
@@ -412,19 +667,14 @@ impl<K, V> PTree<K, V> where K: Serialize + Eq + PartialOrd + PartialEq,
         check!(self.treefile.seek(SeekFrom::End(0)));
         let pos;
         check!(self.treefile.current_pos(), pos);
-        check!(node.serialize(&mut self.treefile));
+        check!(write_checked(node, &mut self.treefile));
         Ok(pos)
     }
 
-    fn update_node(&mut self, node: &Node, pos: u64) -> Result<(), io::Error> {
-        check!(self.treefile.seek(SeekFrom::Start(pos)));
-        check!(node.serialize(&mut self.treefile));
-        Ok(())
-    }
-
     fn read_entry(&mut self, pos: u64) -> Result<Entry, io::Error> {
-        check!(self.treefile.seek(SeekFrom::Start(pos)));
-        Entry::deserialize(&mut self.treefile)
+        let bytes;
+        check!(bytes_at(&mut self.treefile, pos, ENTRY_LEN as usize), bytes);
+        Entry::from_bytes(&bytes, pos)
     }
 
     fn root(&mut self) -> Result<Node, io::Error> {
@@ -433,19 +683,64 @@ impl<K, V> PTree<K, V> where K: Serialize + Eq + PartialOrd + PartialEq,
     }
 
     fn read_node(&mut self, pos: u64) -> Result<Node, io::Error> {
-        check!(self.treefile.seek(SeekFrom::Start(pos)));
-        Node::deserialize(&mut self.treefile)
+        let bytes;
+        check!(bytes_at(&mut self.treefile, pos, NODE_LEN as usize), bytes);
+        Node::from_bytes(&bytes, pos)
     }
     fn read_value(&mut self, pos: u64) -> Result<V::DeserializeOutput, io::Error> {
         let entry;
         check!(self.read_entry(pos), entry);
-        check!(self.valfile.seek(SeekFrom::Start(entry.value)));
-        V::deserialize(&mut self.valfile)
+        let mut from;
+        check!(read_at(&mut self.valfile, entry.value), from);
+        let packed;
+        check!(read_checked::<Packed<V>>(&mut from, "value", entry.value), packed);
+        packed.unpack()
     }
     fn read_key(&mut self, pos: u64) -> Result<K::DeserializeOutput, io::Error> {
         let entry;
         check!(self.read_entry(pos), entry);
-        check!(self.keyfile.seek(SeekFrom::Start(entry.key)));
-        K::deserialize(&mut self.keyfile)
+        let mut from;
+        check!(read_at(&mut self.keyfile, entry.key), from);
+        read_checked::<K>(&mut from, "key", entry.key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PTree;
+    use storage::MemFile;
+    use compress::Compression;
+    use error::Error;
+
+    fn tree() -> PTree<String, String, MemFile> {
+        let mut tree = PTree::<String, String, MemFile>::create(MemFile::new(), MemFile::new(), MemFile::new(), Compression::Deflate).unwrap();
+        for i in 0..4 {
+            tree.insert(&format!("key {}", i), &format!("value {}", i)).unwrap();
+        }
+        assert_eq!(tree.scrub().unwrap(), 4);
+        tree
+    }
+
+    #[test]
+    fn test_root_near_end_of_address_space() {
+        let mut tree = tree();
+        // A pointer this close to u64::MAX overflows when the node length is added to it
+        tree.root_location = u64::max_value() - 2;
+        match tree.scrub() {
+            Err(Error::Corrupt(_)) => {},
+            other => panic!("expected a corrupt error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_entry_near_end_of_address_space() {
+        let mut tree = tree();
+        let mut root = tree.root().unwrap();
+        root.entries[0] = u64::max_value() - 2;
+        tree.root_location = tree.write_node(&root).unwrap();
+        match tree.verify() {
+            Err(Error::Corrupt(_)) => {},
+            other => panic!("expected a corrupt error, got {:?}", other)
+        }
     }
 }
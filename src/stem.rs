@@ -0,0 +1,302 @@
+/*
+                            ********************************
+                            *           STEMMING           *
+                            ********************************
+
+    "vote", "votes", "voted" and "voting" are all the same word as far as what someone tweets
+    about goes, so the stem stage (see tokenize.rs) cuts words down to their stems before they
+    are counted. This is the Porter stemmer, as Martin Porter described it in "An algorithm
+    for suffix stripping" (1980): five steps, each of which takes a suffix off (or swaps it
+    for a shorter one) if what would be left is long enough.
+
+    How long a stem is, is measured in m, the number of times it goes from vowels to
+    consonants: a word is [C](VC)^m[V], where C is a run of consonants and V a run of vowels.
+    So "tree" is m = 0, "trouble" is m = 1 and "troubles" is m = 2. y is a vowel if it comes
+    after a consonant.
+
+    Stems aren't always words ("happy" -> "happi"), which is why FrequencyTables remember a
+    word each stem came from, to show instead.
+
+    Only words made of the letters a to z are stemmed (lowercase them first), and a 's on the
+    end is taken off first. Anything else is left alone.
+*/
+
+/// The Porter stem of a word.
+pub fn stem(word: &str) -> String {
+    let word = if word.ends_with("'s") || word.ends_with("\u{2019}s") {
+        &word[..word.rfind(|c: char| c == '\'' || c == '\u{2019}').unwrap()]
+    } else {
+        word
+    };
+    if word.len() <= 2 || !word.bytes().all(|b| b'a' <= b && b <= b'z') {
+        return word.to_string()
+    }
+    let mut s = Stemmer { b: word.as_bytes().to_vec(), j: 0 };
+    s.step1ab();
+    if s.k() > 0 {
+        s.step1c();
+        s.step2();
+        s.step3();
+        s.step4();
+        s.step5();
+    }
+    String::from_utf8(s.b).unwrap()
+}
+
+/// The word being stemmed, and j, which points at the last letter before the suffix that
+/// `ends` last matched (so it can be -1).
+struct Stemmer {
+    b: Vec<u8>,
+    j: isize
+}
+
+impl Stemmer {
+    /// The index of the last letter.
+    fn k(&self) -> isize {
+        self.b.len() as isize - 1
+    }
+
+    fn cons(&self, i: isize) -> bool {
+        match self.b[i as usize] {
+            b'a' | b'e' | b'i' | b'o' | b'u' => false,
+            b'y' => i == 0 || !self.cons(i - 1),
+            _ => true
+        }
+    }
+
+    /// m (see the top of this file) of the letters up to and including j.
+    fn m(&self) -> usize {
+        let mut n = 0;
+        let mut i = 0;
+        // Skip the first consonants
+        while i <= self.j && self.cons(i) {
+            i += 1;
+        }
+        loop {
+            while i <= self.j && !self.cons(i) {
+                i += 1;
+            }
+            if i > self.j {
+                return n
+            }
+            while i <= self.j && self.cons(i) {
+                i += 1;
+            }
+            n += 1;
+        }
+    }
+
+    /// Whether there's a vowel up to and including j.
+    fn vowel_in_stem(&self) -> bool {
+        (0..self.j + 1).any(|i| !self.cons(i))
+    }
+
+    /// Whether i and i - 1 are the same consonant.
+    fn double_c(&self, i: isize) -> bool {
+        i >= 1 && self.b[i as usize] == self.b[i as usize - 1] && self.cons(i)
+    }
+
+    /// Whether i - 2, i - 1, i are consonant, vowel, consonant, and the last one isn't w, x or
+    /// y. That's what the end of a short word like "hop" looks like, that needs its e back
+    /// ("hoping" -> "hop" -> "hope").
+    fn cvc(&self, i: isize) -> bool {
+        if i < 2 || !self.cons(i) || self.cons(i - 1) || !self.cons(i - 2) {
+            return false
+        }
+        match self.b[i as usize] {
+            b'w' | b'x' | b'y' => false,
+            _ => true
+        }
+    }
+
+    /// Whether the word ends with s, setting j to the letter before it if it does.
+    fn ends(&mut self, s: &str) -> bool {
+        if !self.b.ends_with(s.as_bytes()) {
+            return false
+        }
+        self.j = self.k() - s.len() as isize;
+        true
+    }
+
+    /// Replaces everything after j with s.
+    fn set_to(&mut self, s: &str) {
+        self.b.truncate((self.j + 1) as usize);
+        self.b.extend_from_slice(s.as_bytes());
+    }
+
+    /// set_to, if m > 0.
+    fn r(&mut self, s: &str) {
+        if self.m() > 0 {
+            self.set_to(s);
+        }
+    }
+
+    /// Plurals and -ed or -ing: caresses -> caress, ponies -> poni, cats -> cat, agreed ->
+    /// agree, motoring -> motor, hopping -> hop, filing -> file.
+    fn step1ab(&mut self) {
+        if self.b[self.k() as usize] == b's' {
+            if self.ends("sses") {
+                self.b.truncate(self.b.len() - 2);
+            } else if self.ends("ies") {
+                self.set_to("i");
+            } else if self.b[self.k() as usize - 1] != b's' {
+                self.b.pop();
+            }
+        }
+        if self.ends("eed") {
+            if self.m() > 0 {
+                self.b.pop();
+            }
+        } else if (self.ends("ed") || self.ends("ing")) && self.vowel_in_stem() {
+            let j = self.j;
+            self.b.truncate((j + 1) as usize);
+            if self.ends("at") {
+                self.set_to("ate");
+            } else if self.ends("bl") {
+                self.set_to("ble");
+            } else if self.ends("iz") {
+                self.set_to("ize");
+            } else if self.double_c(self.k()) {
+                match self.b[self.k() as usize] {
+                    b'l' | b's' | b'z' => {},
+                    _ => { self.b.pop(); }
+                }
+            } else {
+                self.j = self.k();
+                if self.m() == 1 && self.cvc(self.k()) {
+                    self.b.push(b'e');
+                }
+            }
+        }
+    }
+
+    /// A y after a vowel becomes i: happy -> happi.
+    fn step1c(&mut self) {
+        if self.ends("y") && self.vowel_in_stem() {
+            let k = self.k() as usize;
+            self.b[k] = b'i';
+        }
+    }
+
+    /// Double suffixes become single ones: relational -> relate, hopefulness -> hopeful.
+    fn step2(&mut self) {
+        const SUFFIXES: [(&'static str, &'static str); 21] = [
+            ("ational", "ate"), ("tional", "tion"), ("enci", "ence"), ("anci", "ance"),
+            ("izer", "ize"), ("bli", "ble"), ("alli", "al"), ("entli", "ent"), ("eli", "e"),
+            ("ousli", "ous"), ("ization", "ize"), ("ation", "ate"), ("ator", "ate"),
+            ("alism", "al"), ("iveness", "ive"), ("fulness", "ful"), ("ousness", "ous"),
+            ("aliti", "al"), ("iviti", "ive"), ("biliti", "ble"), ("logi", "log")
+        ];
+        self.replace_suffix(&SUFFIXES);
+    }
+
+    /// -ic-, -full, -ness and so on: triplicate -> triplic, hopeful -> hope.
+    fn step3(&mut self) {
+        const SUFFIXES: [(&'static str, &'static str); 7] = [
+            ("icate", "ic"), ("ative", ""), ("alize", "al"), ("iciti", "ic"), ("ical", "ic"),
+            ("ful", ""), ("ness", "")
+        ];
+        self.replace_suffix(&SUFFIXES);
+    }
+
+    /// Replaces the first of suffixes the word ends with (if any), if m > 0.
+    fn replace_suffix(&mut self, suffixes: &[(&str, &str)]) {
+        for &(suffix, with) in suffixes.iter() {
+            if self.ends(suffix) {
+                self.r(with);
+                return
+            }
+        }
+    }
+
+    /// Takes off -ant, -ence and friends if m > 1: revival -> reviv, adjustment -> adjust.
+    fn step4(&mut self) {
+        const SUFFIXES: [&'static str; 19] = [
+            "al", "ance", "ence", "er", "ic", "able", "ible", "ant", "ement", "ment", "ent",
+            "ion", "ou", "ism", "ate", "iti", "ous", "ive", "ize"
+        ];
+        for &suffix in SUFFIXES.iter() {
+            if self.ends(suffix) {
+                // -ion only comes off after an s or a t
+                if suffix == "ion" && (self.j < 0 || (self.b[self.j as usize] != b's' && self.b[self.j as usize] != b't')) {
+                    return
+                }
+                if self.m() > 1 {
+                    let j = self.j;
+                    self.b.truncate((j + 1) as usize);
+                }
+                return
+            }
+        }
+    }
+
+    /// Takes off a final -e, and -ll becomes -l, on long enough words: probate -> probat,
+    /// controll -> control.
+    fn step5(&mut self) {
+        // Both of these go by m of the whole word, e and all
+        self.j = self.k();
+        let m = self.m();
+        if self.b[self.k() as usize] == b'e' && (m > 1 || (m == 1 && !self.cvc(self.k() - 1))) {
+            self.b.pop();
+        }
+        if self.b[self.k() as usize] == b'l' && self.double_c(self.k()) && m > 1 {
+            self.b.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::stem;
+
+    /// Words from the vocabulary Martin Porter publishes next to his stemmer, with the stems
+    /// it gives for them.
+    const VOCABULARY: &'static [(&'static str, &'static str)] = &[
+        ("a", "a"), ("abandoned", "abandon"), ("abase", "abas"), ("abated", "abat"), ("abatement", "abat"),
+        ("abbey", "abbei"), ("abhorred", "abhor"), ("abide", "abid"), ("abilities", "abil"), ("ability", "abil"),
+        ("able", "abl"), ("above", "abov"), ("absence", "absenc"), ("absolutely", "absolut"), ("abundance", "abund"),
+        ("abundantly", "abundantli"), ("academy", "academi"), ("acceptable", "accept"), ("accident", "accid"),
+        ("accompanied", "accompani"), ("according", "accord"), ("accordingly", "accordingli"), ("accurate", "accur"),
+        ("accustomed", "accustom"), ("achievement", "achiev"), ("acknowledge", "acknowledg"),
+        ("acquaintance", "acquaint"), ("across", "across"), ("activity", "activ"), ("actually", "actual"),
+        ("added", "ad"), ("caresses", "caress"), ("ponies", "poni"), ("ties", "ti"), ("cats", "cat"),
+        ("feed", "feed"), ("agreed", "agre"), ("plastered", "plaster"), ("bled", "bled"), ("motoring", "motor"),
+        ("sing", "sing"), ("conflated", "conflat"), ("troubled", "troubl"), ("sized", "size"), ("hopping", "hop"),
+        ("tanned", "tan"), ("falling", "fall"), ("hissing", "hiss"), ("fizzed", "fizz"), ("failing", "fail"),
+        ("filing", "file"), ("happy", "happi"), ("sky", "sky"), ("relational", "relat"), ("conditional", "condit"),
+        ("rational", "ration"), ("digitizer", "digit"), ("vietnamization", "vietnam"), ("predication", "predic"),
+        ("operator", "oper"), ("feudalism", "feudal"), ("decisiveness", "decis"), ("hopefulness", "hope"),
+        ("callousness", "callous"), ("sensitivity", "sensit"), ("triplicate", "triplic"), ("formative", "form"),
+        ("formalize", "formal"), ("electrical", "electr"), ("goodness", "good"), ("revival", "reviv"),
+        ("allowance", "allow"), ("inference", "infer"), ("airliner", "airlin"), ("gyroscopic", "gyroscop"),
+        ("adjustable", "adjust"), ("defensible", "defens"), ("irritant", "irrit"), ("replacement", "replac"),
+        ("adjustment", "adjust"), ("dependent", "depend"), ("adoption", "adopt"), ("communism", "commun"),
+        ("activate", "activ"), ("homologous", "homolog"), ("effective", "effect"), ("bowdlerize", "bowdler"),
+        ("probate", "probat"), ("rate", "rate"), ("cease", "ceas"), ("controlling", "control"), ("roll", "roll"),
+        ("generalizations", "gener"), ("oscillators", "oscil"), ("connections", "connect"), ("connecting", "connect")
+    ];
+
+    #[test]
+    fn test_vocabulary() {
+        let wrong = VOCABULARY.iter()
+            .map(|&(word, expected)| (word, expected, stem(word)))
+            .filter(|&(_, expected, ref actual)| expected != actual)
+            .collect::<Vec<_>>();
+        assert!(wrong.is_empty(), "(word, expected, actual): {:?}", wrong);
+    }
+
+    #[test]
+    fn test_left_alone() {
+        assert_eq!(stem("is"), "is");
+        assert_eq!(stem("Voting"), "Voting");
+        assert_eq!(stem("café"), "café");
+        assert_eq!(stem("covid19"), "covid19");
+        assert_eq!(stem(""), "");
+    }
+
+    #[test]
+    fn test_possessive() {
+        assert_eq!(stem("congress's"), stem("congress"));
+        assert_eq!(stem("nation’s"), "nation");
+    }
+}
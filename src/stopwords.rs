@@ -0,0 +1,126 @@
+use std::fs::File;
+use std::io::Read;
+
+/*
+                            ********************************
+                            *          STOPWORDS           *
+                            ********************************
+
+    Words like "the", "to" and "of" are in everyone's tweets, so they say nothing about what
+    anyone is tweeting about. They can be thrown away while tweets are tokenized (the
+    stopwords and auto-stopwords stages in tokenize.rs), or taken out of tables that were
+    loaded without doing that (the --stopwords and --auto-stopwords options of display).
+
+    Stopwords are given as a list of things separated by +, each of which is one of:
+
+        english     -> The built in list of common English words below.
+        twitter     -> The built in list of words that are only common because it's twitter
+                       (rt, via, amp, ...).
+        file:path   -> Every word in a file, separated by whitespace. Lines starting with #
+                       are comments.
+        anything else
+                    -> That word.
+
+    So "english+twitter+file:mine.txt+congress" is all of those. The automatic mode doesn't
+    need a list: it takes the N words that are in the most users' tables, whatever they are.
+*/
+
+/// Common English words, mostly function words.
+pub const ENGLISH: &'static [&'static str] = &[
+    "a", "about", "above", "after", "again", "against", "all", "am", "an", "and", "any", "are",
+    "aren't", "as", "at", "be", "because", "been", "before", "being", "below", "between", "both",
+    "but", "by", "can", "can't", "could", "couldn't", "did", "didn't", "do", "does", "doesn't",
+    "doing", "don't", "down", "during", "each", "few", "for", "from", "further", "had", "hadn't",
+    "has", "hasn't", "have", "haven't", "having", "he", "he'd", "he'll", "he's", "her", "here",
+    "here's", "hers", "herself", "him", "himself", "his", "how", "how's", "i", "i'd", "i'll",
+    "i'm", "i've", "if", "in", "into", "is", "isn't", "it", "it's", "its", "itself", "just",
+    "let's", "me", "more", "most", "mustn't", "my", "myself", "no", "nor", "not", "now", "of",
+    "off", "on", "once", "only", "or", "other", "ought", "our", "ours", "ourselves", "out",
+    "over", "own", "same", "shan't", "she", "she'd", "she'll", "she's", "should", "shouldn't",
+    "so", "some", "such", "than", "that", "that's", "the", "their", "theirs", "them",
+    "themselves", "then", "there", "there's", "these", "they", "they'd", "they'll", "they're",
+    "they've", "this", "those", "through", "to", "too", "under", "until", "up", "very", "was",
+    "wasn't", "we", "we'd", "we'll", "we're", "we've", "were", "weren't", "what", "what's",
+    "when", "when's", "where", "where's", "which", "while", "who", "who's", "whom", "why",
+    "why's", "will", "with", "won't", "would", "wouldn't", "you", "you'd", "you'll", "you're",
+    "you've", "your", "yours", "yourself", "yourselves"
+];
+
+/// Words that are common on twitter no matter what anyone is talking about.
+pub const TWITTER: &'static [&'static str] = &[
+    "rt", "via", "amp", "http", "https", "co", "t", "s", "u", "ur", "im", "dont", "cant",
+    "thx", "pls", "today", "new", "get", "one", "w"
+];
+
+/// Every word a stopword list (see the top of this file) stands for, lowercased.
+pub fn parse(spec: &str) -> Result<Vec<String>, String> {
+    let mut words = vec![];
+    for part in spec.split('+').filter(|p| !p.is_empty()) {
+        match part {
+            "english" => words.extend(ENGLISH.iter().map(|w| w.to_string())),
+            "twitter" => words.extend(TWITTER.iter().map(|w| w.to_string())),
+            _ if part.starts_with("file:") => match read_file(&part[5..]) {
+                Ok(mut x) => words.append(&mut x),
+                Err(e) => return Err(e)
+            },
+            _ => words.push(part.to_string())
+        }
+    }
+    Ok(words.into_iter().map(|w| w.to_lowercase()).collect())
+}
+
+/// The words in a stopword file.
+fn read_file(path: &str) -> Result<Vec<String>, String> {
+    let mut text = String::new();
+    if let Err(e) = File::open(path).and_then(|mut f| f.read_to_string(&mut text)) {
+        return Err(format!("failed to read stopwords from {}: {}", path, e))
+    }
+    Ok(text.lines()
+        .filter(|line| !line.trim_left().starts_with('#'))
+        .flat_map(|line| line.split_whitespace())
+        .map(|w| w.to_string())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{ self, File };
+    use std::io::Write;
+    use super::{ parse, ENGLISH, TWITTER };
+
+    #[test]
+    fn test_words() {
+        assert_eq!(parse("Congress+house").unwrap(), vec!["congress", "house"]);
+        assert_eq!(parse("++senate+").unwrap(), vec!["senate"]);
+        assert!(parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_built_in_lists() {
+        let words = parse("english+twitter+bill").unwrap();
+        assert_eq!(words.len(), ENGLISH.len() + TWITTER.len() + 1);
+        assert!(words.iter().any(|w| w == "the"));
+        assert!(words.iter().any(|w| w == "rt"));
+        assert_eq!(words.last().unwrap(), "bill");
+    }
+
+    #[test]
+    fn test_file() {
+        let path = env::temp_dir().join("stopwords_test.txt");
+        {
+            let mut f = File::create(&path).unwrap();
+            f.write_all(b"# Words from\n  # the house\nVote  bill\n\nfloor\tCommittee\n").unwrap();
+        }
+        let spec = format!("file:{}+senate", path.to_str().unwrap());
+        let words = parse(&spec);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(words.unwrap(), vec!["vote", "bill", "floor", "committee", "senate"]);
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let e = parse("english+file:/this/file/is/not/there").unwrap_err();
+        assert!(e.contains("/this/file/is/not/there"), "{}", e);
+    }
+}
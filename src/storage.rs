@@ -0,0 +1,429 @@
+use std::io::{ Seek, SeekFrom, Read, Write };
+use std::io;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::borrow::Cow;
+use std::fs::{ File, OpenOptions };
+use std::fs;
+use error::{ Error, Context };
+use codec::Serialize;
+use checksum::{ read_checked, write_checked };
+use cfile_rs::CFile;
+use memmap::{ Mmap, Protection };
+
+/// Anything PHash and PTree can keep their files in. CFile is what gets used for real
+/// data; the other backends in here exist so the storage code can be poked at without
+/// touching the disk.
+pub trait Storage: Read + Write + Seek {
+    /// Returns the current position of the cursor, in bytes from the start.
+    fn current_pos(&mut self) -> Result<u64, io::Error>;
+
+    /// If the whole backend is sitting in memory, returns all of it so it can be read
+    /// without any seeking or copying.
+    fn mapped(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+impl Storage for CFile {
+    fn current_pos(&mut self) -> Result<u64, io::Error> {
+        CFile::current_pos(self)
+    }
+}
+
+/// Creates an io::Error for data that doesn't make sense (pointers off the end of a file,
+/// lists that never end, etc.)
+pub fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A reader that starts at some position in a backend. If the backend is mapped it reads
+/// straight out of memory, otherwise it just reads from the backend.
+pub enum At<'a, S> where S: Storage + 'a {
+    Mapped(&'a [u8]),
+    Stream(&'a mut S)
+}
+
+impl<'a, S> Read for At<'a, S> where S: Storage + 'a {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            At::Mapped(ref mut bytes) => bytes.read(buf),
+            At::Stream(ref mut s) => s.read(buf)
+        }
+    }
+}
+
+/// Returns a reader that starts `pos` bytes into the backend.
+pub fn read_at<'a, S: Storage>(s: &'a mut S, pos: u64) -> Result<At<'a, S>, io::Error> {
+    let mapped_len = s.mapped().map(|bytes| bytes.len() as u64);
+    match mapped_len {
+        Some(len) => {
+            if pos > len {
+                return Err(invalid_data(format!("tried to read at {}, past the end of the file ({})", pos, len)))
+            }
+            Ok(At::Mapped(&s.mapped().unwrap()[pos as usize..]))
+        },
+        None => {
+            if let Err(e) = s.seek(SeekFrom::Start(pos)) {
+                return Err(e)
+            }
+            Ok(At::Stream(s))
+        }
+    }
+}
+
+/// Returns the `len` bytes starting at `pos`. If the backend is mapped they are borrowed
+/// right out of the map, otherwise they are read in one go.
+pub fn bytes_at<'a, S: Storage>(s: &'a mut S, pos: u64, len: usize) -> Result<Cow<'a, [u8]>, io::Error> {
+    let mapped_len = s.mapped().map(|bytes| bytes.len() as u64);
+    match mapped_len {
+        Some(file_len) => {
+            // A corrupt pointer can be anything, so pos + len could overflow
+            let end = match pos.checked_add(len as u64) {
+                Some(end) => end,
+                None => return Err(invalid_data(format!("tried to read {} bytes at {}, past the end of any file", len, pos)))
+            };
+            if end > file_len {
+                return Err(invalid_data(format!("tried to read {} bytes at {}, past the end of the file ({})", len, pos, file_len)))
+            }
+            Ok(Cow::Borrowed(&s.mapped().unwrap()[pos as usize..pos as usize + len]))
+        },
+        None => {
+            let mut buf = vec![0u8; len];
+            if let Err(e) = s.seek(SeekFrom::Start(pos)) {
+                return Err(e)
+            }
+            if let Err(e) = s.read_exact(&mut buf) {
+                return Err(e)
+            }
+            Ok(Cow::Owned(buf))
+        }
+    }
+}
+
+/// Keeps other processes from opening a store for writing at the same time. While one of these
+/// is alive there is a `<path>.lock` file, and trying to take the lock again fails with
+/// Error::Locked. If the program dies without cleaning up, the lock file has to be deleted by hand.
+pub struct LockFile {
+    path: String
+}
+
+impl LockFile {
+    pub fn acquire(path: &str) -> Result<LockFile, Error> {
+        let lock_path = path.to_string() + ".lock";
+        match OpenOptions::new().write(true).create_new(true).open(&lock_path) {
+            Ok(_) => Ok(LockFile { path: lock_path }),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                Err(Error::Locked(format!("{} is already open somewhere else (if it isn't, delete {})", path, lock_path)))
+            },
+            Err(e) => Err(Error::from(e).context(&format!("creating {}", lock_path)))
+        }
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Reads a file written by save_file. `what` is what the file is called in corruption errors.
+pub fn open_file<T: Serialize>(path: &str, what: &str) -> Result<T::DeserializeOutput, Error> {
+    let mut file = match File::open(path).context(&format!("opening {}", path)) {
+        Ok(f) => f,
+        Err(e) => return Err(e)
+    };
+    read_checked::<T>(&mut file, what, 0).context(&format!("reading {}", path))
+}
+
+/// Writes x, followed by its checksum, to `<path>.tmp` and then renames that over path, so a
+/// crash leaves either the old file or the new one, never half of one.
+pub fn save_file<T: Serialize>(x: &T, path: &str) -> Result<(), Error> {
+    let _lock = match LockFile::acquire(path) {
+        Ok(l) => l,
+        Err(e) => return Err(e)
+    };
+    let tmp = path.to_string() + ".tmp";
+    let mut file = match OpenOptions::new().write(true).create(true).truncate(true).open(&tmp)
+            .context(&format!("creating {}", tmp)) {
+        Ok(f) => f,
+        Err(e) => return Err(e)
+    };
+    if let Err(e) = write_checked(x, &mut file).and_then(|_| file.sync_all()) {
+        return Err(Error::from(e).context(&format!("writing {}", tmp)))
+    }
+    fs::rename(&tmp, path).context(&format!("replacing {}", path))
+}
+
+/// A read only, memory mapped file. Reads never have to go through a system call, and the
+/// bytes can be parsed in place via Storage::mapped.
+pub struct MmapFile {
+    /// Empty files can't be mapped, so there isn't always a map.
+    map: Option<Mmap>,
+    pos: u64
+}
+
+impl MmapFile {
+    pub fn open(path: &str) -> Result<MmapFile, io::Error> {
+        let len = match File::open(path).and_then(|f| f.metadata()) {
+            Ok(m) => m.len(),
+            Err(e) => return Err(e)
+        };
+        let map = if len == 0 {
+            None
+        } else {
+            match Mmap::open_path(path, Protection::Read) {
+                Ok(m) => Some(m),
+                Err(e) => return Err(e)
+            }
+        };
+        Ok(MmapFile { map: map, pos: 0 })
+    }
+
+    fn slice(&self) -> &[u8] {
+        match self.map {
+            // The map is read only and never handed out mutably, so this is fine as long as
+            // nobody truncates the file out from under us.
+            Some(ref m) => unsafe { m.as_slice() },
+            None => &[]
+        }
+    }
+}
+
+impl Read for MmapFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let bytes = self.slice();
+            if self.pos >= bytes.len() as u64 {
+                return Ok(0)
+            }
+            let start = self.pos as usize;
+            let n = if buf.len() < bytes.len() - start { buf.len() } else { bytes.len() - start };
+            buf[..n].copy_from_slice(&bytes[start..start + n]);
+            n
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for MmapFile {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "memory mapped files are read only"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MmapFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(x) => x as i64,
+            SeekFrom::End(x) => self.slice().len() as i64 + x,
+            SeekFrom::Current(x) => self.pos as i64 + x
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position"))
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl Storage for MmapFile {
+    fn current_pos(&mut self) -> Result<u64, io::Error> {
+        Ok(self.pos)
+    }
+
+    fn mapped(&self) -> Option<&[u8]> {
+        Some(self.slice())
+    }
+}
+
+/// A file that lives entirely in memory. Every handle made with reopen shares the same
+/// bytes, so a store can be dropped and opened again over the same "disk".
+pub struct MemFile {
+    data: Rc<RefCell<Vec<u8>>>,
+    pos: u64
+}
+
+impl MemFile {
+    pub fn new() -> MemFile {
+        MemFile {
+            data: Rc::new(RefCell::new(Vec::new())),
+            pos: 0
+        }
+    }
+
+    /// A new handle to the same bytes, with its cursor at the start.
+    pub fn reopen(&self) -> MemFile {
+        MemFile {
+            data: self.data.clone(),
+            pos: 0
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.data.borrow().len() as u64
+    }
+}
+
+impl Read for MemFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.data.borrow();
+        if self.pos >= data.len() as u64 {
+            return Ok(0)
+        }
+        let start = self.pos as usize;
+        let n = if buf.len() < data.len() - start { buf.len() } else { data.len() - start };
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut data = self.data.borrow_mut();
+        let start = self.pos as usize;
+        if data.len() < start + buf.len() {
+            data.resize(start + buf.len(), 0);
+        }
+        data[start..start + buf.len()].copy_from_slice(buf);
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(x) => x as i64,
+            SeekFrom::End(x) => self.len() as i64 + x,
+            SeekFrom::Current(x) => self.pos as i64 + x
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position"))
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl Storage for MemFile {
+    fn current_pos(&mut self) -> Result<u64, io::Error> {
+        Ok(self.pos)
+    }
+}
+
+/// What happens to the write that a crash lands on.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Fault {
+    /// The write never makes it to the disk.
+    Drop,
+    /// Only the first half of the write makes it to the disk.
+    Tear
+}
+
+struct CrashState {
+    at: u64,
+    writes: u64,
+    crashed: bool,
+    fault: Fault
+}
+
+/// Decides when a group of CrashFiles "crash". The count is shared between every file it
+/// wraps, so a crash at write N means the Nth write to any of them.
+#[derive(Clone)]
+pub struct CrashSwitch(Rc<RefCell<CrashState>>);
+
+impl CrashSwitch {
+    /// Crash on write number `at` (counting from zero), with the given fault.
+    pub fn new(at: u64, fault: Fault) -> CrashSwitch {
+        CrashSwitch(Rc::new(RefCell::new(CrashState {
+            at: at,
+            writes: 0,
+            crashed: false,
+            fault: fault
+        })))
+    }
+
+    /// A switch that never trips. Useful for counting how many writes something does.
+    pub fn never() -> CrashSwitch {
+        CrashSwitch::new(u64::max_value(), Fault::Drop)
+    }
+
+    pub fn wrap<S: Storage>(&self, inner: S) -> CrashFile<S> {
+        CrashFile {
+            inner: inner,
+            switch: self.clone()
+        }
+    }
+
+    pub fn crashed(&self) -> bool {
+        self.0.borrow().crashed
+    }
+
+    /// The number of writes that reached the disk before the crash (or so far, if there
+    /// hasn't been one).
+    pub fn writes(&self) -> u64 {
+        self.0.borrow().writes
+    }
+}
+
+/// A backend that behaves like the backend it wraps until its CrashSwitch trips. From then
+/// on every write claims to succeed but never reaches the disk, just like it would if the
+/// process had died.
+pub struct CrashFile<S> where S: Storage {
+    inner: S,
+    switch: CrashSwitch
+}
+
+impl<S> Read for CrashFile<S> where S: Storage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<S> Write for CrashFile<S> where S: Storage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.switch.0.borrow_mut();
+        if state.crashed {
+            return Ok(buf.len())
+        }
+        if state.writes == state.at {
+            state.crashed = true;
+            if state.fault == Fault::Tear {
+                let half = buf.len() / 2;
+                if let Err(e) = self.inner.write_all(&buf[..half]) {
+                    return Err(e)
+                }
+            }
+            return Ok(buf.len())
+        }
+        state.writes += 1;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S> Seek for CrashFile<S> where S: Storage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl<S> Storage for CrashFile<S> where S: Storage {
+    fn current_pos(&mut self) -> Result<u64, io::Error> {
+        self.inner.current_pos()
+    }
+}
@@ -0,0 +1,741 @@
+use std::io::{ Read, Write };
+use std::io;
+use std::fmt;
+use std::cmp;
+use std::str::FromStr;
+use codec::{ Serialize, read_varint, write_varint, varint_len };
+use storage::{ invalid_data, open_file, save_file };
+use error::Error;
+use stopwords;
+use stem::stem;
+
+/*
+                            ********************************
+                            *         TOKENIZATION         *
+                            ********************************
+
+    Turning a tweet into the words that get counted is a Pipeline of Stages, run in order.
+    Every stage takes a list of tokens and gives back a new one. The text of the tweet starts
+    out as a single Text token (text that hasn't been split up yet), and the stages are:
+
+        entities    -> Decodes HTML entities (&amp; and friends, and &#39; style ones) in the
+                       text of every token. Twitter escapes some characters even though its
+                       responses are json.
+        extract     -> Pulls URLs, @mentions and #hashtags out of Text tokens, so they don't get
+                       split up with the rest of the text.
+        drop=kind   -> Throws away every token of a kind (text, word, url, mention, hashtag or
+                       emoji).
+        segment     -> Splits Text tokens into words and emoji. A word is a run of letters,
+                       numbers (in any script) and underscores, with apostrophes allowed inside
+                       of it. Every emoji is a token of its own, and everything else is a space.
+        lowercase   -> Lowercases every token but URLs, where case can matter.
+        stopwords=list
+                    -> Throws away the words in a stopword list, like english+twitter (see
+                       stopwords.rs).
+        auto-stopwords=n
+                    -> Throws away the n words that are in the most users' tables. Those can
+                       only be known once every tweet has been counted, so TweetProcessor
+                       counts everything once without them, fills them in, and then counts
+                       everything again. Until then the stage doesn't do anything.
+        min-length=n
+                    -> Throws away words shorter than n characters.
+        stem        -> Cuts words down to their stems with the Porter stemmer (see stem.rs), so
+                       "votes" and "voting" are both "vote". It only stems lowercase words, so
+                       it goes after lowercase. Each token remembers the word it was.
+        ngrams=n    -> Adds a phrase for every run of 2 to n words in a row (so ngrams=3 adds
+                       bigrams and trigrams), with the words separated by spaces: "health care",
+                       "border wall". The words are kept too. Only words next to each other in
+                       what's left of the tweet count, so a stopwords stage before it makes
+                       phrases out of words that had a stopword between them.
+        phrases=n[:pmi]
+                    -> ngrams=n, but only keeping the collocations: phrases that show up a lot
+                       more often than their words would together by chance. That's measured
+                       by PMI (pointwise mutual information), log2(p(phrase) / p(each word)
+                       multiplied together), over every tweet, which again can only be known
+                       once everything has been counted. TweetProcessor counts everything with
+                       every n-gram, picks the phrases with a PMI of at least pmi (3 if it's
+                       left out, so 8 times more often than chance) that show up at least a
+                       few times, and counts everything again.
+
+    A pipeline is written as its stages separated by commas, and the default one is
+
+        entities,extract,segment,lowercase
+
+    which is close to what strip_text used to do, without its regexes (the URL one missed most
+    URLs, and the entity one ate everything between the first & and the last ;). URLs are
+    kept, but only for their domains: which table each kind of token ends up in is up to
+    TweetProcessor (see channel.rs).
+
+    Which words a user's FrequencyTable has depends on the pipeline, so the pipeline is saved
+    with the data (in data/tokenizer) when it's loaded. A Stage is written as a u8 saying which
+    stage it is, followed by whatever it needs:
+
+        0 entities, 1 extract, 3 segment, 4 lowercase
+                    -> Nothing else.
+        2 drop      -> A u8, the kind.
+        5 stopwords -> The words, as a Vec<String>.
+        6 min-length
+                    -> A varint.
+        7 auto-stopwords
+                    -> A varint, n, and then the words that were picked, as a Vec<String>.
+        8 stem      -> Nothing else.
+        9 ngrams    -> A varint, n.
+        10 phrases  -> A varint, n, the bits of pmi as a u64, a bool saying whether the
+                       phrases have been picked, and if they have, the phrases as a
+                       Vec<String>.
+*/
+
+/// The PMI phrases need to have, unless another one is asked for.
+pub const DEFAULT_MIN_PMI: f64 = 3.0;
+
+/// What a token is.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TokenKind {
+    /// Text that hasn't been segmented yet
+    Text,
+    Word,
+    Url,
+    Mention,
+    Hashtag,
+    Emoji,
+    /// A few words in a row, from ngrams or phrases
+    Phrase
+}
+
+const KINDS: [(TokenKind, &'static str); 7] = [(TokenKind::Text, "text"), (TokenKind::Word, "word"),
+    (TokenKind::Url, "url"), (TokenKind::Mention, "mention"), (TokenKind::Hashtag, "hashtag"),
+    (TokenKind::Emoji, "emoji"), (TokenKind::Phrase, "phrase")];
+
+impl TokenKind {
+    fn name(&self) -> &'static str {
+        KINDS.iter().find(|&&(k, _)| k == *self).unwrap().1
+    }
+}
+
+impl FromStr for TokenKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TokenKind, String> {
+        match KINDS.iter().find(|&&(_, name)| name == s) {
+            Some(&(k, _)) => Ok(k),
+            None => Err(format!("{} is not text, word, url, mention, hashtag, emoji or phrase", s))
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Token {
+    pub text: String,
+    pub kind: TokenKind,
+    /// What the word was before it was stemmed, if it was
+    pub surface: Option<String>
+}
+
+impl Token {
+    pub fn new(text: &str, kind: TokenKind) -> Token {
+        Token { text: text.to_string(), kind: kind, surface: None }
+    }
+}
+
+/// Something that splits text into tokens.
+pub trait Tokenizer {
+    fn tokenize(&self, text: &str) -> Vec<Token>;
+}
+
+/// One step of a Pipeline (see the top of this file).
+#[derive(Clone, PartialEq, Debug)]
+pub enum Stage {
+    DecodeEntities,
+    Extract,
+    Drop(TokenKind),
+    Segment,
+    Lowercase,
+    /// Sorted, so they can be binary searched
+    Stopwords(Vec<String>),
+    MinLength(usize),
+    /// How many words to pick, and the (sorted) words once they've been picked
+    AutoStopwords(usize, Vec<String>),
+    Stem,
+    /// The most words in a phrase
+    NGrams(usize),
+    /// The most words in a phrase, the PMI a phrase needs, and the (sorted) phrases once
+    /// they've been picked
+    Phrases(usize, f64, Option<Vec<String>>)
+}
+
+impl Stage {
+    /// Runs the stage over tokens.
+    pub fn apply(&self, tokens: Vec<Token>) -> Vec<Token> {
+        match *self {
+            Stage::DecodeEntities => tokens.into_iter().map(|t| Token { text: decode_entities(&t.text), ..t }).collect(),
+            Stage::Extract => tokens.into_iter().flat_map(|t| {
+                if t.kind == TokenKind::Text { extract(&t.text) } else { vec![t] }
+            }).collect(),
+            Stage::Drop(kind) => tokens.into_iter().filter(|t| t.kind != kind).collect(),
+            Stage::Segment => tokens.into_iter().flat_map(|t| {
+                if t.kind == TokenKind::Text { segment(&t.text) } else { vec![t] }
+            }).collect(),
+            Stage::Lowercase => tokens.into_iter().map(|t| {
+                if t.kind == TokenKind::Url { t } else { Token { text: t.text.to_lowercase(), ..t } }
+            }).collect(),
+            Stage::Stopwords(ref words) | Stage::AutoStopwords(_, ref words) => tokens.into_iter().filter(|t| {
+                t.kind != TokenKind::Word || words.binary_search(&t.text).is_err()
+            }).collect(),
+            Stage::MinLength(n) => tokens.into_iter().filter(|t| {
+                t.kind != TokenKind::Word || t.text.chars().count() >= n
+            }).collect(),
+            Stage::Stem => tokens.into_iter().map(|t| {
+                if t.kind != TokenKind::Word { return t }
+                let stemmed = stem(&t.text);
+                if stemmed == t.text { t } else {
+                    Token { surface: Some(t.surface.unwrap_or(t.text)), text: stemmed, kind: t.kind }
+                }
+            }).collect(),
+            Stage::NGrams(n) => ngrams(tokens, n, None),
+            Stage::Phrases(n, _, ref picked) => ngrams(tokens, n, picked.as_ref().map(|p| &p[..]))
+        }
+    }
+
+    /// Makes a Stopwords stage, sorting the words.
+    pub fn stopwords<I: IntoIterator<Item = String>>(words: I) -> Stage {
+        Stage::Stopwords(sorted(words))
+    }
+
+    fn tag(&self) -> u8 {
+        match *self {
+            Stage::DecodeEntities => 0,
+            Stage::Extract => 1,
+            Stage::Drop(_) => 2,
+            Stage::Segment => 3,
+            Stage::Lowercase => 4,
+            Stage::Stopwords(_) => 5,
+            Stage::MinLength(_) => 6,
+            Stage::AutoStopwords(_, _) => 7,
+            Stage::Stem => 8,
+            Stage::NGrams(_) => 9,
+            Stage::Phrases(_, _, _) => 10
+        }
+    }
+}
+
+impl FromStr for Stage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Stage, String> {
+        let (name, arg) = match s.find('=') {
+            Some(eq) => (&s[..eq], Some(&s[eq + 1..])),
+            None => (s, None)
+        };
+        match (name, arg) {
+            ("entities", None) => Ok(Stage::DecodeEntities),
+            ("extract", None) => Ok(Stage::Extract),
+            ("drop", Some(kind)) => kind.parse().map(Stage::Drop),
+            ("segment", None) => Ok(Stage::Segment),
+            ("lowercase", None) => Ok(Stage::Lowercase),
+            ("stem", None) => Ok(Stage::Stem),
+            ("stopwords", Some(list)) => stopwords::parse(list).map(Stage::stopwords),
+            ("auto-stopwords", Some(n)) => match n.parse() {
+                Ok(n) => Ok(Stage::AutoStopwords(n, vec![])),
+                Err(_) => Err(format!("auto-stopwords needs a number, not {}", n))
+            },
+            ("min-length", Some(n)) => match n.parse() {
+                Ok(n) => Ok(Stage::MinLength(n)),
+                Err(_) => Err(format!("min-length needs a number, not {}", n))
+            },
+            ("ngrams", Some(n)) => match n.parse() {
+                Ok(n) if n >= 2 => Ok(Stage::NGrams(n)),
+                _ => Err(format!("ngrams needs a number from 2 up, not {}", n))
+            },
+            ("phrases", Some(arg)) => {
+                let (n, pmi) = match arg.find(':') {
+                    Some(colon) => (&arg[..colon], Some(&arg[colon + 1..])),
+                    None => (arg, None)
+                };
+                let pmi = match pmi.map(|x| x.parse::<f64>()) {
+                    None => DEFAULT_MIN_PMI,
+                    Some(Ok(x)) => x,
+                    Some(Err(_)) => return Err(format!("phrases needs a number for the pmi, not {}", pmi.unwrap()))
+                };
+                match n.parse() {
+                    Ok(n) if n >= 2 => Ok(Stage::Phrases(n, pmi, None)),
+                    _ => Err(format!("phrases needs a number from 2 up, not {}", n))
+                }
+            },
+            _ => Err(format!("{} is not a tokenizer stage", s))
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    /// The stage like it would be written on the command line, except stopwords, which are
+    /// only counted.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Stage::DecodeEntities => write!(f, "entities"),
+            Stage::Extract => write!(f, "extract"),
+            Stage::Drop(kind) => write!(f, "drop={}", kind.name()),
+            Stage::Segment => write!(f, "segment"),
+            Stage::Lowercase => write!(f, "lowercase"),
+            Stage::Stopwords(ref words) => write!(f, "stopwords({} words)", words.len()),
+            Stage::MinLength(n) => write!(f, "min-length={}", n),
+            Stage::AutoStopwords(n, _) => write!(f, "auto-stopwords={}", n),
+            Stage::Stem => write!(f, "stem"),
+            Stage::NGrams(n) => write!(f, "ngrams={}", n),
+            Stage::Phrases(n, pmi, _) => write!(f, "phrases={}:{}", n, pmi)
+        }
+    }
+}
+
+impl Serialize for Stage {
+    type DeserializeOutput = Stage;
+
+    fn serialize(&self, to: &mut Write) -> Result<(), io::Error> {
+        check!(self.tag().serialize(to));
+        match *self {
+            Stage::Drop(kind) => (KINDS.iter().position(|&(k, _)| k == kind).unwrap() as u8).serialize(to),
+            Stage::Stopwords(ref words) => words.serialize(to),
+            Stage::MinLength(n) => write_varint(n as u64, to),
+            Stage::AutoStopwords(n, ref words) => {
+                check!(write_varint(n as u64, to));
+                words.serialize(to)
+            },
+            Stage::NGrams(n) => write_varint(n as u64, to),
+            Stage::Phrases(n, pmi, ref picked) => {
+                check!(write_varint(n as u64, to));
+                check!(pmi.to_bits().serialize(to));
+                check!(picked.is_some().serialize(to));
+                match *picked {
+                    Some(ref phrases) => phrases.serialize(to),
+                    None => Ok(())
+                }
+            },
+            _ => Ok(())
+        }
+    }
+
+    fn deserialize(from: &mut Read) -> Result<Stage, io::Error> {
+        let tag;
+        check!(u8::deserialize(from), tag);
+        match tag {
+            0 => Ok(Stage::DecodeEntities),
+            1 => Ok(Stage::Extract),
+            2 => {
+                let kind;
+                check!(u8::deserialize(from), kind);
+                match KINDS.get(kind as usize) {
+                    Some(&(k, _)) => Ok(Stage::Drop(k)),
+                    None => Err(invalid_data(format!("{} is not a kind of token", kind)))
+                }
+            },
+            3 => Ok(Stage::Segment),
+            4 => Ok(Stage::Lowercase),
+            5 => {
+                let words;
+                check!(Vec::<String>::deserialize(from), words);
+                Ok(Stage::Stopwords(words))
+            },
+            6 => {
+                let n;
+                check!(read_varint(from), n);
+                Ok(Stage::MinLength(n as usize))
+            },
+            7 => {
+                let (n, words);
+                check!(read_varint(from), n);
+                check!(Vec::<String>::deserialize(from), words);
+                Ok(Stage::AutoStopwords(n as usize, words))
+            },
+            8 => Ok(Stage::Stem),
+            9 => {
+                let n;
+                check!(read_varint(from), n);
+                Ok(Stage::NGrams(n as usize))
+            },
+            10 => {
+                let (n, pmi, is_picked);
+                check!(read_varint(from), n);
+                check!(u64::deserialize(from), pmi);
+                check!(bool::deserialize(from), is_picked);
+                let mut picked = None;
+                if is_picked {
+                    let phrases;
+                    check!(Vec::<String>::deserialize(from), phrases);
+                    picked = Some(phrases);
+                }
+                Ok(Stage::Phrases(n as usize, f64::from_bits(pmi), picked))
+            },
+            x => Err(invalid_data(format!("{} is not a tokenizer stage", x)))
+        }
+    }
+
+    fn serialized_len(&self) -> u64 {
+        1 + match *self {
+            Stage::Drop(_) => 1,
+            Stage::Stopwords(ref words) => words.serialized_len(),
+            Stage::MinLength(n) => varint_len(n as u64),
+            Stage::AutoStopwords(n, ref words) => varint_len(n as u64) + words.serialized_len(),
+            Stage::NGrams(n) => varint_len(n as u64),
+            Stage::Phrases(n, pmi, ref picked) => varint_len(n as u64) + pmi.to_bits().serialized_len() + 1
+                + picked.as_ref().map(|p| p.serialized_len()).unwrap_or(0),
+            _ => 0
+        }
+    }
+}
+
+/// Stages, run one after the other.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Pipeline {
+    pub stages: Vec<Stage>
+}
+
+record!(Pipeline, version 1 { stages: Vec<Stage> });
+
+/// The pipeline that gets used unless another one is asked for.
+pub const DEFAULT_PIPELINE: &'static str = "entities,extract,segment,lowercase";
+
+impl Default for Pipeline {
+    fn default() -> Pipeline {
+        DEFAULT_PIPELINE.parse().unwrap()
+    }
+}
+
+impl Tokenizer for Pipeline {
+    fn tokenize(&self, text: &str) -> Vec<Token> {
+        self.stages.iter().fold(vec![Token::new(text, TokenKind::Text)], |tokens, stage| stage.apply(tokens))
+    }
+}
+
+impl FromStr for Pipeline {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Pipeline, String> {
+        let mut stages = vec![];
+        for stage in s.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            match stage.parse() {
+                Ok(x) => stages.push(x),
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(Pipeline { stages: stages })
+    }
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stages = self.stages.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        write!(f, "{}", stages.join(","))
+    }
+}
+
+impl Pipeline {
+    /// If there's an auto-stopwords stage that hasn't picked its words yet, how many it
+    /// wants.
+    pub fn unpicked_stopwords(&self) -> Option<usize> {
+        self.stages.iter().filter_map(|s| match *s {
+            Stage::AutoStopwords(n, ref words) if n > 0 && words.is_empty() => Some(n),
+            _ => None
+        }).next()
+    }
+
+    /// Gives the auto-stopwords stages their words.
+    pub fn pick_stopwords(&mut self, words: Vec<String>) {
+        let words = sorted(words);
+        for stage in self.stages.iter_mut() {
+            if let Stage::AutoStopwords(_, ref mut picked) = *stage {
+                *picked = words.clone();
+            }
+        }
+    }
+
+    /// If there's a phrases stage that hasn't picked its phrases yet, the PMI they need.
+    pub fn unpicked_phrases(&self) -> Option<f64> {
+        self.stages.iter().filter_map(|s| match *s {
+            Stage::Phrases(_, pmi, None) => Some(pmi),
+            _ => None
+        }).next()
+    }
+
+    /// Gives the phrases stages their phrases.
+    pub fn pick_phrases(&mut self, phrases: Vec<String>) {
+        let phrases = sorted(phrases);
+        for stage in self.stages.iter_mut() {
+            if let Stage::Phrases(_, _, ref mut picked) = *stage {
+                *picked = Some(phrases.clone());
+            }
+        }
+    }
+
+    /// Reads the pipeline saved at path.
+    pub fn open(path: &str) -> Result<Pipeline, Error> {
+        open_file::<Pipeline>(path, "tokenizer")
+    }
+
+    /// Saves the pipeline to path, replacing whatever was there.
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        save_file(self, path)
+    }
+}
+
+fn sorted<I: IntoIterator<Item = String>>(words: I) -> Vec<String> {
+    let mut words = words.into_iter().collect::<Vec<String>>();
+    words.sort();
+    words.dedup();
+    words
+}
+
+/// Adds a Phrase token after the rest for every run of 2 to n Word tokens in a row, or only
+/// the ones in keep (which is sorted), if there is one.
+fn ngrams(mut tokens: Vec<Token>, n: usize, keep: Option<&[String]>) -> Vec<Token> {
+    let mut phrases = vec![];
+    for start in 0..tokens.len() {
+        for end in start + 1..cmp::min(start + n, tokens.len()) + 1 {
+            if tokens[end - 1].kind != TokenKind::Word {
+                break
+            }
+            if end - start < 2 {
+                continue
+            }
+            let words = &tokens[start..end];
+            let text = words.iter().map(|t| &t.text[..]).collect::<Vec<_>>().join(" ");
+            if keep.map(|k| k.binary_search(&text).is_err()).unwrap_or(false) {
+                continue
+            }
+            // A phrase made of stemmed words shows the words it was made of
+            let surface = if words.iter().any(|t| t.surface.is_some()) {
+                Some(words.iter().map(|t| &t.surface.as_ref().unwrap_or(&t.text)[..]).collect::<Vec<_>>().join(" "))
+            } else {
+                None
+            };
+            phrases.push(Token { text: text, kind: TokenKind::Phrase, surface: surface });
+        }
+    }
+    tokens.extend(phrases);
+    tokens
+}
+
+/// Decodes HTML entities. Anything that looks like an entity but isn't one is left alone.
+fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        // Entities are short, so only look a little way for the ;
+        let decoded = rest.char_indices().skip(1).take(10)
+            .find(|&(_, c)| c == ';' || !(c.is_alphanumeric() || c == '#'))
+            .and_then(|(end, c)| if c == ';' { entity(&rest[1..end]).map(|x| (x, end)) } else { None });
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            },
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The character an entity (without the & and ;) stands for.
+fn entity(name: &str) -> Option<char> {
+    match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        _ if name.starts_with("#x") || name.starts_with("#X") => {
+            u32::from_str_radix(&name[2..], 16).ok().and_then(::std::char::from_u32)
+        },
+        _ if name.starts_with('#') => name[1..].parse::<u32>().ok().and_then(::std::char::from_u32),
+        _ => None
+    }
+}
+
+/// Splits text into URLs, mentions, hashtags, and Text tokens for everything in between.
+fn extract(text: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut start = 0;
+    let mut i = 0;
+    while i < text.len() {
+        let rest = &text[i..];
+        // Mentions and hashtags have to start a word, so emails aren't mentions
+        let starts_word = text[..i].chars().next_back().map(|c| !is_word_char(c)).unwrap_or(true);
+        let found = if rest.starts_with("http://") || rest.starts_with("https://") || rest.starts_with("www.") {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            // Punctuation right after a URL is almost always part of the sentence
+            let url = rest[..end].trim_right_matches(|c: char| ".,;:!?)]}'\"".contains(c));
+            Some((url.len(), TokenKind::Url))
+        } else if starts_word && (rest.starts_with('@') || rest.starts_with('#')) {
+            let name = rest[1..].find(|c: char| !is_word_char(c)).unwrap_or(rest.len() - 1);
+            if name == 0 { None } else if rest.starts_with('@') {
+                Some((name + 1, TokenKind::Mention))
+            } else {
+                Some((name + 1, TokenKind::Hashtag))
+            }
+        } else {
+            None
+        };
+        match found {
+            Some((len, kind)) => {
+                if start < i {
+                    tokens.push(Token::new(&text[start..i], TokenKind::Text));
+                }
+                tokens.push(Token::new(&text[i..i + len], kind));
+                i += len;
+                start = i;
+            },
+            None => i += rest.chars().next().unwrap().len_utf8()
+        }
+    }
+    if start < text.len() {
+        tokens.push(Token::new(&text[start..], TokenKind::Text));
+    }
+    tokens
+}
+
+/// Splits text into words and emoji, dropping everything else.
+fn segment(text: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut word = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let inside = !word.is_empty() && chars.peek().map(|&n| is_word_char(n)).unwrap_or(false);
+        if is_word_char(c) || (is_apostrophe(c) && inside) {
+            word.push(c);
+            continue
+        }
+        if !word.is_empty() {
+            tokens.push(Token { text: word, kind: TokenKind::Word, surface: None });
+            word = String::new();
+        }
+        if is_emoji(c) {
+            tokens.push(Token::new(&c.to_string(), TokenKind::Emoji));
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(Token { text: word, kind: TokenKind::Word, surface: None });
+    }
+    tokens
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn is_apostrophe(c: char) -> bool {
+    c == '\'' || c == '\u{2019}'
+}
+
+/// Roughly recognizes emoji: the pictographic blocks, plus the older symbol blocks that have
+/// emoji in them. Modifiers and joiners aren't emoji on their own, so a sequence of them
+/// comes out as its parts.
+fn is_emoji(c: char) -> bool {
+    match c {
+        '\u{203C}' | '\u{2049}' | '\u{2122}' | '\u{2139}' |
+        '\u{2194}'...'\u{21AA}' | '\u{231A}'...'\u{23FF}' | '\u{24C2}' |
+        '\u{25AA}'...'\u{25FE}' | '\u{2600}'...'\u{27BF}' | '\u{2934}' | '\u{2935}' |
+        '\u{2B05}'...'\u{2B55}' | '\u{3030}' | '\u{303D}' | '\u{3297}' | '\u{3299}' |
+        '\u{1F000}'...'\u{1F1E5}' | '\u{1F200}'...'\u{1F3FA}' | '\u{1F400}'...'\u{1FAFF}' => true,
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ Token, TokenKind, decode_entities, extract, segment, ngrams };
+
+    fn kinds(tokens: &[Token]) -> Vec<(&str, TokenKind)> {
+        tokens.iter().map(|t| (&t.text[..], t.kind)).collect()
+    }
+
+    #[test]
+    fn test_decode_entities() {
+        assert_eq!(decode_entities("&amp;x&lt;"), "&x<");
+        assert_eq!(decode_entities("&quot;hi&quot; &#39;s &#x1F9C0;"), "\"hi\" 's 🧀");
+        // Things that only look like entities are left alone
+        assert_eq!(decode_entities("AT&T &amp &bogus; & ;"), "AT&T &amp &bogus; & ;");
+        assert_eq!(decode_entities("&amp;lt;"), "&lt;");
+        assert_eq!(decode_entities("é&"), "é&");
+    }
+
+    #[test]
+    fn test_extract() {
+        let tokens = extract("Read https://example.com/a?b=c. @someone #Budget2017 now");
+        assert_eq!(kinds(&tokens), vec![("Read ", TokenKind::Text), ("https://example.com/a?b=c", TokenKind::Url),
+                                        (". ", TokenKind::Text), ("@someone", TokenKind::Mention), (" ", TokenKind::Text),
+                                        ("#Budget2017", TokenKind::Hashtag), (" now", TokenKind::Text)]);
+        assert_eq!(kinds(&extract("(www.house.gov)")), vec![("(", TokenKind::Text), ("www.house.gov", TokenKind::Url),
+                                                              (")", TokenKind::Text)]);
+        // A lone @ or # isn't anything
+        assert_eq!(kinds(&extract("@ # #!")), vec![("@ # #!", TokenKind::Text)]);
+        assert!(extract("").is_empty());
+    }
+
+    #[test]
+    fn test_emails_are_not_mentions() {
+        assert_eq!(kinds(&extract("write to office@house.gov")), vec![("write to office@house.gov", TokenKind::Text)]);
+        assert_eq!(kinds(&extract("issue#5 (#five)")), vec![("issue#5 (", TokenKind::Text), ("#five", TokenKind::Hashtag),
+                                                              (")", TokenKind::Text)]);
+    }
+
+    #[test]
+    fn test_segment() {
+        assert_eq!(kinds(&segment("Don't stop—we’re 100% in_it! 🧀👍")),
+                   vec![("Don't", TokenKind::Word), ("stop", TokenKind::Word), ("we’re", TokenKind::Word),
+                        ("100", TokenKind::Word), ("in_it", TokenKind::Word), ("🧀", TokenKind::Emoji),
+                        ("👍", TokenKind::Emoji)]);
+        // Apostrophes only count inside of a word
+        assert_eq!(kinds(&segment("'quoted' rock'n'roll")),
+                   vec![("quoted", TokenKind::Word), ("rock'n'roll", TokenKind::Word)]);
+        assert_eq!(kinds(&segment("naïve café")), vec![("naïve", TokenKind::Word), ("café", TokenKind::Word)]);
+        assert!(segment(" ... ").is_empty());
+    }
+
+    fn words(text: &str) -> Vec<Token> {
+        text.split(' ').map(|w| if w == "#" { Token::new(w, TokenKind::Hashtag) } else { Token::new(w, TokenKind::Word) }).collect()
+    }
+
+    fn phrases(tokens: &[Token]) -> Vec<&str> {
+        tokens.iter().filter(|t| t.kind == TokenKind::Phrase).map(|t| &t.text[..]).collect()
+    }
+
+    #[test]
+    fn test_ngrams() {
+        let tokens = ngrams(words("pass the bill"), 3, None);
+        assert_eq!(tokens.len(), 6);
+        assert_eq!(kinds(&tokens[..3]), vec![("pass", TokenKind::Word), ("the", TokenKind::Word), ("bill", TokenKind::Word)]);
+        assert_eq!(phrases(&tokens), vec!["pass the", "pass the bill", "the bill"]);
+        assert_eq!(phrases(&ngrams(words("pass the bill"), 2, None)), vec!["pass the", "the bill"]);
+        assert!(phrases(&ngrams(words("pass the bill"), 1, None)).is_empty());
+        assert!(phrases(&ngrams(words("bill"), 3, None)).is_empty());
+    }
+
+    #[test]
+    fn test_ngrams_stop_at_other_tokens() {
+        assert_eq!(phrases(&ngrams(words("health care # tax cuts now"), 3, None)),
+                   vec!["health care", "tax cuts", "tax cuts now", "cuts now"]);
+    }
+
+    #[test]
+    fn test_ngrams_keep() {
+        let keep = vec!["health care".to_string(), "tax cuts now".to_string()];
+        assert_eq!(phrases(&ngrams(words("health care tax cuts now"), 3, Some(&keep))), vec!["health care", "tax cuts now"]);
+        assert!(phrases(&ngrams(words("health care"), 3, Some(&[]))).is_empty());
+    }
+
+    #[test]
+    fn test_ngrams_surface() {
+        let mut tokens = words("vote health");
+        tokens[0].text = "vot".to_string();
+        tokens[0].surface = Some("voting".to_string());
+        let tokens = ngrams(tokens, 2, None);
+        assert_eq!(tokens[2].text, "vot health");
+        assert_eq!(tokens[2].surface, Some("voting health".to_string()));
+        assert_eq!(ngrams(words("vote health"), 2, None)[2].surface, None);
+    }
+}
@@ -1,159 +1,931 @@
-use std::hash::{ Hash, Hasher };
-use std::collections::hash_map::DefaultHasher;
-use std::collections::LinkedList;
-use std::io::{ Seek, SeekFrom, Read, Write };
+use std::hash::{ Hash, Hasher, BuildHasher };
+use std::collections::hash_map::RandomState;
+use std::borrow::Borrow;
+use std::iter::{ FromIterator, IntoIterator };
+use std::fmt;
+use fxhash::FxBuildHasher;
+use std::io::{ Read, Write };
 use std::io;
+use std::mem;
+use std::slice;
+use std::vec;
+use codec::{ Serialize, write_varint, read_varint, varint_len };
 
-#[derive(PartialEq, Eq)]
-struct Entry<K, V> where K: Hash + Eq, V: Eq {
+/*
+                            ********************************
+                            *           TWITHASH           *
+                            ********************************
+
+    An open addressing hash table using Robin Hood hashing. Every slot in `buckets` is either
+    empty or holds a key, its value and its hash. A key goes in the slot its hash points to (its
+    home), or if that is taken, the first free slot after it. The distance from a key's home to
+    where it actually is is its probe distance.
+
+    The Robin Hood part: while looking for a free slot, if the key being inserted is further
+    from its home than the key sitting in a slot, they swap, and the key that got kicked out
+    keeps looking. This keeps probe distances short and even, and means a lookup can stop as
+    soon as it sees a key that is closer to its home than the one it is looking for would be.
+
+    Removing a key shifts the keys after it back one slot, until it hits an empty slot or a key
+    that is already home, so there are no tombstones.
+
+    Keys are hashed with the table's BuildHasher, which is FxHash unless the table was made
+    with_hasher or randomized (see fxhash.rs for when that matters). Every operation hashes its
+    key once, and growing the table reuses the hashes kept in the buckets.
+*/
+
+/// The number of slots a new table starts with. Always a power of 2.
+const INITIAL_SLOTS: usize = 16;
+
+#[derive(Clone)]
+struct Bucket<K, V> {
+    hash: u64,
     key: K,
-    value: V,
-    hash: u64
+    value: V
 }
-impl<K, V> Entry<K, V> where K: Hash + Eq, V: Eq {
-    fn new(key: K, value: V, hash: u64) -> Entry<K, V> {
-        Entry {
-            key: key,
-            value: value,
-            hash: hash
-        }
+
+pub struct TwitHash<K, V, S = FxBuildHasher> where K: Hash + Eq {
+    buckets: Vec<Option<Bucket<K, V>>>,
+    pub count: usize,
+    hasher: S
+}
+
+/// A table with `n` empty slots.
+fn empty_buckets<K, V>(n: usize) -> Vec<Option<Bucket<K, V>>> {
+    let mut buckets = Vec::with_capacity(n);
+    for _ in 0..n { buckets.push(None); }
+    buckets
+}
+
+impl<K, V> TwitHash<K, V, FxBuildHasher> where K: Hash + Eq {
+    pub fn new() -> TwitHash<K, V, FxBuildHasher> {
+        TwitHash::with_hasher(FxBuildHasher)
     }
 }
 
-#[derive(PartialEq, Eq)]
-pub struct TwitHash<K, V> where K: Hash + Eq, V: Eq {
-    entries: Vec<LinkedList<Box<Entry<K, V>>>>,
-    len: usize,
-    pub count: usize,
+/// A TwitHash for keys that someone might pick on purpose, e.g. words out of tweets.
+pub type RandomTwitHash<K, V> = TwitHash<K, V, RandomState>;
+
+impl<K, V> TwitHash<K, V, RandomState> where K: Hash + Eq {
+    /// A table that hashes with SipHash, seeded with random keys when it is made, so nobody can
+    /// work out ahead of time which keys will all land in the same slot.
+    pub fn randomized() -> RandomTwitHash<K, V> {
+        TwitHash::with_hasher(RandomState::new())
+    }
 }
 
-impl<K, V> TwitHash<K, V> where K: Hash + Eq, V: Eq {
-    pub fn new() -> TwitHash<K, V> {
-        let mut t = TwitHash {
-            entries: Vec::with_capacity(16),
+impl<K, V, S> TwitHash<K, V, S> where K: Hash + Eq, S: BuildHasher {
+    /// A table that hashes its keys with hashers from `hasher`, e.g.
+    /// TwitHash::with_hasher(RandomState::new()) for keys that might be picked by an attacker.
+    pub fn with_hasher(hasher: S) -> TwitHash<K, V, S> {
+        TwitHash {
+            buckets: empty_buckets(INITIAL_SLOTS),
             count: 0,
-            len: 16
-        };
-        for _ in 0..16 { t.entries.push(LinkedList::new()); }
-        t
+            hasher: hasher
+        }
     }
 
     pub fn is_empty(&self) -> bool {
         self.count == 0
     }
 
-    fn insert_entry(&mut self, e: Box<Entry<K, V>>) {
-        self.entries[e.hash as usize & (self.len - 1)].push_front(e);
+    pub fn len(&self) -> usize {
+        self.count
     }
 
-    /// Will dynamically resize the size of the hash once alpha is > .75 (alpha being
-    /// the total number of elements divided by the number of slots).
-    pub fn insert(&mut self, key: K, value: V) {
-        if let Some(v) = self.get_mut(&key) {
-            *v = value;
-            return;
-        }
-        let hash = self.hash(&key);
-        self.insert_entry(Box::new(Entry::new(key, value, hash)));
-        self.count += 1;
+    fn mask(&self) -> usize {
+        self.buckets.len() - 1
+    }
+
+    fn hash<Q: ?Sized>(&self, k: &Q) -> u64 where Q: Hash {
+        let mut hasher = self.hasher.build_hasher();
+        k.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Finds the slot key is in.
+    fn find<Q: ?Sized>(&self, key: &Q) -> Option<usize> where K: Borrow<Q>, Q: Hash + Eq {
+        let hash = self.hash(key);
+        self.find_hashed(hash, key)
+    }
 
-        if (self.count as f32) / (self.len as f32) > 0.75 {
-            self.len *= 2;
-            let mut elements = Vec::with_capacity(self.len);
-            while let Some(element) = self.entries.pop() {
-                elements.append(&mut element.into_iter().collect());
+    /// Finds the slot key is in, when its hash is already known.
+    fn find_hashed<Q: ?Sized>(&self, hash: u64, key: &Q) -> Option<usize> where K: Borrow<Q>, Q: Eq {
+        let mask = self.mask();
+        let mut ind = hash as usize & mask;
+        let mut dist = 0;
+        loop {
+            match self.buckets[ind] {
+                None => return None,
+                Some(ref b) => {
+                    // If key were here, it would have kicked this one out
+                    if (ind.wrapping_sub(b.hash as usize) & mask) < dist {
+                        return None
+                    }
+                    if b.hash == hash && b.key.borrow() == key {
+                        return Some(ind)
+                    }
+                }
             }
-            for _ in 0 .. self.len {
-                self.entries.push(LinkedList::new());
+            ind = (ind + 1) & mask;
+            dist += 1;
+        }
+    }
+
+    /// Puts a key that isn't in the table yet into it, without checking whether the table is
+    /// full enough to grow. Returns the slot it ended up in.
+    fn insert_hashed(&mut self, hash: u64, key: K, value: V) -> usize {
+        let mask = self.mask();
+        let mut ind = hash as usize & mask;
+        let mut dist = 0;
+        let mut cur = Bucket { hash: hash, key: key, value: value };
+        let mut placed = None;
+        loop {
+            match self.buckets[ind] {
+                None => {},
+                Some(ref mut b) => {
+                    let their_dist = ind.wrapping_sub(b.hash as usize) & mask;
+                    if their_dist < dist {
+                        mem::swap(b, &mut cur);
+                        if placed.is_none() { placed = Some(ind); }
+                        dist = their_dist;
+                    }
+                    ind = (ind + 1) & mask;
+                    dist += 1;
+                    continue;
+                }
             }
-            while let Some(element) = elements.pop() {
-                self.insert_entry(element);
+            self.buckets[ind] = Some(cur);
+            return match placed { Some(p) => p, None => ind }
+        }
+    }
+
+    /// Doubles the number of slots if adding one more key would make the table more than 3/4
+    /// full. The hashes are kept in the buckets, so nothing gets hashed again.
+    fn reserve_one(&mut self) {
+        if (self.count + 1) * 4 <= self.buckets.len() * 3 {
+            return
+        }
+        let len = self.buckets.len() * 2;
+        let old = mem::replace(&mut self.buckets, empty_buckets(len));
+        for b in old.into_iter() {
+            if let Some(b) = b {
+                self.insert_hashed(b.hash, b.key, b.value);
             }
         }
     }
 
-    pub fn get(&self, key: &K) -> Option<&V> {
-        let hash = self.hash(&key);
-        let ind = (hash as usize) & (self.len - 1);
-        if let Some(b) = self.entries[ind].iter().find(|ref x| x.key == *key && x.hash == hash) {
-            Some(&b.value)
-        } else {
-            None
+    /// Takes the key out of a slot, and shifts the ones after it back to fill the hole.
+    fn remove_at(&mut self, ind: usize) -> (K, V) {
+        let mask = self.mask();
+        let removed = self.buckets[ind].take().unwrap();
+        let mut ind = ind;
+        loop {
+            let next = (ind + 1) & mask;
+            let shift = match self.buckets[next] {
+                Some(ref b) => (next.wrapping_sub(b.hash as usize) & mask) != 0,
+                None => false
+            };
+            if !shift { break }
+            self.buckets[ind] = self.buckets[next].take();
+            ind = next;
         }
+        self.count -= 1;
+        (removed.key, removed.value)
     }
 
-    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+    /// Inserts a key, replacing its value if it is already in the table. The table grows
+    /// once it is more than 3/4 full.
+    pub fn insert(&mut self, key: K, value: V) {
         let hash = self.hash(&key);
-        let ind = (hash as usize) & (self.len - 1);
-        if let Some(b) = self.entries[ind].iter_mut().find(|ref x| x.key == *key && x.hash == hash) {
-            Some(&mut b.value)
-        } else {
-            None
+        if let Some(ind) = self.find_hashed(hash, &key) {
+            self.buckets[ind].as_mut().unwrap().value = value;
+            return;
         }
+        self.reserve_one();
+        self.insert_hashed(hash, key, value);
+        self.count += 1;
     }
 
-    fn hash(&self, k: &K) -> u64 {
-        let mut hasher = DefaultHasher::new();
-        k.hash(&mut hasher);
-        hasher.finish()
+    /// Removes a key, returning its value if it was there. Like get, it takes anything the
+    /// keys can be borrowed as.
+    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> Option<V> where K: Borrow<Q>, Q: Hash + Eq {
+        match self.find(key) {
+            Some(ind) => Some(self.remove_at(ind).1),
+            None => None
+        }
     }
 
-    pub fn contains_key(&self, key: &K) -> bool {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        let hash = hasher.finish();
-        let ind = (hash as usize) & (self.len - 1);
-        if self.entries[ind].is_empty() {
-            false
-        } else {
-            !self.entries[ind].iter().find(|ref x| x.key == *key && x.hash == hash).is_none()
+    /// Looks up anything the keys can be borrowed as, e.g. a &str in a table of Strings, so
+    /// there's no need to build a key just to look for it.
+    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<&V> where K: Borrow<Q>, Q: Hash + Eq {
+        match self.find(key) {
+            Some(ind) => self.buckets[ind].as_ref().map(|b| &b.value),
+            None => None
         }
     }
 
-    pub fn keys(&self) -> Vec<&K> {
-        let mut ret = Vec::new();
-        for list in self.entries.iter() {
-            for entry in list.iter() {
-                if !ret.contains(&&entry.key) {
-                    ret.push(&entry.key);
+    pub fn get_mut<Q: ?Sized>(&mut self, key: &Q) -> Option<&mut V> where K: Borrow<Q>, Q: Hash + Eq {
+        match self.find(key) {
+            Some(ind) => self.buckets[ind].as_mut().map(|b| &mut b.value),
+            None => None
+        }
+    }
+
+    pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool where K: Borrow<Q>, Q: Hash + Eq {
+        self.find(key).is_some()
+    }
+
+    /// Gets the spot for a key, whether or not it is in the table, so that it can be looked at
+    /// and filled in with only one lookup, e.g. *table.entry(word).or_insert(0) += 1
+    pub fn entry(&mut self, key: K) -> Entry<K, V, S> {
+        let hash = self.hash(&key);
+        match self.find_hashed(hash, &key) {
+            Some(ind) => Entry::Occupied(OccupiedEntry { table: self, ind: ind }),
+            None => Entry::Vacant(VacantEntry { table: self, key: key, hash: hash })
+        }
+    }
+
+    /// Only keeps the pairs that f returns true for.
+    pub fn retain<F>(&mut self, mut f: F) where F: FnMut(&K, &mut V) -> bool {
+        let len = self.buckets.len();
+        let old = mem::replace(&mut self.buckets, empty_buckets(len));
+        self.count = 0;
+        for b in old.into_iter() {
+            if let Some(mut b) = b {
+                if f(&b.key, &mut b.value) {
+                    self.insert_hashed(b.hash, b.key, b.value);
+                    self.count += 1;
                 }
             }
         }
-        ret
+    }
+
+    /// Takes every pair out of the table, leaving it empty (but just as big).
+    pub fn drain(&mut self) -> Drain<K, V> {
+        let len = self.buckets.len();
+        let old = mem::replace(&mut self.buckets, empty_buckets(len));
+        self.count = 0;
+        Drain { inner: old.into_iter() }
+    }
+
+    /// Every pair in the table, in no particular order.
+    pub fn iter(&self) -> Iter<K, V> {
+        Iter { inner: self.buckets.iter(), left: self.count }
+    }
+
+    /// Every pair in the table, with the values mutable.
+    pub fn iter_mut(&mut self) -> IterMut<K, V> {
+        let left = self.count;
+        IterMut { inner: self.buckets.iter_mut(), left: left }
+    }
+
+    pub fn keys(&self) -> Vec<&K> {
+        self.iter().map(|(k, _)| k).collect()
     }
 
     pub fn values(&self) -> Vec<&V> {
-        let mut ret = Vec::new();
-        for list in self.entries.iter() {
-            for entry in list.iter() {
-                if !ret.contains(&&entry.value) {
-                    ret.push(&entry.value);
-                }
+        self.iter().map(|(_, v)| v).collect()
+    }
+
+    pub fn pairs(&self) -> Vec<(&K, &V)> {
+        self.iter().collect()
+    }
+}
+
+/// A spot in a TwitHash, see TwitHash::entry.
+pub enum Entry<'a, K: 'a, V: 'a, S: 'a> where K: Hash + Eq {
+    Occupied(OccupiedEntry<'a, K, V, S>),
+    Vacant(VacantEntry<'a, K, V, S>)
+}
+
+/// A spot in a TwitHash that has a key in it.
+pub struct OccupiedEntry<'a, K: 'a, V: 'a, S: 'a> where K: Hash + Eq {
+    table: &'a mut TwitHash<K, V, S>,
+    ind: usize
+}
+
+/// A spot in a TwitHash that doesn't have a key in it yet.
+pub struct VacantEntry<'a, K: 'a, V: 'a, S: 'a> where K: Hash + Eq {
+    table: &'a mut TwitHash<K, V, S>,
+    key: K,
+    hash: u64
+}
+
+impl<'a, K, V, S> Entry<'a, K, V, S> where K: Hash + Eq, S: BuildHasher {
+    pub fn key(&self) -> &K {
+        match *self {
+            Entry::Occupied(ref e) => e.key(),
+            Entry::Vacant(ref e) => e.key()
+        }
+    }
+
+    /// Fills in the spot with value if it is empty, then returns the value in it.
+    pub fn or_insert(self, value: V) -> &'a mut V {
+        match self {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(value)
+        }
+    }
+
+    /// Like or_insert, but f is only called if the spot is empty.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, f: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(f())
+        }
+    }
+}
+
+impl<'a, K, V, S> OccupiedEntry<'a, K, V, S> where K: Hash + Eq, S: BuildHasher {
+    fn bucket(&self) -> &Bucket<K, V> {
+        self.table.buckets[self.ind].as_ref().unwrap()
+    }
+
+    pub fn key(&self) -> &K {
+        &self.bucket().key
+    }
+
+    pub fn get(&self) -> &V {
+        &self.bucket().value
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.table.buckets[self.ind].as_mut().unwrap().value
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        &mut self.table.buckets[self.ind].as_mut().unwrap().value
+    }
+
+    /// Replaces the value, returning the old one.
+    pub fn insert(&mut self, value: V) -> V {
+        mem::replace(self.get_mut(), value)
+    }
+
+    /// Takes the pair out of the table, returning the value.
+    pub fn remove(self) -> V {
+        self.table.remove_at(self.ind).1
+    }
+}
+
+impl<'a, K, V, S> VacantEntry<'a, K, V, S> where K: Hash + Eq, S: BuildHasher {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn insert(self, value: V) -> &'a mut V {
+        let table = self.table;
+        table.reserve_one();
+        let ind = table.insert_hashed(self.hash, self.key, value);
+        table.count += 1;
+        &mut table.buckets[ind].as_mut().unwrap().value
+    }
+}
+
+pub struct Iter<'a, K: 'a, V: 'a> {
+    inner: slice::Iter<'a, Option<Bucket<K, V>>>,
+    left: usize
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        while let Some(b) = self.inner.next() {
+            if let Some(ref b) = *b {
+                self.left -= 1;
+                return Some((&b.key, &b.value))
             }
         }
-        ret
+        None
     }
 
-    pub fn pairs(&self) -> Vec<(&K, &V)> {
-        let mut ret = Vec::new();
-        for list in self.entries.iter() {
-            for entry in list.iter() {
-                if !ret.contains(&(&entry.key, &entry.value)) {
-                    ret.push((&entry.key, &entry.value));
-                }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.left, Some(self.left))
+    }
+}
+
+pub struct IterMut<'a, K: 'a, V: 'a> {
+    inner: slice::IterMut<'a, Option<Bucket<K, V>>>,
+    left: usize
+}
+
+impl<'a, K, V> Iterator for IterMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
+        while let Some(b) = self.inner.next() {
+            if let Some(ref mut b) = *b {
+                self.left -= 1;
+                return Some((&b.key, &mut b.value))
             }
         }
-        ret
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.left, Some(self.left))
     }
 }
 
+/// The pairs taken out of a TwitHash, by TwitHash::drain or into_iter.
+pub struct Drain<K, V> {
+    inner: vec::IntoIter<Option<Bucket<K, V>>>
+}
+
+impl<K, V> Iterator for Drain<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        while let Some(b) = self.inner.next() {
+            if let Some(b) = b {
+                return Some((b.key, b.value))
+            }
+        }
+        None
+    }
+}
+
+impl<K, V, S> IntoIterator for TwitHash<K, V, S> where K: Hash + Eq {
+    type Item = (K, V);
+    type IntoIter = Drain<K, V>;
+
+    fn into_iter(self) -> Drain<K, V> {
+        Drain { inner: self.buckets.into_iter() }
+    }
+}
+
+impl<'a, K, V, S> IntoIterator for &'a TwitHash<K, V, S> where K: Hash + Eq, S: BuildHasher {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+impl<'a, K, V, S> IntoIterator for &'a mut TwitHash<K, V, S> where K: Hash + Eq, S: BuildHasher {
+    type Item = (&'a K, &'a mut V);
+    type IntoIter = IterMut<'a, K, V>;
+
+    fn into_iter(self) -> IterMut<'a, K, V> {
+        self.iter_mut()
+    }
+}
+
+/// Later pairs replace earlier ones with the same key, just like insert.
+impl<K, V, S> FromIterator<(K, V)> for TwitHash<K, V, S> where K: Hash + Eq, S: BuildHasher + Default {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> TwitHash<K, V, S> {
+        let mut t = TwitHash::with_hasher(S::default());
+        t.extend(iter);
+        t
+    }
+}
+
+impl<K, V, S> Extend<(K, V)> for TwitHash<K, V, S> where K: Hash + Eq, S: BuildHasher {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+impl<K, V, S> Clone for TwitHash<K, V, S> where K: Hash + Eq + Clone, V: Clone, S: Clone {
+    fn clone(&self) -> TwitHash<K, V, S> {
+        TwitHash { buckets: self.buckets.clone(), count: self.count, hasher: self.hasher.clone() }
+    }
+}
+
+impl<K, V, S> fmt::Debug for TwitHash<K, V, S> where K: Hash + Eq + fmt::Debug, V: fmt::Debug, S: BuildHasher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<K, V, S> Default for TwitHash<K, V, S> where K: Hash + Eq, S: BuildHasher + Default {
+    fn default() -> TwitHash<K, V, S> {
+        TwitHash::with_hasher(S::default())
+    }
+}
+
+/// Two tables are equal if they have the same pairs, no matter what order they went in.
+impl<K, V, S> PartialEq for TwitHash<K, V, S> where K: Hash + Eq, V: PartialEq, S: BuildHasher {
+    fn eq(&self, other: &TwitHash<K, V, S>) -> bool {
+        self.count == other.count && self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl<K, V, S> Eq for TwitHash<K, V, S> where K: Hash + Eq, V: Eq, S: BuildHasher {}
+
 use std::ops::Index;
 
-/// Index operator
-impl<K, V> Index<K> for TwitHash<K, V> where K: Eq + Hash, V: Eq {
+/// Index operator, e.g. table[&key]. Panics if the key isn't there.
+impl<'a, K, V, S> Index<&'a K> for TwitHash<K, V, S> where K: Eq + Hash, S: BuildHasher {
     type Output = V;
 
-    fn index(&self, key: K) -> &V {
-        &self.get(&key).unwrap()
+    fn index(&self, key: &K) -> &V {
+        match self.get(key) {
+            Some(v) => v,
+            None => panic!("key is not in the TwitHash")
+        }
+    }
+}
+
+/// A varint count, then every key followed by its value.
+impl<K, V, S> Serialize for TwitHash<K, V, S>
+    where K: Hash + Eq + Serialize<DeserializeOutput = K>,
+          V: Serialize<DeserializeOutput = V>,
+          S: BuildHasher + Default {
+    type DeserializeOutput = TwitHash<K, V, S>;
+
+    fn serialize(&self, to: &mut Write) -> Result<(), io::Error> {
+        if let Err(e) = write_varint(self.count as u64, to) {
+            return Err(e)
+        }
+        for (k, v) in self.iter() {
+            if let Err(e) = k.serialize(to) {
+                return Err(e)
+            }
+            if let Err(e) = v.serialize(to) {
+                return Err(e)
+            }
+        }
+        Ok(())
+    }
+
+    fn deserialize(from: &mut Read) -> Result<TwitHash<K, V, S>, io::Error> {
+        let count = match read_varint(from) {
+            Ok(x) => x,
+            Err(e) => return Err(e)
+        };
+        let mut t = TwitHash::with_hasher(S::default());
+        for _ in 0..count {
+            let k = match K::deserialize(from) {
+                Ok(x) => x,
+                Err(e) => return Err(e)
+            };
+            let v = match V::deserialize(from) {
+                Ok(x) => x,
+                Err(e) => return Err(e)
+            };
+            t.insert(k, v);
+        }
+        Ok(t)
+    }
+
+    fn serialized_len(&self) -> u64 {
+        self.iter().fold(varint_len(self.count as u64), |acc, (k, v)| acc + k.serialized_len() + v.serialized_len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ TwitHash, Entry };
+    use super::RandomTwitHash;
+    use std::hash::{ Hasher, BuildHasher, BuildHasherDefault };
+    use std::collections::HashMap;
+    use std::collections::hash_map::{ RandomState, DefaultHasher };
+    use std::rc::Rc;
+    use std::cell::Cell;
+    use rng::Rng;
+    use codec::{ Serialize, round_trip };
+
+    /// Hashes a u64 to itself, so a test can pick which slot every key's home is.
+    #[derive(Default)]
+    struct IdentityHasher(u64);
+
+    impl Hasher for IdentityHasher {
+        fn write(&mut self, _: &[u8]) {
+            panic!("only u64 keys")
+        }
+
+        fn write_u64(&mut self, x: u64) {
+            self.0 = x;
+        }
+
+        fn finish(&self) -> u64 {
+            self.0
+        }
+    }
+
+    type Table = TwitHash<u64, u64, BuildHasherDefault<IdentityHasher>>;
+
+    fn table(keys: &[u64]) -> Table {
+        let mut t = Table::default();
+        for &k in keys {
+            t.insert(k, k * 10);
+        }
+        t
+    }
+
+    /// Which slot every key is in, in slot order.
+    fn slots(t: &Table) -> Vec<(usize, u64)> {
+        t.buckets.iter().enumerate().filter_map(|(i, b)| b.as_ref().map(|b| (i, b.key))).collect()
+    }
+
+    #[test]
+    fn test_wrap_around() {
+        // All three want slot 14 of 16, so the last one wraps to slot 0
+        let mut t = table(&[14, 30, 46]);
+        assert_eq!(slots(&t), vec![(0, 46), (14, 14), (15, 30)]);
+        assert_eq!(t.get(&46), Some(&460));
+        assert_eq!(t.get(&62), None);
+        assert_eq!(t.remove(&14), Some(140));
+        assert_eq!(slots(&t), vec![(14, 30), (15, 46)]);
+        assert_eq!(t.get(&30), Some(&300));
+        assert_eq!(t.get(&46), Some(&460));
+        assert_eq!(t.len(), 2);
+    }
+
+    #[test]
+    fn test_robin_hood() {
+        // 2 is home at slot 2, but 17 and 33 are further from theirs, so 2 ends up behind them
+        let t = table(&[1, 17, 33, 2]);
+        assert_eq!(slots(&t), vec![(1, 1), (2, 17), (3, 33), (4, 2)]);
+        assert_eq!(t.get(&2), Some(&20));
+    }
+
+    #[test]
+    fn test_backshift() {
+        let mut t = table(&[1, 17, 33, 2, 6]);
+        assert_eq!(t.remove(&17), Some(170));
+        // Everything after it shifts back, until 6, which is already home
+        assert_eq!(slots(&t), vec![(1, 1), (2, 33), (3, 2), (6, 6)]);
+        for &k in &[1, 33, 2, 6] {
+            assert_eq!(t.get(&k), Some(&(k * 10)));
+        }
+        assert_eq!(t.remove(&17), None);
+        assert_eq!(t.len(), 4);
+    }
+
+    #[test]
+    fn test_grow() {
+        let mut t = Table::default();
+        for k in 0..12 {
+            t.insert(k * 16, k);
+        }
+        // 12 of 16 is exactly 3/4 full
+        assert_eq!(t.buckets.len(), 16);
+        t.insert(12 * 16, 12);
+        assert_eq!(t.buckets.len(), 32);
+        for k in 0..13 {
+            assert_eq!(t.get(&(k * 16)), Some(&k));
+        }
+        for k in 13..100 {
+            t.insert(k * 16, k);
+        }
+        assert_eq!(t.len(), 100);
+        assert_eq!(t.buckets.len(), 256);
+        for k in 0..100 {
+            assert_eq!(t.get(&(k * 16)), Some(&k));
+        }
+    }
+
+    #[test]
+    fn test_entry() {
+        let mut t = table(&[1]);
+        match t.entry(1) {
+            Entry::Occupied(mut e) => {
+                assert_eq!(*e.key(), 1);
+                assert_eq!(*e.get(), 10);
+                assert_eq!(e.insert(11), 10);
+                *e.get_mut() += 1;
+            },
+            Entry::Vacant(_) => panic!("1 is in the table")
+        }
+        assert_eq!(t.get(&1), Some(&12));
+        match t.entry(17) {
+            Entry::Vacant(e) => {
+                assert_eq!(*e.key(), 17);
+                *e.insert(170) += 1;
+            },
+            Entry::Occupied(_) => panic!("17 isn't in the table")
+        }
+        assert_eq!(t.get(&17), Some(&171));
+        assert_eq!(t.len(), 2);
+        match t.entry(1) {
+            Entry::Occupied(e) => assert_eq!(e.remove(), 12),
+            Entry::Vacant(_) => panic!("1 is in the table")
+        }
+        assert_eq!(t.get(&1), None);
+        assert_eq!(t.get(&17), Some(&171));
+        assert_eq!(t.len(), 1);
+
+        *t.entry(5).or_insert(0) += 1;
+        *t.entry(5).or_insert(0) += 1;
+        assert_eq!(*t.entry(6).or_insert_with(|| 60), 60);
+        assert_eq!(t.get(&5), Some(&2));
+        assert_eq!(t.len(), 3);
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut t = table(&(0..40).collect::<Vec<_>>());
+        t.retain(|k, v| { *v += 1; k % 3 == 0 });
+        assert_eq!(t.len(), 14);
+        for k in 0..40 {
+            assert_eq!(t.get(&k).cloned(), if k % 3 == 0 { Some(k * 10 + 1) } else { None });
+        }
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut t = table(&(0..40).collect::<Vec<_>>());
+        let slots = t.buckets.len();
+        let mut drained = t.drain().collect::<Vec<_>>();
+        drained.sort();
+        assert_eq!(drained, (0..40).map(|k| (k, k * 10)).collect::<Vec<_>>());
+        assert!(t.is_empty());
+        assert_eq!(t.len(), 0);
+        assert_eq!(t.buckets.len(), slots);
+        assert_eq!(t.get(&3), None);
+        assert_eq!(t.iter().count(), 0);
+
+        t.insert(3, 4);
+        assert_eq!(t.get(&3), Some(&4));
+        assert_eq!(t.len(), 1);
+    }
+
+    #[test]
+    fn test_against_hash_map() {
+        let mut rng = Rng::new(34);
+        let mut t = Table::default();
+        let mut expected = HashMap::new();
+        for _ in 0..5000 {
+            // Multiples of 8 pile up in the same few homes, so the runs get long
+            let k = rng.range(0, 200) as u64 * 8;
+            if rng.range(0, 3) == 0 {
+                assert_eq!(t.remove(&k), expected.remove(&k));
+            } else {
+                let v = rng.next_u64();
+                t.insert(k, v);
+                expected.insert(k, v);
+            }
+            assert_eq!(t.len(), expected.len());
+        }
+        assert_eq!(t.iter().count(), expected.len());
+        for k in 0..200 {
+            assert_eq!(t.get(&(k * 8)), expected.get(&(k * 8)));
+        }
+    }
+
+    #[test]
+    fn test_borrowed_lookups() {
+        let mut t: TwitHash<String, u32> = TwitHash::new();
+        t.insert("cat".to_string(), 1);
+        t.insert("dog".to_string(), 2);
+        assert_eq!(t.get("cat"), Some(&1));
+        assert_eq!(t.get("cow"), None);
+        assert!(t.contains_key("dog"));
+        *t.get_mut("dog").unwrap() += 1;
+        assert_eq!(t.remove("dog"), Some(3));
+        assert_eq!(t.remove("dog"), None);
+        assert_eq!(t.len(), 1);
+    }
+
+    #[test]
+    fn test_iterators() {
+        let mut t = table(&[1, 17, 33, 2]);
+        let mut pairs = (&t).into_iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
+        pairs.sort();
+        assert_eq!(pairs, vec![(1, 10), (2, 20), (17, 170), (33, 330)]);
+        assert_eq!(t.iter().size_hint(), (4, Some(4)));
+        for (_, v) in &mut t {
+            *v += 1;
+        }
+        let mut owned = t.into_iter().collect::<Vec<_>>();
+        owned.sort();
+        assert_eq!(owned, vec![(1, 11), (2, 21), (17, 171), (33, 331)]);
+    }
+
+    #[test]
+    fn test_from_iter_and_extend() {
+        // Later pairs win, like insert
+        let mut t = vec![(1, 1), (2, 2), (1, 3)].into_iter().collect::<Table>();
+        assert_eq!(t.len(), 2);
+        assert_eq!(t.get(&1), Some(&3));
+        t.extend(vec![(2, 4), (5, 5)]);
+        assert_eq!(t.len(), 3);
+        assert_eq!(t.get(&2), Some(&4));
+        assert_eq!(t.get(&5), Some(&5));
+    }
+
+    #[test]
+    fn test_clone_and_eq() {
+        let t = table(&[1, 17, 33, 2]);
+        let c = t.clone();
+        assert_eq!(slots(&c), slots(&t));
+        assert!(c == t);
+
+        // Same pairs put in a different order end up in different slots, but are still equal
+        let other = table(&[2, 33, 17, 1]);
+        assert!(slots(&other) != slots(&t));
+        assert!(other == t);
+
+        let mut changed = t.clone();
+        changed.insert(2, 0);
+        assert!(changed != t);
+        let mut more = t.clone();
+        more.insert(3, 30);
+        assert!(more != t && t != more);
+        assert!(Table::default() == Table::default());
+    }
+
+    #[test]
+    fn test_index() {
+        let t = table(&[1, 17]);
+        assert_eq!(t[&17], 170);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_index_missing() {
+        let t = table(&[1, 17]);
+        let _ = t[&2];
+    }
+
+    #[test]
+    fn test_serialize() {
+        let mut t: TwitHash<String, u32> = TwitHash::new();
+        round_trip(&t).unwrap();
+        for i in 0..50 {
+            t.insert(format!("word {}", i), i);
+        }
+        round_trip(&t).unwrap();
+        let mut bytes = vec![];
+        t.serialize(&mut bytes).unwrap();
+        let back = TwitHash::<String, u32>::deserialize(&mut &bytes[..]).unwrap();
+        assert_eq!(back.len(), 50);
+        assert_eq!(back.get("word 7"), Some(&7));
+    }
+
+    #[test]
+    fn test_custom_hasher() {
+        let mut t = TwitHash::with_hasher(RandomState::new());
+        let mut expected = HashMap::new();
+        let mut rng = Rng::new(36);
+        for _ in 0..2000 {
+            let k = format!("word {}", rng.range(0, 300));
+            if rng.range(0, 3) == 0 {
+                assert_eq!(t.remove(&k), expected.remove(&k));
+            } else {
+                t.insert(k.clone(), k.len());
+                expected.insert(k.clone(), k.len());
+            }
+        }
+        assert_eq!(t.len(), expected.len());
+        for (k, v) in expected.iter() {
+            assert_eq!(t.get(k), Some(v));
+        }
+    }
+
+    #[test]
+    fn test_randomized() {
+        let mut t: RandomTwitHash<String, u32> = TwitHash::randomized();
+        let u: RandomTwitHash<String, u32> = TwitHash::randomized();
+        // Every table gets its own keys, so the same word hashes differently in each
+        assert!(t.hash("word") != u.hash("word"));
+        for i in 0..100 {
+            t.insert(format!("word {}", i), i);
+        }
+        for i in 0..100 {
+            assert_eq!(t.get(&format!("word {}", i)[..]), Some(&i));
+        }
+        assert_eq!(t.remove("word 3"), Some(3));
+        assert_eq!(t.len(), 99);
+    }
+
+    /// Counts how many times anything was hashed.
+    #[derive(Clone)]
+    struct CountingState(Rc<Cell<usize>>);
+
+    impl BuildHasher for CountingState {
+        type Hasher = DefaultHasher;
+
+        fn build_hasher(&self) -> DefaultHasher {
+            self.0.set(self.0.get() + 1);
+            DefaultHasher::new()
+        }
+    }
+
+    #[test]
+    fn test_hashed_once() {
+        let count = Rc::new(Cell::new(0));
+        let mut t = TwitHash::with_hasher(CountingState(count.clone()));
+        for i in 0..1000 {
+            t.insert(i, i);
+        }
+        // Growing from 16 slots to 2048 didn't hash anything again
+        assert_eq!(t.buckets.len(), 2048);
+        assert_eq!(count.get(), 1000);
+        t.insert(5, 6);
+        *t.entry(6).or_insert(0) += 1;
+        *t.entry(1000).or_insert(0) += 1;
+        assert_eq!(t.get(&5), Some(&6));
+        assert_eq!(t.remove(&7), Some(7));
+        assert_eq!(count.get(), 1005);
+        t.retain(|k, _| k % 2 == 0);
+        assert_eq!(count.get(), 1005);
     }
 }
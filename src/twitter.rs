@@ -1,205 +1,231 @@
 use egg_mode::*;
 use twithash::*;
-use regex::*;
 use std::f64;
+use std::cmp;
 use std::cmp::Ordering;
 use LOG_FILE;
 use std::io::{ Seek, SeekFrom, Read, Write };
 use std::io;
-use random_access_file::Serialize;
-use std::mem;
+use std::iter::Zip;
+use std::slice;
+use vocab::Vocabulary;
+use cluster;
+use cluster::{ SparseVector, Clustering, Scores, KChoice };
+use hac;
+use hac::{ Dendrogram, Linkage, Cut };
+use dbscan;
+use weighting::{ Weighting, DocumentFrequencies };
+use tokenize::{ Token, TokenKind, Tokenizer, Pipeline };
+use channel;
+use channel::{ Channel, ChannelTables, Mix };
+use drift::{ Period, Window, Step };
 use crossbeam;
 
-/// An incredibly useful macro. It will check an expression of type Error<T, Z>,
-/// if it is Err(err) it will RETURN in whatever function it is placed in with
-/// error, otherwise it will continue in the function. This cuts down on the amount
-/// of error checking code that will clog things up.
-/// Optionally, it will also store the value x in $v (e.g. if it is Ok(x), $v = x).
-macro_rules! check {
-    ( $e:expr ) => (
-    match $e {
-        Ok(_) => {},
-    Err(e) => return Err(e)
-        }
-    );
-    ( $e:expr, $v:ident) => (
-        match $e {
-            Ok(r) => $v = r,
-            Err(e) => return Err(e)
-        }
-    )
-}
-
 const MAX_THREADS: i32 = 8;
 
 /// A structure representing the occurences of words from a collection
 /// of tweets. The tweets are not stored.
+/// Words are kept as their ids in a Vocabulary, sorted, with the number of times each one
+/// showed up in `counts`, so two tables can be compared by walking both of them at once.
 #[derive(PartialEq, Eq)]
 pub struct FrequencyTable {
-    /// The actual frequency table, word -> occurences
-    table: TwitHash<String, usize>,
+    /// The ids of the words in the table, smallest first
+    ids: Vec<u32>,
+    /// counts[i] is the number of occurences of ids[i]
+    counts: Vec<usize>,
     /// The total number of words (non-unique) that have been entered
-    word_count: usize
+    word_count: usize,
+    /// surfaces[i] is the id of the word ids[i] was stemmed from, to show instead of the stem.
+    /// If it was stemmed from a few different words, it's the shortest one, which is usually
+    /// the plainest ("vote", not "voting"). This is empty until something stemmed is added
+    /// (and in tables from before there was stemming), which means every word is itself.
+    surfaces: Vec<u32>
 }
 
-impl Serialize for FrequencyTable {
-    type DeserializeOutput = Self;
+record!(FrequencyTable, version 2 { word_count: usize, ids: Vec<u32>, counts: Vec<usize>, surfaces: Vec<u32> });
 
-    fn serialize(&self, to: &mut Write) -> Result<(), io::Error> {
-        check!((self.word_count as u64).serialize(to));
-        check!((self.table.count as u64).serialize(to));
-        let keys = self.table.keys();
-        for key in keys {
-            check!(key.serialize(to));
-            check!((*(self.table.get(key).unwrap()) as u64).serialize(to));
-        }
-        Ok(())
+fn magnitude(s: &str) -> f64 {
+    let mut acc = 0.0;
+    for i in s.as_bytes().iter() {
+        acc += (i*i) as f64;
     }
+    acc.sqrt()
+}
 
-    fn deserialize(from: &mut Read) -> Result<Self::DeserializeOutput, io::Error> {
-        let word_count;
-        check!(u64::deserialize(from), word_count);
-        let num_pairs;
-        check!(u64::deserialize(from), num_pairs);
+impl FrequencyTable {
 
-        let mut table = TwitHash::new();
+    /// Creates a new empty FrequencyTable
+    pub fn new() -> Self {
+        FrequencyTable {
+            ids: vec![],
+            counts: vec![],
+            word_count: 0usize,
+            surfaces: vec![]
+        }
+    }
 
-        for _ in 0..num_pairs {
-            let key;
-            check!(String::deserialize(from), key);
-            let value;
-            check!(u64::deserialize(from), value);
-            table.insert(key, value as usize);
+    /// Adds the words from a tweet to the FrequencyTable.
+    /// It splits the text into tokens with the tokenizer and increments the
+    /// matching value in the table (if it exists, otherwise it is initialized
+    /// to 1). Words that aren't in the vocabulary yet are added to it.
+    pub fn add_tweet(&mut self, t: &Tweet, tokenizer: &Tokenizer, vocab: &mut Vocabulary) {
+        for token in tokenizer.tokenize(&t.text) {
+            self.add_token(&token, vocab);
         }
+    }
 
-        Ok(FrequencyTable {
-            table: table,
-            word_count: word_count as usize
-        })
+    /// Adds one occurence of a token, and the word it was stemmed from if it was.
+    pub fn add_token(&mut self, token: &Token, vocab: &mut Vocabulary) {
+        let id = vocab.intern(&token.text);
+        match token.surface {
+            Some(ref surface) => {
+                let surface = vocab.intern(surface);
+                self.add_as(id, surface, 1, vocab);
+            },
+            None => self.add(id, 1)
+        }
     }
 
-    fn serialized_len(&self) -> u64 {
-        let mut count = 16;
+    /// Adds n occurences of the word with the given id.
+    pub fn add(&mut self, id: u32, n: usize) {
+        match self.ids.binary_search(&id) {
+            Ok(i) => self.counts[i] += n,
+            Err(i) => {
+                self.ids.insert(i, id);
+                self.counts.insert(i, n);
+                if !self.surfaces.is_empty() {
+                    self.surfaces.insert(i, id);
+                }
+            }
+        }
+        self.word_count += n;
+    }
 
-        for key in self.table.keys() {
-            count += 16 + key.len();
+    /// Adds n occurences of the stem with the given id, which came from the word `surface`.
+    pub fn add_as(&mut self, id: u32, surface: u32, n: usize, vocab: &Vocabulary) {
+        if surface != id && self.surfaces.is_empty() {
+            self.surfaces = self.ids.clone();
+        }
+        if self.surfaces.is_empty() {
+            return self.add(id, n)
+        }
+        match self.ids.binary_search(&id) {
+            Ok(i) => {
+                self.counts[i] += n;
+                let (new, old) = (vocab.word(surface).unwrap_or(""), vocab.word(self.surfaces[i]).unwrap_or(""));
+                if (new.len(), new) < (old.len(), old) {
+                    self.surfaces[i] = surface;
+                }
+            },
+            Err(i) => {
+                self.ids.insert(i, id);
+                self.counts.insert(i, n);
+                self.surfaces.insert(i, surface);
+            }
         }
+        self.word_count += n;
+    }
 
-        count as u64
+    /// The id of the word to show for the word with the given id (see `surfaces`).
+    pub fn surface(&self, id: u32) -> u32 {
+        match self.ids.binary_search(&id) {
+            Ok(i) if !self.surfaces.is_empty() => self.surfaces[i],
+            _ => id
+        }
     }
-}
 
-/// A helper method that creates a set-type union out of two vectors,
-/// consuming both of them.
-fn union<T: PartialEq>(mut v1: Vec<T>, v2: Vec<T>) -> Vec<T> {
-    for element in v2.into_iter() {
-        if !v1.contains(&element) {
-            v1.push(element);
+    /// Takes a word out of the table, returning how many times it was in it.
+    pub fn remove(&mut self, id: u32) -> usize {
+        match self.ids.binary_search(&id) {
+            Ok(i) => {
+                self.ids.remove(i);
+                if !self.surfaces.is_empty() {
+                    self.surfaces.remove(i);
+                }
+                let n = self.counts.remove(i);
+                self.word_count -= n;
+                n
+            },
+            Err(_) => 0
         }
     }
-    v1
-}
 
-fn intersection<T: PartialEq>(mut v1: Vec<T>, v2: Vec<T>) -> Vec<T> {
-    v1.retain(|x| v2.contains(x));
-    v1
-}
+    /// The number of occurences of the word with the given id.
+    pub fn count(&self, id: u32) -> usize {
+        match self.ids.binary_search(&id) {
+            Ok(i) => self.counts[i],
+            Err(_) => 0
+        }
+    }
 
-fn magnitude(s: &str) -> f64 {
-    let mut acc = 0.0;
-    for i in s.as_bytes().iter() {
-        acc += (i*i) as f64;
+    /// The number of different words in the table.
+    pub fn len(&self) -> usize {
+        self.ids.len()
     }
-    acc.sqrt()
-}
 
-impl FrequencyTable {
+    /// The number of words in the table, counting every occurence.
+    pub fn word_count(&self) -> usize {
+        self.word_count
+    }
 
-    /// Creates a new empty FrequencyTable
-    pub fn new() -> Self {
-        FrequencyTable {
-            table: TwitHash::new(),
-            word_count: 0usize
+    /// Every (id, occurences) pair, smallest id first.
+    pub fn iter(&self) -> Zip<slice::Iter<u32>, slice::Iter<usize>> {
+        self.ids.iter().zip(self.counts.iter())
+    }
+
+    /// The table as a vector of word weights, for comparing and clustering. Words that
+    /// weigh nothing are left out.
+    pub fn vector(&self, weighting: Weighting, df: &DocumentFrequencies) -> SparseVector {
+        let (mut ids, mut weights) = (vec![], vec![]);
+        for (&id, &count) in self.iter() {
+            let w = df.weight(weighting, id, count, self.word_count);
+            if w != 0.0 {
+                ids.push(id);
+                weights.push(w);
+            }
         }
+        SparseVector::new(ids, weights)
     }
 
-    /// Adds the words from a tweet to the FrequencyTable.
-    /// It essentially splits by whitespace and adds increments the
-    /// matching value in the table (if it exists, otherwise it is initialized
-    /// to 1)
-    pub fn add_tweet(&mut self, t: &Tweet) {
-        for w in t.stripped_text.split_whitespace() {
-            // not sure if I want this here or not
-            //if w.len() < 2 { continue };
-            let v =
-                if let Some(x) = self.table.get_mut(&w.to_string()) {
-                    *x
-                } else {
-                    0
-                };
-            self.table.insert(w.to_string(), v + 1);
-        }
-    }
-
-    /// Returns log2 of the number of occurences of the given word.
-    pub fn tf(&self, word: &String) -> f64 {
-        match self.table.get(word) {
-            Some(x) => {
-                (*x as f64).log2() // + 1 maybe?
-            },
-            None => 0.0,
+    /// Returns 1 + log2 of the number of occurences of the given word, or 0 if there aren't
+    /// any. This is the tf part of TF-IDF.
+    pub fn tf(&self, id: u32) -> f64 {
+        match self.count(id) {
+            0 => 0.0,
+            x => 1.0 + (x as f64).log2()
         }
     }
 
-    pub fn freq(&self, word: &String) -> usize {
-        match self.table.get(word) {
-            Some(x) => {
-                *x
-            },
-            None => 1usize,     // Divide by zero prevention
+    pub fn freq(&self, id: u32) -> usize {
+        match self.count(id) {
+            0 => 1usize,     // Divide by zero prevention
+            x => x
         }
     }
 
     /// Compares two FrequencyTables and returns a f64 which represents their
     /// difference. The closer to 1, the more similar.
-    /// The algorithm used is a spin off of cosine similarity.
-    pub fn compare(&self, other: &Self) -> f64 {
-        /*
-        let v = union(self.table.keys(), other.table.keys());
-        let mut difference = 0f64;
-        for word in v.into_iter() {
-            let tf1 = self.tf(word);
-            let f1 = self.freq(word) as f64;
-            let tf2 = other.tf(word);
-            let f2 = self.freq(word) as f64;
-            if tf1 != 0.0 && tf2 != 0.0 {
-                difference += (tf1 / f2 - tf2 / f1).abs();
-            }
-        }
-        difference
-        */
-        let num = self.mul(other);
-        let denom = self.abs() * other.abs();
+    /// It is the cosine similarity of their vectors, weighed with the given weighting.
+    pub fn compare(&self, other: &Self, weighting: Weighting, df: &DocumentFrequencies) -> f64 {
+        let (a, b) = (self.vector(weighting, df), other.vector(weighting, df));
+        let denom = a.norm() * b.norm();
         if denom == 0.0 {
             0.0
         } else {
-            num / denom
+            a.dot(&b) / denom
         }
     }
 
     /// Returns a vector to be used to display the top 10 most
     /// frequent words using a GuiSelection.
-    pub fn display(&self) -> Vec<String> {
-        let mut disp = Vec::new();
-        for key in self.table.keys().into_iter() {
-            disp.push((self.table.get(key).unwrap(), key));
-        }
+    pub fn display(&self, vocab: &Vocabulary) -> Vec<String> {
+        let mut disp = self.iter().map(|(&id, freq)| (freq, vocab.word(self.surface(id)).unwrap_or("?"))).collect::<Vec<_>>();
         disp.sort_by(|a, b| {
             if a.0 > b.0        { Ordering::Less }
             else if a.0 < b.0   { Ordering::Greater }
             else                { Ordering::Equal }
         });
-        let size = if 10 >= self.table.count { self.table.count } else { 10 };
+        let size = if 10 >= self.len() { self.len() } else { 10 };
         let mut ret = Vec::with_capacity(size);
         for i in 0..size {
             let (freq, key) = disp[i];
@@ -214,15 +240,11 @@ impl FrequencyTable {
     }
 
     pub fn sum(&self) -> f64 {
-        let mut acc = 0.0;
-        for key in self.table.keys() {
-            acc += *self.table.get(key).unwrap() as f64;
-        }
-        acc
+        self.counts.iter().map(|&freq| freq as f64).sum()
     }
 
     pub fn angle(&self) -> f64 {
-        let len = self.table.count as f64;
+        let len = self.len() as f64;
         let x = ((1.0 + len) / self.abs() as f64).acos();
         if x == f64::NAN {
             3.1415926
@@ -232,70 +254,79 @@ impl FrequencyTable {
     }
 
     pub fn abs(&self) -> f64 {
-        let mut acc = 0.0;
-        for key in self.table.keys() {
-            let freq = *self.table.get(key).unwrap();
-            acc += (freq * freq) as f64;
-        }
-        acc.sqrt()
+        self.counts.iter().map(|&freq| (freq * freq) as f64).sum::<f64>().sqrt()
     }
 
+    /// The dot product of the two tables. Both are sorted by id, so this is a merge: only
+    /// the words in both of them count.
     pub fn mul(&self, rhs: &Self) -> f64 {
-        let keys = intersection(self.table.keys(), rhs.table.keys());
+        let (mut i, mut j) = (0, 0);
         let mut acc = 0.0;
-        for key in keys.iter() {
-            acc += (self.table.get(key).unwrap() * rhs.table.get(key).unwrap()) as f64;
+        while i < self.ids.len() && j < rhs.ids.len() {
+            if self.ids[i] < rhs.ids[j] {
+                i += 1;
+            } else if self.ids[i] > rhs.ids[j] {
+                j += 1;
+            } else {
+                acc += (self.counts[i] * rhs.counts[j]) as f64;
+                i += 1;
+                j += 1;
+            }
         }
         acc
     }
 }
 
-// Lazy static will compile the regular expressions once at the first use,
-// rather than every time it gets called. The lazy static macro has to be used
-// because rust doesn't like static / global variables.
-lazy_static! {
-    /// Roughly recognizes URLs. NOTE: this regex is awful.
-    static ref URL: Regex = Regex::new(r#"(https://|http://)?[a-zA-Z\-]+\.([a-zA-Z]{2,3})(/\S+)?"#).unwrap();
-    /// Recognizes html escaped characters, e.g. &amp;. Twitter likes to html
-    /// escape some characters even though it's response is in json
-    static ref ESCAPED_HTML: Regex = Regex::new(r#"&.+;"#).unwrap();
-}
-
-/// Strips text of all non alpha-numeric-emoji-or-@-#-'-_ characters.
-fn strip_text(x: &str) -> String {
-    let mut s = String::with_capacity(x.len());
-    let z = URL.replace_all(x, "");
-    let y = ESCAPED_HTML.replace_all(&z, "");
-    let ret = y.chars()
-        .fold(&mut s, |mut acc, c| {
-            match c {
-                'A'...'Z' | 'a'...'z' | '0'...'9' | '‼' ... '🧀' |
-                '_' | '@' | '#' | '\'' | ' ' => acc.push(c),
-                _ => {},
-            }
-            acc
-        }).chars()
-        .flat_map(char::to_lowercase)
-        .collect::<String>();
-    ret
-}
-
 /// A struct that contains a tweet, the handle of the person who tweeted it,
-/// and the tweet itself that has been passed through the strip_text function.
-/// This is mostly to avoid calling strip_text more than once per tweet,
-/// otherwise the Tweet struct in egg_mode would be fine.
+/// and the words of the tweet as the default tokenizer sees them (FrequencyTables
+/// tokenize `text` themselves, with whatever tokenizer they're given, so this is
+/// mostly for looking at), along with when it was tweeted, how much it was retweeted and
+/// liked, what it was replying to, quoting or retweeting, and what was in it.
+/// Tweets that were cached before everything after handle was kept read those back as
+/// defaults: no created_at, no counts, not a reply, quote or retweet, an empty lang and no
+/// entities. Twitter always gives a lang ("und" if it can't tell), so an empty one means
+/// the rest isn't known either.
+#[derive(PartialEq, Default, Debug)]
 pub struct Tweet {
     //pub tweet: tweet::Tweet,
     pub stripped_text: String,
     pub text: String,
     pub id: u64,
-    pub handle: String
+    pub handle: String,
+    /// When it was tweeted, in seconds since 1970 (UTC)
+    pub created_at: Option<i64>,
+    pub retweet_count: u32,
+    pub favorite_count: u32,
+    /// The id of the tweet this is a reply to
+    pub reply_to: Option<u64>,
+    /// The id of the tweet this quotes
+    pub quoted: Option<u64>,
+    /// The id of the tweet this is a retweet of
+    pub retweet_of: Option<u64>,
+    /// The language twitter thinks it's in, like "en"
+    pub lang: String,
+    pub entities: Entities
+}
+
+/// The hashtags, mentions and links twitter found in a tweet.
+#[derive(PartialEq, Default, Debug)]
+pub struct Entities {
+    /// Without the #
+    pub hashtags: Vec<String>,
+    /// Screen names, without the @
+    pub mentions: Vec<String>,
+    /// Where the links go, not the t.co links
+    pub urls: Vec<String>
 }
 
+record!(Entities, version 1 { hashtags: Vec<String>, mentions: Vec<String>, urls: Vec<String> });
+
 impl Tweet {
     /// A new tweet from an egg_mode tweet. This consumes the tweet::Tweet
     pub fn new(tweet: tweet::Tweet) -> Tweet {
-        let stripped_text = strip_text(&tweet.text);
+        let text = tweet.text;
+        let stripped_text = Pipeline::default().tokenize(&text).into_iter()
+            .map(|t| t.text).collect::<Vec<_>>().join(" ");
         let user = {
             if let Some(ref x) = tweet.user {
                 x.screen_name.clone()
@@ -303,102 +334,38 @@ impl Tweet {
                 "".to_string()
             }
         };
+        let entities = Entities {
+            hashtags: tweet.entities.hashtags.iter().map(|h| h.text.clone()).collect(),
+            mentions: tweet.entities.user_mentions.iter().map(|m| m.screen_name.clone()).collect(),
+            urls: tweet.entities.urls.iter().map(|u| u.expanded_url.clone()).collect()
+        };
         Tweet {
             stripped_text: stripped_text,
-            text: tweet.text,
+            text: text,
             handle: user,
-            id: tweet.id
+            id: tweet.id,
+            created_at: Some(tweet.created_at.timestamp()),
+            // Twitter's counts are never negative, but they come as i32s
+            retweet_count: cmp::max(tweet.retweet_count, 0) as u32,
+            favorite_count: cmp::max(tweet.favorite_count, 0) as u32,
+            reply_to: tweet.in_reply_to_status_id,
+            quoted: tweet.quoted_status_id,
+            retweet_of: tweet.retweeted_status.as_ref().map(|t| t.id),
+            lang: tweet.lang,
+            entities: entities
         }
     }
 }
 
-impl Serialize for Tweet {
-    type DeserializeOutput = Tweet;
-    fn serialize(&self, to: &mut Write) -> Result<(), io::Error> {
-        if let Err(e) = self.id.serialize(to) {
-            return Err(e);
-        };
-        if let Err(e) = self.stripped_text.serialize(to) {
-            return Err(e);
-        };
-        if let Err(e) = self.text.serialize(to) {
-            return Err(e);
-        };
-        if let Err(e) = self.handle.serialize(to) {
-            return Err(e);
-        };
-        Ok(())
-    }
-    fn deserialize(from: &mut Read) -> Result<Self, io::Error> {
-        let id = u64::deserialize(from);
-        let stripped_text = String::deserialize(from);
-        let text = String::deserialize(from);
-        let handle = String::deserialize(from);
-        if id.is_err() || stripped_text.is_err() || text.is_err() || handle.is_err() {
-            if let Err(e) = id {
-                return Err(e);
-            }
-            if let Err(e) = stripped_text {
-                return Err(e);
-            }
-            if let Err(e) = text {
-                return Err(e);
-            }
-            if let Err(e) = handle {
-                return Err(e);
-            }
-        }
-        Ok(Tweet {
-            stripped_text: stripped_text.unwrap(),
-            id: id.unwrap(),
-            text: text.unwrap(),
-            handle: handle.unwrap()
-        })
-    }
-    fn serialized_len(&self) -> u64 {
-        self.stripped_text.serialized_len() +
-        self.text.serialized_len() +
-        self.id.serialized_len() +
-        self.handle.serialized_len()
-    }
-}
+record!(Tweet, version 1 { id: u64, stripped_text: String, text: String, handle: String,
+                           created_at: Option<i64>, retweet_count: u32, favorite_count: u32,
+                           reply_to: Option<u64>, quoted: Option<u64>, retweet_of: Option<u64>,
+                           lang: String, entities: Entities });
 
+#[derive(PartialEq)]
 pub struct TweetList(pub Vec<Tweet>);
 
-impl Serialize for TweetList {
-    type DeserializeOutput = TweetList;
-    fn serialize(&self, to: &mut Write) -> Result<(), io::Error> {
-        let TweetList(ref x) = *self;
-        let _ = (x.len() as u64).serialize(to);
-        for i in x {
-            let _ = i.serialize(to);
-        }
-        Ok(())
-    }
-    fn deserialize(from: &mut Read) -> Result<Self, io::Error> {
-        let mut r = Vec::new();
-        let len = u64::deserialize(from);
-        if len.is_err() {
-            return Err(len.err().unwrap());
-        }
-        for _ in 0..len.unwrap() {
-            let t = Tweet::deserialize(from);
-            if t.is_err() {
-                return Err(t.err().unwrap());
-            }
-            r.push(t.unwrap());
-        }
-        Ok(TweetList(r))
-    }
-    fn serialized_len(&self) -> u64 {
-        let TweetList(ref x) = *self;
-        let mut len = 8u64;
-        for i in 0..x.len() {
-            len += x[i].serialized_len();
-        }
-        len
-    }
-}
+serializable!(TweetList(0: Vec<Tweet>));
 
 #[allow(non_snake_case)]
 #[allow(dead_code)]
@@ -436,98 +403,463 @@ impl PolarCoord {
     }
 }
 
+/// The number of words that are kept to describe a group.
+const TOP_WORDS: usize = 5;
+/// How many times a phrase has to show up to be a collocation. PMI alone thinks a pair of
+/// words that are only ever seen once, together, is the best phrase there is.
+const MIN_PHRASE_COUNT: usize = 3;
+
+/// Some users that tweet about the same things.
+pub struct Group {
+    pub members: Vec<String>,
+    /// The words that weigh the most in the middle of the group, heaviest first
+    pub top_words: Vec<(String, f64)>
+}
+
+/// The users split up into groups.
+pub struct Grouping {
+    /// Every user that was grouped
+    pub keys: Vec<String>,
+    /// assignments[i] is the index of the group keys[i] is in
+    pub assignments: Vec<usize>,
+    pub groups: Vec<Group>,
+    /// Users that aren't like anyone else, and so aren't in keys or any group. Only DBSCAN
+    /// leaves anyone out.
+    pub outliers: Vec<String>,
+    /// How good the grouping is (outliers aren't counted)
+    pub scores: Scores
+}
+
+impl Grouping {
+    /// The members of every group, leaving out groups that ended up empty.
+    pub fn handles(&self) -> Vec<Vec<String>> {
+        self.groups.iter().filter(|g| !g.members.is_empty()).map(|g| g.members.clone()).collect()
+    }
+}
+
 /// A helper struct used to process the tweets.
 pub struct TweetProcessor {
     pub map: TwitHash<String, FrequencyTable>,
+    /// The ids of every word in the tables in map
+    pub vocab: Vocabulary,
+    /// How many of the tables in map each word is in. It isn't kept up to date as tweets are
+    /// processed, count_documents has to be called once they all are.
+    pub df: DocumentFrequencies,
+    /// How words are weighed when users are compared or clustered
+    pub weighting: Weighting,
+    /// How tweets are split up into the words that get counted
+    pub tokenizer: Pipeline,
+    /// The tables of the channels other than words (see channel.rs), in the order of
+    /// channel::OTHERS. Users don't have to have a table in any of them.
+    pub channels: Vec<ChannelTables>,
+    /// How much each channel counts when users are compared or clustered
+    pub mix: Mix,
+    /// Every user's word tables, one for each window (see drift.rs) they tweeted in, oldest
+    /// first. These are only filled in by process_windows.
+    pub windows: TwitHash<String, Vec<Window>>
 }
 
 impl TweetProcessor {
 
     /// Creates a new empty TweetProcessor
     pub fn new() -> TweetProcessor {
-        TweetProcessor { map: TwitHash::new() }
+        TweetProcessor {
+            map: TwitHash::new(),
+            vocab: Vocabulary::new(),
+            df: DocumentFrequencies::new(),
+            weighting: Weighting::Raw,
+            tokenizer: Pipeline::default(),
+            channels: channel::OTHERS.iter().map(|_| ChannelTables::new()).collect(),
+            mix: Mix::default(),
+            windows: TwitHash::new()
+        }
     }
 
-    pub fn k_means_groups(&self, k: usize, itters: usize) -> Vec<Vec<String>> {
-        let mut keys = self.map.keys();
-        let mut groups: Vec<f64> = Vec::with_capacity(k);
+    /// Counts how many of the tables in map each word is in, replacing df, and does the same
+    /// for every other channel.
+    pub fn count_documents(&mut self) {
+        let mut df = DocumentFrequencies::new();
+        for table in self.map.values() {
+            df.add(table);
+        }
+        self.df = df;
+        for channel in self.channels.iter_mut() {
+            channel.count_documents();
+        }
+    }
 
-        for i in 0..k {
-            let t = self.map.get(keys[i]).unwrap();
-            groups.push(t.abs());
+    /// A user's table in a channel, if they have one.
+    pub fn table(&self, channel: Channel, key: &String) -> Option<&FrequencyTable> {
+        match channel.other_index() {
+            Some(i) => self.channels[i].map.get(key),
+            None => self.map.get(key)
         }
+    }
 
-        let mut count = 0;
-        let mut members = vec![0i32; k];
-        let mut averages: Vec<f64> = Vec::with_capacity(k);
-        let mut abs_hash = TwitHash::new();
+    /// The document frequencies of a channel.
+    fn channel_df(&self, channel: Channel) -> &DocumentFrequencies {
+        match channel.other_index() {
+            Some(i) => &self.channels[i].df,
+            None => &self.df
+        }
+    }
 
-        for key in keys.iter() {
-            abs_hash.insert(key, unsafe { mem::transmute::<f64, u64>(self.map.get(&key).unwrap().abs()) });
+    /// A user's vector, made of every channel in the mix (see channel.rs). Each channel has
+    /// a norm of the square root of its share of the mix, or 0 if the user has nothing in it.
+    pub fn vector(&self, key: &String) -> SparseVector {
+        let mut pairs = vec![];
+        for (channel, share) in self.mix.normalized() {
+            if let Some(table) = self.table(channel, key) {
+                let v = table.vector(self.weighting, self.channel_df(channel));
+                let norm = v.norm();
+                if norm != 0.0 {
+                    pairs.extend(v.iter().map(|(id, w)| (id, w * share.sqrt() / norm)));
+                }
+            }
         }
-        loop {
-            averages.drain(..);
-            for i in 0..k {
-                averages.push(0.0);
-                members[i] = 0;
+        // Channels don't share words (a hashtag starts with #, a domain has dots in it), so
+        // this never has to add anything up
+        pairs.sort_by_key(|&(id, _)| id);
+        pairs.dedup_by_key(|&mut (id, _)| id);
+        let (ids, weights) = pairs.into_iter().unzip();
+        SparseVector::new(ids, weights)
+    }
+
+    /// Splits the users into (up to) k groups by what they tweet about, using k-means over
+    /// their word vectors with cosine distance. Stops after itters rounds if it hasn't
+    /// settled down by then. It is run `restarts` times, keeping the best, and the same seed
+    /// always gives the same groups.
+    pub fn k_means_groups(&self, k: usize, itters: usize, restarts: usize, seed: u64) -> Grouping {
+        let keys = self.sorted_keys();
+        let points = self.points(&keys);
+        let clustering = cluster::k_means(&points, k, itters, restarts, seed);
+        log!("k-means took {} iterations, error {:.3}", clustering.iterations, clustering.error);
+        self.grouping(keys, &points, clustering)
+    }
+
+    /// Runs k_means_groups for every k from k_min to k_max, and keeps the one `how` likes
+    /// best. Also returns the scores of every k that was tried.
+    pub fn auto_k_groups(&self, k_min: usize, k_max: usize, itters: usize, restarts: usize, seed: u64, how: KChoice) -> (Grouping, Vec<(usize, Scores)>) {
+        let keys = self.sorted_keys();
+        let points = self.points(&keys);
+        let k_min = if k_min == 0 { 1 } else { k_min };
+        let k_max = if k_max < k_min { k_min } else { k_max };
+        let mut runs = vec![];
+        for k in k_min..k_max + 1 {
+            let clustering = cluster::k_means(&points, k, itters, restarts, seed);
+            let scores = cluster::scores(&points, &clustering);
+            runs.push((k, scores, clustering));
+        }
+        let scan = runs.iter().map(|&(k, s, _)| (k, s)).collect::<Vec<_>>();
+        // k_max is at least k_min, so there's always something to pick from
+        let k = cluster::pick_k(&scan, how).unwrap_or(k_min);
+        let (_, _, clustering) = runs.into_iter().find(|&(x, _, _)| x == k).unwrap();
+        (self.grouping(keys, &points, clustering), scan)
+    }
+
+    /// The dendrogram of every user, made by agglomerative clustering with the given linkage.
+    /// Also returns the users, sorted, which are its leaves in the same order.
+    pub fn dendrogram(&self, linkage: Linkage) -> (Vec<String>, Dendrogram) {
+        let keys = self.sorted_keys();
+        let dendrogram = hac::agglomerate(&self.similarities(&keys), linkage);
+        (keys, dendrogram)
+    }
+
+    /// Cuts a dendrogram from `dendrogram` into groups.
+    pub fn cut_groups(&self, keys: Vec<String>, dendrogram: &Dendrogram, cut: Cut) -> Grouping {
+        let points = self.points(&keys);
+        let clustering = cluster::clustering_of(&points, dendrogram.cut(cut));
+        self.grouping(keys, &points, clustering)
+    }
+
+    /// Cuts a dendrogram into every number of groups from k_min to k_max, and keeps the one
+    /// `how` likes best, like auto_k_groups. Also returns the scores of every k that was tried.
+    pub fn auto_cut_groups(&self, keys: Vec<String>, dendrogram: &Dendrogram, k_min: usize, k_max: usize, how: KChoice) -> (Grouping, Vec<(usize, Scores)>) {
+        let points = self.points(&keys);
+        let k_min = if k_min == 0 { 1 } else { k_min };
+        let k_max = if k_max < k_min { k_min } else { k_max };
+        let scan = (k_min..k_max + 1).map(|k| {
+            let clustering = cluster::clustering_of(&points, dendrogram.cut(Cut::Groups(k)));
+            (k, cluster::scores(&points, &clustering))
+        }).collect::<Vec<_>>();
+        // k_max is at least k_min, so there's always something to pick from
+        let k = cluster::pick_k(&scan, how).unwrap_or(k_min);
+        let clustering = cluster::clustering_of(&points, dendrogram.cut(Cut::Groups(k)));
+        (self.grouping(keys, &points, clustering), scan)
+    }
+
+    /// Groups the users with DBSCAN: users with at least min_points users (themselves
+    /// included) within a cosine distance of eps are the middle of a group, and anyone that
+    /// isn't close to one of those is an outlier.
+    pub fn dbscan_groups(&self, eps: f64, min_points: usize) -> Grouping {
+        let keys = self.sorted_keys();
+        let labels = dbscan::dbscan(&self.similarities(&keys), eps, min_points);
+        let (mut grouped, mut assignments, mut outliers) = (vec![], vec![], vec![]);
+        for (key, label) in keys.into_iter().zip(labels.into_iter()) {
+            match label {
+                Some(g) => {
+                    grouped.push(key);
+                    assignments.push(g);
+                },
+                None => outliers.push(key)
             }
-            for key in keys.iter() {
-                let p = unsafe { mem::transmute::<u64, f64>(*abs_hash.get(&key).unwrap()) };
-                let mut closest_dist = (groups[0] - p).abs();
-                let mut val = 0;
-                for i in 1..k {
-                    let dist = (groups[i] - p).abs();
-                    if closest_dist > (groups[i] - p).abs() {
-                        closest_dist = dist;
-                        val = i;
-                    }
-                }
-                members[val] += 1;
-                averages[val] += p;
+        }
+        let points = self.points(&grouped);
+        let clustering = cluster::clustering_of(&points, assignments);
+        let mut grouping = self.grouping(grouped, &points, clustering);
+        grouping.outliers = outliers;
+        grouping
+    }
+
+    /// How similar every pair of users in keys is, by FrequencyTable::compare.
+    /// similarities[i][j] is the similarity of keys[i] and keys[j].
+    pub fn similarities(&self, keys: &[String]) -> Vec<Vec<f64>> {
+        // The same as compare, without weighing every table n times
+        let points = cluster::normalized(&self.points(keys));
+        let mut sims = vec![vec![0.0; keys.len()]; keys.len()];
+        for i in 0..keys.len() {
+            sims[i][i] = 1.0;
+            for j in i + 1..keys.len() {
+                let x = points[i].dot(&points[j]);
+                sims[i][j] = x;
+                sims[j][i] = x;
             }
+        }
+        sims
+    }
+
+    /// The vector of every user in keys, in the same order.
+    fn points(&self, keys: &[String]) -> Vec<SparseVector> {
+        keys.iter().map(|key| self.vector(key)).collect()
+    }
 
-            let mut delta = 0.0;
+    /// Scores a clustering of the users in keys.
+    pub fn scores(&self, keys: &[String], clustering: &Clustering) -> Scores {
+        cluster::scores(&self.points(keys), clustering)
+    }
 
-            for i in 0..k {
-                averages[i] = averages[i] / members[i] as f64;
-                delta += (averages[i] - groups[i]).abs();
-                groups[i] = averages[i];
+    /// Every user, sorted, so that nothing depends on the order of the hash table.
+    fn sorted_keys(&self) -> Vec<String> {
+        let mut keys = self.map.keys().into_iter().cloned().collect::<Vec<String>>();
+        keys.sort();
+        keys
+    }
+
+    /// Puts names (and words) to the points and centroids that came out of a clustering.
+    fn grouping(&self, keys: Vec<String>, points: &[SparseVector], clustering: Clustering) -> Grouping {
+        let scores = cluster::scores(points, &clustering);
+        let groups = clustering.members().into_iter().zip(clustering.centroids.iter()).map(|(members, centroid)| {
+            Group {
+                members: members.into_iter().map(|i| keys[i].clone()).collect(),
+                top_words: centroid.top(TOP_WORDS).into_iter()
+                    .map(|(id, w)| (self.readable(id).to_string(), w))
+                    .collect()
             }
+        }).collect();
+        Grouping {
+            keys: keys,
+            assignments: clustering.assignments,
+            groups: groups,
+            outliers: vec![],
+            scores: scores
+        }
+    }
 
-            if delta < 0.01 { break; }
-            log!("Itter {}", count);
-            count += 1;
-            if count > itters {
-                break;
+    /// Processes every tweet. If the tokenizer has an auto-stopwords stage, the tweets are
+    /// counted once without it, the words in the most users' tables are picked as its
+    /// stopwords, and then everything is counted again from scratch. A phrases stage is
+    /// filled in the same way (after the stopwords, since they change which words are next to
+    /// each other), from the collocations.
+    pub fn process_tweets(&mut self, tweets: &[Tweet]) {
+        for t in tweets.iter() {
+            self.process_tweet(t);
+        }
+        loop {
+            if let Some(n) = self.tokenizer.unpicked_stopwords() {
+                self.count_documents();
+                let words = self.most_common_words(n);
+                log!("Picked {} stopwords: {}", words.len(), words.join(", "));
+                self.tokenizer.pick_stopwords(words);
+            } else if let Some(pmi) = self.tokenizer.unpicked_phrases() {
+                let phrases = self.collocations(pmi);
+                log!("Picked {} phrases: {}", phrases.len(), phrases.join(", "));
+                self.tokenizer.pick_phrases(phrases);
+            } else {
+                break
+            }
+            self.map = TwitHash::new();
+            self.channels = channel::OTHERS.iter().map(|_| ChannelTables::new()).collect();
+            self.vocab = Vocabulary::new();
+            for t in tweets.iter() {
+                self.process_tweet(t);
+            }
+        }
+        self.count_documents();
+    }
+
+    /// Every phrase in the tables that shows up at least MIN_PHRASE_COUNT times in all of
+    /// them put together, and has a PMI of at least min_pmi (see tokenize.rs).
+    pub fn collocations(&self, min_pmi: f64) -> Vec<String> {
+        let mut totals = vec![0usize; self.vocab.len()];
+        for (_, table) in self.map.iter() {
+            for (&id, &count) in table.iter() {
+                totals[id as usize] += count;
             }
         }
+        let is_phrase = |id: usize| self.vocab.word(id as u32).map(|w| w.contains(' ')).unwrap_or(false);
+        let words = (0..totals.len()).filter(|&id| !is_phrase(id)).map(|id| totals[id]).sum::<usize>() as f64;
+        let log_p = |count: usize| (count as f64 / words).log2();
+        let mut phrases = vec![];
+        for id in (0..totals.len()).filter(|&id| is_phrase(id) && totals[id] >= MIN_PHRASE_COUNT) {
+            let phrase = self.vocab.word(id as u32).unwrap();
+            let mut pmi = log_p(totals[id]);
+            for word in phrase.split(' ') {
+                // A word that never got counted on its own makes the phrase look infinitely
+                // likely, so it's treated like it showed up as often as the phrase
+                let count = self.vocab.id(word).map(|w| totals[w as usize]).unwrap_or(0);
+                pmi -= log_p(cmp::max(count, totals[id]));
+            }
+            if pmi >= min_pmi {
+                phrases.push(phrase.to_string());
+            }
+        }
+        phrases
+    }
 
-        let mut final_groups = vec![Vec::new(); k];
+    /// The n words (not phrases) that are in the most users' tables, by df (so
+    /// count_documents has to be up to date). Ties go to the word with the smallest id.
+    pub fn most_common_words(&self, n: usize) -> Vec<String> {
+        let mut ids = (0..self.vocab.len() as u32)
+            .filter(|&id| self.df.count(id) > 0 && !self.vocab.word(id).unwrap_or("").contains(' '))
+            .collect::<Vec<u32>>();
+        ids.sort_by(|&a, &b| self.df.count(b).cmp(&self.df.count(a)).then(a.cmp(&b)));
+        ids.into_iter().take(n).map(|id| self.vocab.word(id).unwrap_or("?").to_string()).collect()
+    }
 
-        for key in keys.iter() {
-            let p = unsafe { mem::transmute::<u64, f64>(*abs_hash.get(&key).unwrap()) };
-            let mut ind = 0;
-            let mut closest_dist = ((p - groups[0])).abs();
-            for i in 1..k {
-                let dist = (groups[i] - p).abs();
-                if closest_dist > (groups[i] - p).abs() {
-                    closest_dist = dist;
-                    ind = i;
-                }
+    /// The word to show for the word with the given id: the shortest word it was stemmed from
+    /// in any table, or the word itself if it wasn't stemmed.
+    pub fn readable(&self, id: u32) -> &str {
+        let mut best = self.vocab.word(id).unwrap_or("?");
+        let mut stemmed = false;
+        for (_, table) in self.map.iter() {
+            let surface = table.surface(id);
+            if surface == id {
+                continue
+            }
+            let word = self.vocab.word(surface).unwrap_or("?");
+            if !stemmed || (word.len(), word) < (best.len(), best) {
+                best = word;
+                stemmed = true;
+            }
+        }
+        best
+    }
+
+    /// Takes words out of every table (they stay in the vocabulary), and counts the document
+    /// frequencies again. Returns how many occurences were taken out.
+    pub fn remove_words(&mut self, words: &[String]) -> usize {
+        let ids = words.iter().filter_map(|w| self.vocab.id(w)).collect::<Vec<u32>>();
+        let mut removed = 0;
+        for (_, table) in self.map.iter_mut() {
+            for &id in ids.iter() {
+                removed += table.remove(id);
             }
-            final_groups[ind].push((*key).to_string());
         }
-        final_groups
+        self.count_documents();
+        removed
     }
 
-    /// Adds a tweet to the proper FrequencyTable (if it exists, otherwise it is
-    /// created).
+    /// Adds a tweet to the proper FrequencyTables (if they exist, otherwise they are
+    /// created), every token going into the table of its channel. Every user gets a word
+    /// table, even if it's empty, since those are the users that get grouped.
     pub fn process_tweet(&mut self, t: &Tweet) {
-        if !self.map.contains_key(&t.handle) {
-            let copy = t.handle.clone();
-            self.map.insert(copy, FrequencyTable::new());
+        self.map.entry(t.handle.clone()).or_insert_with(FrequencyTable::new);
+        let mut tokens = self.tokenizer.tokenize(&t.text);
+        // The links in the text are t.co links, which say nothing about where they go. Twitter
+        // says where in the entities, which tweets cached before they were kept don't have
+        if !t.entities.urls.is_empty() && tokens.iter().any(|token| token.kind == TokenKind::Url) {
+            tokens.retain(|token| token.kind != TokenKind::Url);
+            tokens.extend(t.entities.urls.iter().map(|url| Token::new(url, TokenKind::Url)));
+        }
+        for token in tokens {
+            let (channel, token) = match Channel::of(token) {
+                Some(x) => x,
+                None => continue
+            };
+            let map = match channel.other_index() {
+                Some(i) => &mut self.channels[i].map,
+                None => &mut self.map
+            };
+            map.entry(t.handle.clone()).or_insert_with(FrequencyTable::new).add_token(&token, &mut self.vocab);
         }
-        self.map.get_mut(&t.handle).unwrap().add_tweet(t);
+    }
+
+    /// Counts the words of every tweet into its user's table for the window of the period it
+    /// was tweeted in. Tweets without a created_at are skipped, and the number that were is
+    /// returned.
+    pub fn process_windows(&mut self, tweets: &[Tweet], period: Period) -> usize {
+        let mut skipped = 0;
+        for t in tweets.iter() {
+            let window = match t.created_at {
+                Some(time) => period.window(time),
+                None => {
+                    skipped += 1;
+                    continue
+                }
+            };
+            let windows = self.windows.entry(t.handle.clone()).or_insert_with(Vec::new);
+            let i = match windows.binary_search_by_key(&window, |w| w.window) {
+                Ok(i) => i,
+                Err(i) => {
+                    windows.insert(i, Window { window: window, table: FrequencyTable::new() });
+                    i
+                }
+            };
+            for token in self.tokenizer.tokenize(&t.text) {
+                if let Some((Channel::Words, token)) = Channel::of(token) {
+                    windows[i].table.add_token(&token, &mut self.vocab);
+                }
+            }
+        }
+        skipped
+    }
+
+    /// How a user's words changed from every window they tweeted in to the next one (see
+    /// drift.rs), with the top words that rose and fell the most at each step.
+    pub fn drift(&self, key: &String, top: usize) -> Vec<Step> {
+        let windows = match self.windows.get(key) {
+            Some(w) => w,
+            None => return vec![]
+        };
+        windows.windows(2).map(|pair| {
+            let (a, b) = (&pair[0].table, &pair[1].table);
+            let distance = a.vector(self.weighting, &self.df).cosine_distance(&b.vector(self.weighting, &self.df));
+            // How much of each window every word was, subtracted
+            let (a_len, b_len) = (cmp::max(a.word_count(), 1) as f64, cmp::max(b.word_count(), 1) as f64);
+            let mut changes = a.iter().map(|(&id, &c)| (id, -(c as f64) / a_len))
+                .chain(b.iter().map(|(&id, &c)| (id, c as f64 / b_len)))
+                .collect::<Vec<(u32, f64)>>();
+            changes.sort_by_key(|&(id, _)| id);
+            let mut merged: Vec<(u32, f64)> = vec![];
+            for (id, x) in changes.into_iter() {
+                match merged.last_mut() {
+                    Some(&mut (last, ref mut change)) if last == id => *change += x,
+                    _ => merged.push((id, x))
+                }
+            }
+            merged.sort_by(|x, y| y.1.partial_cmp(&x.1).unwrap_or(Ordering::Equal));
+            let word = |id: u32| {
+                let surface = if b.count(id) > 0 { b.surface(id) } else { a.surface(id) };
+                self.vocab.word(surface).unwrap_or("?").to_string()
+            };
+            Step {
+                from: pair[0].window,
+                to: pair[1].window,
+                distance: distance,
+                rose: merged.iter().take(top).filter(|x| x.1 > 0.0).map(|&(id, x)| (word(id), x)).collect(),
+                fell: merged.iter().rev().take(top).filter(|x| x.1 < 0.0).map(|&(id, x)| (word(id), x)).collect()
+            }
+        }).collect()
     }
 
     /// This is the similarity metric!
@@ -540,9 +872,10 @@ impl TweetProcessor {
         log_file!("Comparisons to {}:\n", s);
         let t = self.map.keys();
         let mut closest = if *s == *t[0] { t[1] } else { t[0] };
-        let mut closest_sim = self.map.get(s).unwrap().compare(self.map.get(closest).unwrap());
+        let v = self.vector(s);
+        let mut closest_sim = 1.0 - v.cosine_distance(&self.vector(closest));
         for key in t.into_iter() {
-            let x = self.map.get(key).unwrap().compare(self.map.get(s).unwrap());
+            let x = 1.0 - v.cosine_distance(&self.vector(key));
             if x == 0.0 { continue; }
             if *s == *key { continue; }
             if x > closest_sim {
@@ -553,3 +886,83 @@ impl TweetProcessor {
         closest.to_string()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ Tweet, Entities, TweetProcessor };
+    use channel::Channel;
+    use drift::Period;
+
+    fn tweet(handle: &str, text: &str, urls: &[&str]) -> Tweet {
+        Tweet {
+            stripped_text: String::new(),
+            text: text.to_string(),
+            id: 1,
+            handle: handle.to_string(),
+            created_at: None,
+            retweet_count: 0,
+            favorite_count: 0,
+            reply_to: None,
+            quoted: None,
+            retweet_of: None,
+            lang: "en".to_string(),
+            entities: Entities { hashtags: vec![], mentions: vec![], urls: urls.iter().map(|u| u.to_string()).collect() }
+        }
+    }
+
+    /// Every domain in a user's table, with its count.
+    fn domains(p: &TweetProcessor, handle: &str) -> Vec<(String, usize)> {
+        let mut domains = match p.table(Channel::Domains, &handle.to_string()) {
+            Some(table) => table.iter().map(|(&id, &n)| (p.vocab.word(id).unwrap().to_string(), n)).collect(),
+            None => vec![]
+        };
+        domains.sort();
+        domains
+    }
+
+    #[test]
+    fn test_domains_from_entities() {
+        let mut p = TweetProcessor::new();
+        let t = tweet("rep", "Read this https://t.co/abc and https://t.co/def", &["https://www.nytimes.com/a", "https://house.gov/b"]);
+        p.process_tweet(&t);
+        assert_eq!(domains(&p, "rep"), vec![("house.gov".to_string(), 1), ("nytimes.com".to_string(), 1)]);
+        // Tweets cached before entities were kept only have the t.co links
+        p.process_tweet(&tweet("old", "Read this https://t.co/abc", &[]));
+        assert_eq!(domains(&p, "old"), vec![("t.co".to_string(), 1)]);
+        // Without a URL stage, nothing is counted as a domain
+        p.tokenizer = "extract,drop=url".parse().unwrap();
+        p.process_tweet(&tweet("dropped", "Read this https://t.co/abc", &["https://house.gov/b"]));
+        assert_eq!(domains(&p, "dropped"), vec![]);
+    }
+
+    #[test]
+    fn test_drift() {
+        let mut p = TweetProcessor::new();
+        let at = |text: &str, time: Option<i64>| {
+            let mut t = tweet("rep", text, &[]);
+            t.created_at = time;
+            t
+        };
+        let day = 24 * 60 * 60;
+        // Weeks 0 (1969-12-29 to 1970-01-04) and 2, with nothing in week 1, and a tweet with no
+        // time that is skipped
+        let tweets = vec![at("vote vote", Some(0)), at("bill house", Some(4 * day - 1)),
+                          at("vote bill bill", Some(11 * day)), at("bill senate", Some(18 * day - 1)),
+                          at("lost", None)];
+        assert_eq!(p.process_windows(&tweets, Period::Week), 1);
+
+        let steps = p.drift(&"rep".to_string(), 2);
+        assert_eq!(steps.len(), 1);
+        assert_eq!((steps[0].from, steps[0].to), (0, 2));
+        // vote 2, bill 1 and house 1 against vote 1, bill 3 and senate 1
+        assert!((steps[0].distance - (1.0 - 5.0 / 66f64.sqrt())).abs() < 1e-9);
+        let words = |changes: &Vec<(String, f64)>| changes.iter().map(|&(ref w, x)| (w.clone(), (x * 100.0).round() / 100.0)).collect::<Vec<_>>();
+        assert_eq!(words(&steps[0].rose), vec![("bill".to_string(), 0.35), ("senate".to_string(), 0.2)]);
+        assert_eq!(words(&steps[0].fell), vec![("vote".to_string(), -0.3), ("house".to_string(), -0.25)]);
+
+        let steps = p.drift(&"rep".to_string(), 1);
+        assert_eq!(words(&steps[0].rose), vec![("bill".to_string(), 0.35)]);
+        assert_eq!(words(&steps[0].fell), vec![("vote".to_string(), -0.3)]);
+        assert!(p.drift(&"nobody".to_string(), 3).is_empty());
+    }
+}
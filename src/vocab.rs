@@ -0,0 +1,103 @@
+use std::io::{ Read, Write };
+use std::io;
+use codec::Serialize;
+use twithash::TwitHash;
+use storage::{ invalid_data, open_file, save_file };
+use error::Error;
+
+/*
+                            ********************************
+                            *          VOCABULARY          *
+                            ********************************
+
+    Every word that shows up in any tweet gets a u32 id, the position it has in `words`, and
+    FrequencyTables are keyed by those ids instead of by the words themselves. So each word
+    is only stored once, no matter how many tables it's in, and comparing two tables only
+    compares integers.
+
+    Ids never change once they are handed out, so the vocabulary has to be saved along with
+    anything that has ids in it (the tweet tree). It goes in its own file:
+
+        varint       -> The number of words
+        n strings    -> The words, in order of id
+        32 bits      -> CRC32C of everything before it
+
+    It is written to <path>.tmp first, and then renamed over <path>, so a crash leaves either
+    the old vocabulary or the new one.
+*/
+
+#[derive(PartialEq)]
+pub struct Vocabulary {
+    words: Vec<String>,
+    ids: TwitHash<String, u32>
+}
+
+impl Vocabulary {
+    pub fn new() -> Vocabulary {
+        Vocabulary { words: vec![], ids: TwitHash::new() }
+    }
+
+    /// The id of a word, giving it a new one if it doesn't have one yet.
+    pub fn intern(&mut self, word: &str) -> u32 {
+        if let Some(id) = self.ids.get(word) {
+            return *id
+        }
+        let id = self.words.len() as u32;
+        self.words.push(word.to_string());
+        self.ids.insert(word.to_string(), id);
+        id
+    }
+
+    /// The id of a word, if it has one.
+    pub fn id(&self, word: &str) -> Option<u32> {
+        self.ids.get(word).map(|id| *id)
+    }
+
+    /// The word with the given id.
+    pub fn word(&self, id: u32) -> Option<&str> {
+        self.words.get(id as usize).map(|w| &w[..])
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /// Reads the vocabulary saved at path.
+    pub fn open(path: &str) -> Result<Vocabulary, Error> {
+        open_file::<Vocabulary>(path, "vocabulary")
+    }
+
+    /// Saves the vocabulary to path, replacing whatever was there.
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        save_file(self, path)
+    }
+}
+
+/// Only the words are written, the ids are just their positions.
+impl Serialize for Vocabulary {
+    type DeserializeOutput = Vocabulary;
+
+    fn serialize(&self, to: &mut Write) -> Result<(), io::Error> {
+        self.words.serialize(to)
+    }
+
+    fn deserialize(from: &mut Read) -> Result<Vocabulary, io::Error> {
+        let words;
+        check!(Vec::<String>::deserialize(from), words);
+        let mut vocab = Vocabulary::new();
+        for (i, w) in words.iter().enumerate() {
+            if vocab.intern(w) as usize != i {
+                return Err(invalid_data(format!("'{}' is in the vocabulary twice", w)))
+            }
+        }
+        Ok(vocab)
+    }
+
+    fn serialized_len(&self) -> u64 {
+        self.words.serialized_len()
+    }
+}
@@ -0,0 +1,241 @@
+use std::str::FromStr;
+use twitter::FrequencyTable;
+use storage::{ open_file, save_file };
+use error::Error;
+
+/*
+                            ********************************
+                            *          WEIGHTING           *
+                            ********************************
+
+    Raw word counts make "the", "to" and "rt" the biggest part of every user's vector, so
+    everyone looks like everyone else. Words that everyone uses shouldn't count for much, and
+    words only a few people use should count for a lot. That takes knowing how many users
+    (documents, here) use each word, which is the DocumentFrequencies table. It is counted
+    over every FrequencyTable when the data is loaded, and saved next to the tweet tree (it
+    only makes sense with the tables and the vocabulary it was counted from).
+
+    A word that shows up `count` times in a table of `length` words, and is in `df` of the
+    N tables, weighs:
+
+        Raw    -> count
+        TfIdf  -> (1 + log2(count)) * log2(N / df)
+        Bm25   -> count * (K1 + 1) / (count + K1 * (1 - B + B * length / average length))
+                    * ln(1 + (N - df + 0.5) / (df + 0.5))
+
+    TF-IDF gives a word in every table no weight at all. BM25's count part levels off as the
+    count goes up (so someone saying one thing 500 times doesn't drown out everything else
+    they say), and it is scaled down for people who tweet a lot more than average.
+
+    The table is saved like the vocabulary (see vocab.rs), as a record:
+
+        documents    -> The number of tables that were counted
+        words        -> The number of words in all of them
+        counts       -> counts[id] is the number of tables the word with that id is in
+*/
+
+/// How much BM25's count part is allowed to grow before it levels off.
+const K1: f64 = 1.2;
+/// How much BM25 scales down users with more words than average, from 0 (not at all) to 1.
+const B: f64 = 0.75;
+
+/// How the words in a FrequencyTable are weighed (see the top of this file).
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Weighting {
+    Raw,
+    TfIdf,
+    Bm25
+}
+
+impl FromStr for Weighting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Weighting, String> {
+        match &s.to_lowercase()[..] {
+            "raw" => Ok(Weighting::Raw),
+            "tf-idf" | "tfidf" => Ok(Weighting::TfIdf),
+            "bm25" => Ok(Weighting::Bm25),
+            _ => Err(format!("{} is not raw, tf-idf or bm25", s))
+        }
+    }
+}
+
+/// How many of the FrequencyTables each word is in.
+#[derive(PartialEq, Debug)]
+pub struct DocumentFrequencies {
+    documents: usize,
+    words: usize,
+    counts: Vec<u32>
+}
+
+record!(DocumentFrequencies, version 1 { documents: usize, words: usize, counts: Vec<u32> });
+
+impl DocumentFrequencies {
+    pub fn new() -> DocumentFrequencies {
+        DocumentFrequencies { documents: 0, words: 0, counts: vec![] }
+    }
+
+    /// Counts a table as one more document.
+    pub fn add(&mut self, table: &FrequencyTable) {
+        self.documents += 1;
+        self.words += table.word_count();
+        for (&id, _) in table.iter() {
+            if id as usize >= self.counts.len() {
+                self.counts.resize(id as usize + 1, 0);
+            }
+            self.counts[id as usize] += 1;
+        }
+    }
+
+    /// The number of tables that were counted.
+    pub fn documents(&self) -> usize {
+        self.documents
+    }
+
+    /// The number of tables the word with the given id is in.
+    pub fn count(&self, id: u32) -> usize {
+        self.counts.get(id as usize).map(|&c| c as usize).unwrap_or(0)
+    }
+
+    /// The average number of words in a table.
+    pub fn average_length(&self) -> f64 {
+        if self.documents == 0 { 0.0 } else { self.words as f64 / self.documents as f64 }
+    }
+
+    /// How much a word counts, from how many tables it's in. Words that weren't counted are
+    /// treated like they're in one table.
+    pub fn idf(&self, weighting: Weighting, id: u32) -> f64 {
+        let n = self.documents as f64;
+        let df = self.count(id) as f64;
+        match weighting {
+            Weighting::Raw => 1.0,
+            Weighting::TfIdf => if n == 0.0 { 0.0 } else { (n / df.max(1.0)).log2().max(0.0) },
+            Weighting::Bm25 => (1.0 + (n - df + 0.5) / (df + 0.5)).ln()
+        }
+    }
+
+    /// The weight of a word that shows up count times in a table of length words.
+    pub fn weight(&self, weighting: Weighting, id: u32, count: usize, length: usize) -> f64 {
+        if count == 0 {
+            return 0.0
+        }
+        let count = count as f64;
+        let tf = match weighting {
+            Weighting::Raw => count,
+            Weighting::TfIdf => 1.0 + count.log2(),
+            Weighting::Bm25 => {
+                let average = self.average_length();
+                let norm = if average == 0.0 { 1.0 } else { 1.0 - B + B * length as f64 / average };
+                count * (K1 + 1.0) / (count + K1 * norm)
+            }
+        };
+        tf * self.idf(weighting, id)
+    }
+
+    /// Reads the table saved at path.
+    pub fn open(path: &str) -> Result<DocumentFrequencies, Error> {
+        open_file::<DocumentFrequencies>(path, "document frequencies")
+    }
+
+    /// Saves the table to path, replacing whatever was there.
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        save_file(self, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ Weighting, DocumentFrequencies, K1 };
+    use twitter::FrequencyTable;
+    use std::env;
+    use std::fs;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    /// Four tables with 16 words between them. Word 0 is in all of them, word 1 in two, words 2
+    /// and 3 in one each, and word 9 in none.
+    fn frequencies() -> DocumentFrequencies {
+        let mut df = DocumentFrequencies::new();
+        for counts in &[vec![(0, 2), (1, 1)], vec![(0, 1), (2, 4)], vec![(0, 5)], vec![(0, 1), (1, 1), (3, 1)]] {
+            let mut table = FrequencyTable::new();
+            for &(id, n) in counts.iter() {
+                table.add(id, n);
+            }
+            df.add(&table);
+        }
+        df
+    }
+
+    #[test]
+    fn test_counts() {
+        let df = frequencies();
+        assert_eq!(df.documents(), 4);
+        assert_eq!(df.count(0), 4);
+        assert_eq!(df.count(1), 2);
+        assert_eq!(df.count(3), 1);
+        assert_eq!(df.count(9), 0);
+        assert!(close(df.average_length(), 4.0));
+    }
+
+    #[test]
+    fn test_idf() {
+        let df = frequencies();
+        assert_eq!(df.idf(Weighting::Raw, 0), 1.0);
+        assert_eq!(df.idf(Weighting::Raw, 9), 1.0);
+        // log2(4 / df), with words that weren't counted treated like they're in one table
+        assert!(close(df.idf(Weighting::TfIdf, 0), 0.0));
+        assert!(close(df.idf(Weighting::TfIdf, 1), 1.0));
+        assert!(close(df.idf(Weighting::TfIdf, 2), 2.0));
+        assert!(close(df.idf(Weighting::TfIdf, 9), 2.0));
+        // ln(1 + (4 - df + 0.5) / (df + 0.5))
+        assert!(close(df.idf(Weighting::Bm25, 0), (10.0f64 / 9.0).ln()));
+        assert!(close(df.idf(Weighting::Bm25, 1), 2f64.ln()));
+        assert!(close(df.idf(Weighting::Bm25, 9), 10f64.ln()));
+    }
+
+    #[test]
+    fn test_weight() {
+        let df = frequencies();
+        assert_eq!(df.weight(Weighting::Raw, 0, 3, 10), 3.0);
+        assert_eq!(df.weight(Weighting::Raw, 0, 0, 10), 0.0);
+        // (1 + log2(4)) * log2(4 / 2), and a word in every table is worth nothing
+        assert!(close(df.weight(Weighting::TfIdf, 1, 4, 10), 3.0));
+        assert!(close(df.weight(Weighting::TfIdf, 0, 4, 10), 0.0));
+        assert!(close(df.weight(Weighting::TfIdf, 9, 1, 10), 2.0));
+        assert_eq!(df.weight(Weighting::TfIdf, 1, 0, 10), 0.0);
+        // 8 words is twice the average, so the length part is 1 - 0.75 + 0.75 * 2 = 1.75
+        assert!(close(df.weight(Weighting::Bm25, 1, 2, 8), 2.0 * (K1 + 1.0) / (2.0 + K1 * 1.75) * 2f64.ln()));
+        // At the average length, a word said once is worth exactly its idf
+        assert!(close(df.weight(Weighting::Bm25, 9, 1, 4), df.idf(Weighting::Bm25, 9)));
+        assert!(df.weight(Weighting::Bm25, 1, 100, 4) < df.idf(Weighting::Bm25, 1) * (K1 + 1.0));
+    }
+
+    #[test]
+    fn test_empty() {
+        let df = DocumentFrequencies::new();
+        assert_eq!(df.average_length(), 0.0);
+        assert_eq!(df.idf(Weighting::TfIdf, 0), 0.0);
+        assert!(close(df.weight(Weighting::Bm25, 0, 1, 5), 2f64.ln()));
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!("raw".parse::<Weighting>(), Ok(Weighting::Raw));
+        assert_eq!("TF-IDF".parse::<Weighting>(), Ok(Weighting::TfIdf));
+        assert_eq!("tfidf".parse::<Weighting>(), Ok(Weighting::TfIdf));
+        assert_eq!("BM25".parse::<Weighting>(), Ok(Weighting::Bm25));
+        assert!("idf".parse::<Weighting>().is_err());
+    }
+
+    #[test]
+    fn test_save_and_open() {
+        let path = env::temp_dir().join("weighting_test.df");
+        let path = path.to_str().unwrap();
+        let df = frequencies();
+        df.save(path).unwrap();
+        assert_eq!(DocumentFrequencies::open(path).unwrap(), df);
+        fs::remove_file(path).unwrap();
+    }
+}